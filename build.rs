@@ -1,30 +1,145 @@
 #![allow(unused)]
 
+use std::{env, fs, path::PathBuf};
+
 use hpm_rt::*;
 
-fn boot_from_flash() {
+const BOARDS: [&str; 4] = [
+    "hpm6300-custom",
+    "hpm6360evk",
+    "hpm6750evk",
+    "hpm6750evkmini",
+];
+
+/// Chip family of the selected board.
+fn family() -> Family {
+    if cfg!(feature = "hpm67") {
+        Family::HPM6700
+    } else {
+        Family::HPM6300
+    }
+}
+
+/// Each HPM6700 core has its own DLM at the same address, so data both cores use goes
+/// to AXI SRAM instead. See `SHARED_RAM` in src/board/mod.rs.
+fn shared_data(builder: RuntimeBuilder) -> RuntimeBuilder {
+    if cfg!(feature = "hpm67") {
+        builder.data(MemoryType::AxiSram).bss(MemoryType::AxiSram)
+    } else {
+        builder
+    }
+}
+
+/// `FLASH_SIZE` of the board, read from its module in src/board so the two agree. The
+/// value is written as a product of integer literals, like `16 * 1024 * 1024`.
+fn flash_size(board: &str) -> u32 {
+    let path = format!("src/board/{}.rs", board.replace('-', "_"));
+    println!("cargo:rerun-if-changed={path}");
+    let source = fs::read_to_string(&path).unwrap();
+    let value = source
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("const FLASH_SIZE: usize ="))
+        .unwrap_or_else(|| panic!("no FLASH_SIZE in {path}"));
+    value
+        .trim()
+        .trim_end_matches(';')
+        .split('*')
+        .map(|factor| factor.trim().replace('_', "").parse::<u32>())
+        .try_fold(1u32, |size, factor| size.checked_mul(factor.ok()?))
+        .unwrap_or_else(|| panic!("cannot evaluate FLASH_SIZE in {path}: {value}"))
+}
+
+fn boot_from_flash(board: &str) {
     let xpi_nor_cfg = XpiNorConfigurationOption::new();
 
-    RuntimeBuilder::load_from_flash(Family::HPM6300, xpi_nor_cfg)
-        .xpi0_flash_size(16 * 1024 * 1024)
+    shared_data(RuntimeBuilder::load_from_flash(family(), xpi_nor_cfg))
+        .xpi0_flash_size(flash_size(board))
         .build()
         .unwrap();
 }
 
 fn boot_from_ram() {
-    RuntimeBuilder::from_ram(Family::HPM6300)
+    shared_data(RuntimeBuilder::from_ram(family()))
         .stack(MemoryType::Dlm, 8 * 1024)
         .build()
         .unwrap();
 }
 
+/// Kernel slot A, where the flash layout of `src/loader` begins. The firmware image,
+/// with any payload, has to end before it.
+const FLASH_LAYOUT_START: u32 = 0x8001_0000;
+
+/// Define `_rustsbi_image_end`, the end of the firmware image where it is loaded from:
+/// code, read-only data including the payload, and the initial values of `.data`. With
+/// `flash`, fail the link if it runs into the flash layout.
+fn image_end(flash: bool) {
+    let mut script = String::from(
+        "_rustsbi_image_end = MAX(ADDR(.rodata) + SIZEOF(.rodata), \
+         LOADADDR(.data) + SIZEOF(.data));\n",
+    );
+    if flash {
+        script += &format!(
+            "ASSERT(_rustsbi_image_end <= {FLASH_LAYOUT_START:#x}, \
+             \"firmware image runs into kernel slot A at {FLASH_LAYOUT_START:#x}, \
+             the payload is too large to link into flash\");\n"
+        );
+    }
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("image-end.x");
+    fs::write(&out, script).unwrap();
+    println!("cargo:rustc-link-arg=-T{}", out.display());
+}
+
+/// Copy the file named by `var`, or `default`, to `name` in `OUT_DIR` for `include_bytes!`.
+/// Without either, the file is left empty.
+fn payload(var: &str, default: Option<&str>, name: &str) {
+    println!("cargo:rerun-if-env-changed={var}");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join(name);
+    match env::var(var).ok().or(default.map(String::from)) {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            if let Err(e) = fs::copy(&path, &out) {
+                panic!("cannot read payload {path}: {e}");
+            }
+        }
+        None => fs::write(&out, []).unwrap(),
+    }
+}
+
 fn main() {
+    let boards: Vec<_> = BOARDS
+        .iter()
+        .filter(|board| {
+            let feature = board.to_uppercase().replace('-', "_");
+            env::var_os(format!("CARGO_FEATURE_{feature}")).is_some()
+        })
+        .collect();
+    if boards.len() != 1 {
+        panic!(
+            "select exactly one board feature of {BOARDS:?}, got {boards:?}; \
+             use --no-default-features to replace the default board"
+        );
+    }
+
     #[cfg(feature = "ram")]
     boot_from_ram();
 
     #[cfg(feature = "flash")]
-    boot_from_flash();
+    boot_from_flash(boards[0]);
+
+    #[cfg(feature = "payload")]
+    {
+        payload(
+            "RUSTSBI_PAYLOAD_KERNEL",
+            Some("src/kernel.bin"),
+            "payload-kernel.bin",
+        );
+        payload("RUSTSBI_PAYLOAD_DTB", None, "payload-dtb.bin");
+    }
+
+    image_end(cfg!(feature = "flash"));
+
+    // Keep a1 and a2 from an earlier stage, see src/fw_dynamic.rs
+    println!("cargo:rustc-link-arg=--entry=_rustsbi_entry");
 
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=src/kernel.bin");
 }
@@ -0,0 +1,233 @@
+#!/usr/bin/env python3
+"""Write the FIT fixtures from linux.its the way `mkimage -f` does.
+
+The layout follows U-Boot's tools/fit_image.c: the source is compiled as dtc would,
+hash values and the timestamp are added, and with -E each `data` property is moved
+after the tree (4-byte aligned, `data-offset` from the end of the tree) or, with -p,
+to a fixed `data-position` in the file. The tree itself is written from the devicetree
+specification alone, so the parser under test is checked against another writer.
+
+Where mkimage is available, it produces equivalent files:
+
+    mkimage -f linux.its linux.itb
+    mkimage -f linux.its -E linux-external.itb
+    mkimage -f linux.its -E -p 0x1000 linux-position.itb
+
+Usage: ./mkfit.py, from this directory.
+"""
+
+import hashlib
+import re
+import struct
+import zlib
+
+FDT_MAGIC = 0xD00DFEED
+FDT_BEGIN_NODE, FDT_END_NODE, FDT_PROP, FDT_END = 1, 2, 3, 9
+# Fixed, so that the fixtures are reproducible
+TIMESTAMP = 0x67000000
+
+
+class Node:
+    def __init__(self, name):
+        self.name = name
+        self.props = []
+        self.children = []
+
+    def prop(self, name):
+        return next((v for n, v in self.props if n == name), None)
+
+    def set(self, name, value):
+        for i, (n, _) in enumerate(self.props):
+            if n == name:
+                self.props[i] = (name, value)
+                return
+        self.props.append((name, value))
+
+    def delete(self, name):
+        self.props = [(n, v) for n, v in self.props if n != name]
+
+
+def parse_dts(text):
+    """The subset of the DTS syntax image sources use."""
+    text = re.sub(r"/\*.*?\*/", "", text, flags=re.S)
+    text = re.sub(r"//[^\n]*", "", text)
+    tokens = re.findall(
+        r'"(?:\\.|[^"])*"|/incbin/\s*\(\s*"[^"]*"\s*\)|<[^>]*>|/dts-v1/|[{};=]|[^\s{};=]+',
+        text,
+    )
+    pos = 0
+
+    def value(tok):
+        if tok.startswith('"'):
+            return tok[1:-1].encode() + b"\0"
+        if tok.startswith("<"):
+            cells = tok[1:-1].split()
+            return b"".join(struct.pack(">I", int(c, 0)) for c in cells)
+        if tok.startswith("/incbin/"):
+            with open(re.search(r'"([^"]*)"', tok).group(1), "rb") as f:
+                return f.read()
+        raise ValueError(f"unsupported value {tok}")
+
+    def node(name):
+        nonlocal pos
+        n = Node(name)
+        while tokens[pos] != "}":
+            name = tokens[pos]
+            pos += 1
+            if tokens[pos] == "{":
+                pos += 1
+                n.children.append(node(name))
+            elif tokens[pos] == "=":
+                n.props.append((name, value(tokens[pos + 1])))
+                pos += 2
+            else:
+                n.props.append((name, b""))
+            assert tokens[pos] == ";", tokens[pos]
+            pos += 1
+        pos += 1
+        return n
+
+    assert tokens[0] == "/dts-v1/" and tokens[1] == ";"
+    assert tokens[2] == "/" and tokens[3] == "{"
+    pos = 4
+    root = node("")
+    assert tokens[pos] == ";"
+    return root
+
+
+def fdt(root):
+    """Flatten a tree as dtc does: version 17, an empty reservation map, strings
+    deduplicated against the tails of earlier ones."""
+    structure = bytearray()
+    strings = bytearray()
+
+    def string(name):
+        s = name.encode() + b"\0"
+        # Strings end in NUL, so any match is the tail of an earlier string
+        i = strings.find(s)
+        if i < 0:
+            i = len(strings)
+            strings.extend(s)
+        return i
+
+    def pad():
+        structure.extend(b"\0" * (-len(structure) % 4))
+
+    def emit(n):
+        structure.extend(struct.pack(">I", FDT_BEGIN_NODE))
+        structure.extend(n.name.encode() + b"\0")
+        pad()
+        for name, v in n.props:
+            structure.extend(struct.pack(">III", FDT_PROP, len(v), string(name)))
+            structure.extend(v)
+            pad()
+        for c in n.children:
+            emit(c)
+        structure.extend(struct.pack(">I", FDT_END_NODE))
+
+    emit(root)
+    structure.extend(struct.pack(">I", FDT_END))
+    rsvmap = 40
+    off_struct = rsvmap + 16
+    off_strings = off_struct + len(structure)
+    total = off_strings + len(strings)
+    header = struct.pack(
+        ">10I",
+        FDT_MAGIC,
+        total,
+        off_struct,
+        off_strings,
+        rsvmap,
+        17,
+        16,
+        0,
+        len(strings),
+        len(structure),
+    )
+    return header + b"\0" * 16 + bytes(structure) + bytes(strings)
+
+
+def add_hashes(root):
+    root.props.insert(0, ("timestamp", struct.pack(">I", TIMESTAMP)))
+    for image in next(c for c in root.children if c.name == "images").children:
+        data = image.prop("data")
+        for h in image.children:
+            if not h.name.startswith("hash"):
+                continue
+            algo = h.prop("algo").rstrip(b"\0").decode()
+            if algo == "crc32":
+                h.set("value", struct.pack(">I", zlib.crc32(data)))
+            elif algo == "sha256":
+                h.set("value", hashlib.sha256(data).digest())
+            else:
+                raise ValueError(f"unsupported hash {algo}")
+
+
+def extract_data(root, position=None):
+    """`mkimage -E`, with `position` for `-p`. Returns the data to append."""
+    external = bytearray()
+    for image in next(c for c in root.children if c.name == "images").children:
+        data = image.prop("data")
+        if data is None:
+            continue
+        image.delete("data")
+        if position is None:
+            image.set("data-offset", struct.pack(">I", len(external)))
+        else:
+            image.set("data-position", struct.pack(">I", position + len(external)))
+        image.set("data-size", struct.pack(">I", len(data)))
+        external.extend(data)
+        external.extend(b"\0" * (-len(external) % 4))
+    return bytes(external)
+
+
+def dtb():
+    """A small board tree for the `fdt` image."""
+    root = Node("")
+    root.props = [
+        ("#address-cells", struct.pack(">I", 1)),
+        ("#size-cells", struct.pack(">I", 1)),
+        ("model", b"HPM6360EVK\0"),
+        ("compatible", b"hpmicro,hpm6360evk\0"),
+    ]
+    memory = Node("memory@40000000")
+    memory.props = [
+        ("device_type", b"memory\0"),
+        ("reg", struct.pack(">II", 0x40000000, 0x2000000)),
+    ]
+    chosen = Node("chosen")
+    chosen.props = [("bootargs", b"earlycon=sbi console=hvc0\0")]
+    root.children = [memory, chosen]
+    return fdt(root)
+
+
+def main():
+    with open("hpm6360evk.dtb", "wb") as f:
+        f.write(dtb())
+    with open("linux.its") as f:
+        source = f.read()
+
+    root = parse_dts(source)
+    add_hashes(root)
+    with open("linux.itb", "wb") as f:
+        f.write(fdt(root))
+
+    root = parse_dts(source)
+    add_hashes(root)
+    data = extract_data(root)
+    tree = fdt(root)
+    with open("linux-external.itb", "wb") as f:
+        f.write(tree + b"\0" * (-len(tree) % 4) + data)
+
+    position = 0x1000
+    root = parse_dts(source)
+    add_hashes(root)
+    data = extract_data(root, position)
+    tree = fdt(root)
+    assert len(tree) <= position
+    with open("linux-position.itb", "wb") as f:
+        f.write(tree + b"\0" * (position - len(tree)) + data)
+
+
+if __name__ == "__main__":
+    main()
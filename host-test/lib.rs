@@ -0,0 +1,38 @@
+//! The firmware modules that do not touch hardware, built for the host to run their
+//! unit tests. The firmware itself only builds for the chip, so `cargo test` runs here:
+//!
+//! ```sh
+//! cd host-test
+//! cargo test --target x86_64-unknown-linux-gnu
+//! cargo test --target x86_64-unknown-linux-gnu --no-default-features --features hpm67
+//! ```
+//!
+//! The target has to be given because the firmware's `.cargo/config.toml` applies here too.
+//! Test data lives in `fixtures/`.
+
+#![allow(dead_code, unused_imports)]
+
+use error::LoadError;
+
+#[path = "../src/crc32.rs"]
+mod crc32;
+#[path = "../src/loader/decompress.rs"]
+mod decompress;
+#[path = "../src/loader/elf.rs"]
+mod elf;
+#[path = "../src/loader/error.rs"]
+mod error;
+#[path = "../src/fdt/mod.rs"]
+mod fdt;
+#[path = "../src/loader/fit.rs"]
+mod fit;
+#[path = "../src/loader/kernel.rs"]
+mod kernel;
+#[path = "../src/board/pll.rs"]
+mod pll;
+#[path = "../src/board/sdram.rs"]
+mod sdram;
+#[path = "../src/sha256.rs"]
+mod sha256;
+#[path = "../src/ymodem.rs"]
+mod ymodem;
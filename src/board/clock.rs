@@ -1,19 +1,100 @@
 #![allow(unused)]
 
-use super::pac::sysctl::vals::ClockMux;
-use super::pac::{pllctl, resources, sysctl};
+pub use super::pac::sysctl::vals::ClockMux;
+use super::pac::{pllctl, sysctl};
+use super::pll::{self, XTAL24M_FREQ};
+use super::Board;
+use crate::println;
 
-pub use super::pac::clocks;
+pub use super::pac::{clocks, resources};
 
-const XTAL24M_FREQ: u32 = 24_000_000;
+/// Clock of the console UART, the crystal undivided.
+pub const CONSOLE_CLOCK_FREQ: u32 = XTAL24M_FREQ;
+
+/// PLL controller of the chip family: PLLCTLv2 on HPM6300, PLLCTL on HPM6700.
+#[cfg(feature = "hpm63")]
+pub type Pllctl = pllctl::Pllctlv2;
+#[cfg(feature = "hpm67")]
+pub type Pllctl = pllctl::Pllctl;
+
+/// PLL outputs clock nodes mux from, with their PLL and output index.
+#[cfg(feature = "hpm63")]
+const SOURCES: [(ClockMux, &str, usize, usize); 7] = [
+    (ClockMux::PLL0CLK0, "pll0clk0", 0, 0),
+    (ClockMux::PLL0CLK1, "pll0clk1", 0, 1),
+    (ClockMux::PLL0CLK2, "pll0clk2", 0, 2),
+    (ClockMux::PLL1CLK0, "pll1clk0", 1, 0),
+    (ClockMux::PLL1CLK1, "pll1clk1", 1, 1),
+    (ClockMux::PLL2CLK0, "pll2clk0", 2, 0),
+    (ClockMux::PLL2CLK1, "pll2clk1", 2, 1),
+];
+#[cfg(feature = "hpm67")]
+const SOURCES: [(ClockMux, &str, usize, usize); 7] = [
+    (ClockMux::PLL0CLK0, "pll0clk0", 0, 0),
+    (ClockMux::PLL1CLK0, "pll1clk0", 1, 0),
+    (ClockMux::PLL1CLK1, "pll1clk1", 1, 1),
+    (ClockMux::PLL2CLK0, "pll2clk0", 2, 0),
+    (ClockMux::PLL2CLK1, "pll2clk1", 2, 1),
+    (ClockMux::PLL3CLK0, "pll3clk0", 3, 0),
+    (ClockMux::PLL4CLK0, "pll4clk0", 4, 0),
+];
+
+#[cfg(feature = "hpm63")]
+const NUM_PLLS: usize = 3;
+#[cfg(feature = "hpm67")]
+const NUM_PLLS: usize = 5;
+
+/// Buses clocked by dividing CPU0: AHB on HPM6300, AXI and AHB on HPM6700.
+#[cfg(feature = "hpm63")]
+pub const CPU_SUB_CLOCKS: usize = 1;
+#[cfg(feature = "hpm67")]
+pub const CPU_SUB_CLOCKS: usize = 2;
+
+/// Clock nodes shown by [`Clocks::dump`] besides the console.
+const NAMED_CLOCKS: [(usize, &str); 3] = [
+    (clocks::MCT0, "mct0"),
+    (clocks::FEMC, "femc"),
+    (clocks::XPI0, "xpi0"),
+];
+
+/// How a board wants the clock tree set up before anything runs from it.
+///
+/// Dividers are written as they are in the registers. What is left empty stays as the
+/// boot ROM set it up.
+pub struct ClockConfig {
+    /// Frequencies to program, by PLL: the VCO on PLLCTLv2, the output after POSTDIV1
+    /// on PLLCTL, which runs in integer mode with REFDIV and POSTDIV1 as they are.
+    pub plls: &'static [(usize, u32)],
+    /// Post dividers of PLL outputs, dividing by $1 + DIV \div 5$ on PLLCTLv2 and by
+    /// `DIV + 1` on PLLCTL.
+    pub postdivs: &'static [(ClockMux, u8)],
+    pub cpu0: Option<CpuClock>,
+}
+
+impl ClockConfig {
+    /// Keep the clocks of the boot ROM.
+    pub const ROM: Self = Self {
+        plls: &[],
+        postdivs: &[],
+        cpu0: None,
+    };
+}
+
+/// Source of CPU0 and the dividers of its domain, each one less than the division.
+pub struct CpuClock {
+    pub mux: ClockMux,
+    pub div: u8,
+    /// Dividers of the buses under CPU0, in the order of [`CPU_SUB_CLOCKS`].
+    pub sub_div: [u8; CPU_SUB_CLOCKS],
+}
 
 pub struct ClockConfigurator {
     sysctl: sysctl::Sysctl,
-    pllctl: pllctl::Pllctlv2,
+    pllctl: Pllctl,
 }
 
 impl ClockConfigurator {
-    pub fn new(sysctl: sysctl::Sysctl, pllctl: pllctl::Pllctlv2) -> Self {
+    pub fn new(sysctl: sysctl::Sysctl, pllctl: Pllctl) -> Self {
         ClockConfigurator { sysctl, pllctl }
     }
 
@@ -30,25 +111,28 @@ impl ClockConfigurator {
             .write(|w| w.set_link(1 << offset))
     }
 
-    pub unsafe fn freeze(self) -> Clocks {
+    /// Set up the PLLs and CPU0 as board `B` asks, then clock what it uses.
+    pub unsafe fn freeze<B: Board>(self) -> Clocks {
+        self.configure(&B::CLOCKS);
+
         self.link_to_group(resources::GPIO);
         self.link_to_group(resources::MCT0);
-        self.link_to_group(resources::URT0);
         self.link_to_group(resources::FEMC);
+        self.link_to_group(resources::XDMA);
+        self.link_to_group(resources::HDMA);
 
+        // The crystal undivided, as from reset, so timestamps taken before keep their rate
         self.sysctl.clock(clocks::MCT0).modify(|w| {
-            w.set_mux(sysctl::vals::ClockMux::CLK_24M);
-            w.set_div(23);
-        });
-
-        self.sysctl.clock(clocks::URT0).modify(|w| {
             w.set_mux(sysctl::vals::ClockMux::CLK_24M);
             w.set_div(0);
         });
 
+        self.enable_console::<B>();
+
+        let (mux, div) = B::SDRAM_CLOCK;
         self.sysctl.clock(clocks::FEMC).modify(|w| {
-            w.set_mux(sysctl::vals::ClockMux::PLL0CLK1);
-            w.set_div(1);
+            w.set_mux(mux);
+            w.set_div(div);
         });
 
         Clocks {
@@ -56,76 +140,265 @@ impl ClockConfigurator {
             pllctl: self.pllctl,
         }
     }
+
+    /// Clock the console UART of board `B` from the crystal, at [`CONSOLE_CLOCK_FREQ`].
+    pub fn enable_console<B: Board>(&self) {
+        let console = B::CONSOLE;
+        self.link_to_group(resources::GPIO);
+        self.link_to_group(console.resource);
+        self.sysctl.clock(console.clock).modify(|w| {
+            w.set_mux(sysctl::vals::ClockMux::CLK_24M);
+            w.set_div(0);
+        });
+    }
+
+    fn configure(&self, config: &ClockConfig) {
+        if !config.plls.is_empty() || !config.postdivs.is_empty() {
+            // CPU0 may run from a PLL about to change, so it waits on the crystal
+            self.set_cpu0(ClockMux::CLK_24M, 0, [0; CPU_SUB_CLOCKS]);
+            for &(pll, freq) in config.plls {
+                self.set_pll(pll, freq);
+            }
+            for &(src, div) in config.postdivs {
+                self.set_postdiv(src, div);
+            }
+        }
+        if let Some(cpu0) = &config.cpu0 {
+            self.set_cpu0(cpu0.mux, cpu0.div, cpu0.sub_div);
+        }
+    }
+
+    /// Program the VCO of `pll` to `freq` and wait for it to lock.
+    #[cfg(feature = "hpm63")]
+    fn set_pll(&self, pll: usize, freq: u32) {
+        let (mfi, mfn) = pll::pllv2_settings(freq).expect("PLL frequency out of range");
+        let r = self.pllctl.pll(pll);
+        r.mfd().write(|w| w.set_mfd(pll::PLLV2_MFD));
+        r.mfn().write(|w| w.set_mfn(mfn));
+        r.mfi().modify(|w| w.set_mfi(mfi as u8));
+        while r.mfi().read().busy() {}
+    }
+
+    #[cfg(feature = "hpm63")]
+    fn set_postdiv(&self, src: ClockMux, div: u8) {
+        let (_, _, pll, index) = source(src).expect("not a PLL output");
+        let r = self.pllctl.pll(pll).div(index);
+        r.modify(|w| w.set_div(div));
+        while r.read().busy() {}
+    }
+
+    /// Program `pll` to `freq` in integer mode and wait for it to run again.
+    #[cfg(feature = "hpm67")]
+    fn set_pll(&self, pll: usize, freq: u32) {
+        let r = self.pllctl.pll(pll);
+        let cfg0 = r.cfg0().read();
+        let fbdiv = pll::pllv1_settings(
+            XTAL24M_FREQ,
+            cfg0.refdiv() as u32,
+            cfg0.postdiv1() as u32,
+            freq,
+        )
+        .expect("PLL frequency out of range");
+        r.cfg2().modify(|w| w.set_fbdiv_int(fbdiv as _));
+        r.cfg0().modify(|w| w.set_dsmpd(true));
+        while !r.status().read().enable() {}
+    }
+
+    #[cfg(feature = "hpm67")]
+    fn set_postdiv(&self, src: ClockMux, div: u8) {
+        let (_, _, pll, index) = source(src).expect("not a PLL output");
+        let r = self.pllctl.pll(pll);
+        match index {
+            0 => {
+                r.div0().modify(|w| w.set_div(div));
+                while r.div0().read().busy() {}
+            }
+            _ => {
+                r.div1().modify(|w| w.set_div(div));
+                while r.div1().read().busy() {}
+            }
+        }
+    }
+
+    fn set_cpu0(&self, mux: ClockMux, div: u8, sub_div: [u8; CPU_SUB_CLOCKS]) {
+        let r = self.sysctl.clock_cpu(0);
+        r.modify(|w| {
+            w.set_mux(mux);
+            w.set_div(div);
+            w.set_sub0_div(sub_div[0]);
+            #[cfg(feature = "hpm67")]
+            w.set_sub1_div(sub_div[1]);
+        });
+        while r.read().glb_busy() {}
+    }
+}
+
+fn source(src: ClockMux) -> Option<(ClockMux, &'static str, usize, usize)> {
+    SOURCES.into_iter().find(|&(mux, ..)| mux == src)
+}
+
+/// A point in the clock tree.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Node {
+    /// The 24MHz crystal, root of the tree.
+    Xtal,
+    /// VCO of a PLL.
+    Pll(usize),
+    /// A post divided PLL output, or the crystal, as clock nodes select it.
+    Source(ClockMux),
+    Cpu0,
+    /// A bus divided from CPU0, by index into [`CPU_SUB_CLOCKS`].
+    CpuSub(usize),
+    /// A clock node, from `clocks`.
+    Clock(usize),
 }
 
 pub struct Clocks {
     sysctl: sysctl::Sysctl,
-    pllctl: pllctl::Pllctlv2,
+    pllctl: Pllctl,
 }
 
 impl Clocks {
-    /// When work in integer mode, the frequency of PLL is:
+    /// Look at the clock tree as it is configured now.
+    pub fn new(sysctl: sysctl::Sysctl, pllctl: Pllctl) -> Self {
+        Clocks { sysctl, pllctl }
+    }
+
+    /// Where `node` takes its clock from, `None` for the crystal.
     ///
-    /// $$F_{vco} = F_{ref} \times (MFI + (MFN \div MFD))$$
-    pub fn get_pll_freq(&self, pll: usize) -> u32 {
-        assert!(pll <= 2);
-        let r = self.pllctl.pll(pll);
-        let mfi = r.mfi().read().mfi() as f64;
-        let mfn = r.mfn().read().mfn() as f64 / 10.0;
-        let mfd = r.mfd().read().mfd() as f64 / 10.0;
-
-        let _freq = ((XTAL24M_FREQ as f64) * (mfi + (mfn / mfd))) as u32;
-        _freq
-    }
-
-    pub fn get_clk_src_freq(&self, src: ClockMux) -> u32 {
-        match src {
-            ClockMux::CLK_24M => XTAL24M_FREQ,
-            (ClockMux::PLL0CLK0 | ClockMux::PLL0CLK1 | ClockMux::PLL0CLK2) => {
-                let freq = self.get_pll_freq(0) as f64;
-                let div = self
-                    .pllctl
-                    .pll(0)
-                    .div(src as usize - ClockMux::PLL0CLK0 as usize)
-                    .read()
-                    .div() as f64;
-                (freq / (1.0 + 0.2 * div)) as _
-            }
-            (ClockMux::PLL1CLK0 | ClockMux::PLL1CLK1) => {
-                let freq = self.get_pll_freq(0) as f64;
-                let div = self
-                    .pllctl
-                    .pll(1)
-                    .div(src as usize - ClockMux::PLL1CLK0 as usize)
-                    .read()
-                    .div() as f64;
-                (freq / (1.0 + 0.2 * div)) as _
-            }
-            (ClockMux::PLL2CLK0 | ClockMux::PLL2CLK1) => {
-                let freq = self.get_pll_freq(0) as f64;
-                let div = self
-                    .pllctl
-                    .pll(2)
-                    .div(src as usize - ClockMux::PLL2CLK0 as usize)
-                    .read()
-                    .div() as f64;
-                (freq / (1.0 + 0.2 * div)) as _
+    /// Panics on a clock node muxed from a source missing in `SOURCES`, which has no
+    /// frequency to report.
+    pub fn parent(&self, node: Node) -> Option<Node> {
+        Some(match node {
+            Node::Xtal => return None,
+            Node::Pll(_) | Node::Source(ClockMux::CLK_24M) => Node::Xtal,
+            Node::Source(src) => match source(src) {
+                Some((_, _, pll, _)) => Node::Pll(pll),
+                None => panic!("clock muxed from an unknown source"),
+            },
+            Node::Cpu0 => Node::Source(self.sysctl.clock_cpu(0).read().mux()),
+            Node::CpuSub(_) => Node::Cpu0,
+            Node::Clock(clock) => Node::Source(self.sysctl.clock(clock).read().mux()),
+        })
+    }
+
+    /// Frequency of `node`, from that of its parent.
+    pub fn freq(&self, node: Node) -> u32 {
+        let Some(parent) = self.parent(node) else {
+            return XTAL24M_FREQ;
+        };
+        let fin = self.freq(parent);
+        match node {
+            Node::Xtal => unreachable!(),
+            Node::Pll(pll) => self.pll_freq(fin, pll),
+            Node::Source(ClockMux::CLK_24M) => fin,
+            Node::Source(src) => self.postdiv_freq(fin, src),
+            Node::Cpu0 => pll::divided(fin, self.sysctl.clock_cpu(0).read().div() as u32),
+            Node::CpuSub(sub) => {
+                let r = self.sysctl.clock_cpu(0).read();
+                let div = match sub {
+                    0 => r.sub0_div(),
+                    #[cfg(feature = "hpm67")]
+                    1 => r.sub1_div(),
+                    _ => unreachable!(),
+                };
+                pll::divided(fin, div as u32)
             }
-            _ => unimplemented!(),
+            Node::Clock(clock) => pll::divided(fin, self.sysctl.clock(clock).read().div() as u32),
         }
     }
 
-    pub fn get_clk_freq(&self, clock: usize) -> u32 {
-        let r = self.sysctl.clock(clock).read();
-        let src = r.mux();
-        let div = r.div() as u32;
-        self.get_clk_src_freq(src) / (div + 1)
+    #[cfg(feature = "hpm63")]
+    fn pll_freq(&self, fref: u32, pll: usize) -> u32 {
+        let r = self.pllctl.pll(pll);
+        let mfi = r.mfi().read().mfi() as u32;
+        let mfn = r.mfn().read().mfn();
+        let mfd = r.mfd().read().mfd();
+        pll::pllv2_freq(fref, mfi, mfn, mfd)
+    }
+
+    #[cfg(feature = "hpm63")]
+    fn postdiv_freq(&self, vco: u32, src: ClockMux) -> u32 {
+        let (_, _, pll, index) = source(src).unwrap();
+        let div = self.pllctl.pll(pll).div(index).read().div();
+        pll::pllv2_postdiv_freq(vco, div as u32)
+    }
+
+    #[cfg(feature = "hpm67")]
+    fn pll_freq(&self, fref: u32, pll: usize) -> u32 {
+        let r = self.pllctl.pll(pll);
+        let cfg0 = r.cfg0().read();
+        let (int, frac) = if cfg0.dsmpd() {
+            (r.cfg2().read().fbdiv_int() as u32, 0)
+        } else {
+            let freq = r.freq().read();
+            (freq.fbdiv_frac() as u32, freq.frac() as u32)
+        };
+        let (refdiv, postdiv) = (cfg0.refdiv() as u32, cfg0.postdiv1() as u32);
+        pll::pllv1_freq(fref, refdiv, int, frac, postdiv)
+    }
+
+    #[cfg(feature = "hpm67")]
+    fn postdiv_freq(&self, vco: u32, src: ClockMux) -> u32 {
+        let (_, _, pll, index) = source(src).unwrap();
+        let r = self.pllctl.pll(pll);
+        let div = match index {
+            0 => r.div0().read().div(),
+            _ => r.div1().read().div(),
+        };
+        pll::divided(vco, div as u32)
+    }
+
+    /// Print every PLL, PLL output and the clocks of board `B` under their parents.
+    pub fn dump<B: Board>(&self) {
+        self.dump_node::<B>(Node::Xtal, 0);
     }
 
-    pub fn get_cpu0_clk_freq(&self) -> u32 {
-        let r = self.sysctl.clock_cpu(0).read();
-        let src = r.mux();
-        let div = r.div() as u32;
-        self.get_clk_src_freq(src) / (div + 1)
+    fn dump_node<B: Board>(&self, node: Node, depth: usize) {
+        let indent = depth * 2;
+        let width = 14 - indent;
+        println!(
+            "{:indent$}{:<width$} {:>10}Hz",
+            "",
+            name::<B>(node),
+            self.freq(node)
+        );
+        for child in nodes::<B>().filter(|&child| self.parent(child) == Some(node)) {
+            self.dump_node::<B>(child, depth + 1);
+        }
+    }
+}
+
+/// The whole tree as far as [`Clocks::dump`] shows it, parents first.
+fn nodes<B: Board>() -> impl Iterator<Item = Node> {
+    let clocks = [B::CONSOLE.clock]
+        .into_iter()
+        .chain(NAMED_CLOCKS.iter().map(|&(clock, _)| clock));
+    [Node::Xtal]
+        .into_iter()
+        .chain((0..NUM_PLLS).map(Node::Pll))
+        .chain([Node::Source(ClockMux::CLK_24M)])
+        .chain(SOURCES.iter().map(|&(src, ..)| Node::Source(src)))
+        .chain([Node::Cpu0])
+        .chain((0..CPU_SUB_CLOCKS).map(Node::CpuSub))
+        .chain(clocks.map(Node::Clock))
+}
+
+fn name<B: Board>(node: Node) -> &'static str {
+    match node {
+        Node::Xtal => "xtal",
+        Node::Pll(pll) => ["pll0", "pll1", "pll2", "pll3", "pll4"][pll],
+        Node::Source(ClockMux::CLK_24M) => "clk_24m",
+        Node::Source(src) => source(src).map_or("?", |(_, name, ..)| name),
+        Node::Cpu0 => "cpu0",
+        #[cfg(feature = "hpm63")]
+        Node::CpuSub(_) => "ahb",
+        #[cfg(feature = "hpm67")]
+        Node::CpuSub(sub) => ["axi", "ahb"][sub],
+        Node::Clock(clock) if clock == B::CONSOLE.clock => "console",
+        Node::Clock(clock) => NAMED_CLOCKS
+            .iter()
+            .find(|&&(c, _)| c == clock)
+            .map_or("?", |&(_, name)| name),
     }
 }
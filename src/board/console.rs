@@ -0,0 +1,224 @@
+//! The console: the board UART behind a receive and a transmit ring.
+//!
+//! Once the supervisor runs, the UART interrupt moves bytes between the rings and the
+//! FIFOs, so input typed while the kernel is busy waits in the receive ring and output
+//! is queued without waiting for the wire. Before that, or when the transmit ring is
+//! full, the rings are served by polling.
+
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::lock_api::Mutex;
+
+use super::uart::{LineErrors, Uart};
+
+const RX_LEN: usize = 1024;
+const TX_LEN: usize = 4096;
+
+/// How long [`take_over`] waits for the console before going around it.
+const TAKE_OVER_SPINS: usize = 1_000_000;
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+/// Whether the UART is set up, by [`init`] or for last words before it.
+static READY: AtomicBool = AtomicBool::new(false);
+
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns `false`, dropping `byte`, if the ring is full.
+    fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
+
+/// Input lost or damaged so far.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Errors the UART saw, overruns of its receive FIFO among them.
+    pub line: LineErrors,
+    /// Bytes dropped on a full receive ring.
+    pub rx_dropped: u32,
+}
+
+struct Console {
+    uart: Uart,
+    rx: Ring<RX_LEN>,
+    tx: Ring<TX_LEN>,
+    rx_dropped: u32,
+    /// Whether the UART interrupt serves the rings.
+    interrupts: bool,
+}
+
+impl Console {
+    /// Move received bytes to the receive ring and queued ones to the transmit FIFO.
+    fn service(&mut self) {
+        while let Some(byte) = self.uart.receive_byte() {
+            if !self.rx.push(byte) {
+                self.rx_dropped += 1;
+            }
+        }
+        self.uart.fill_tx(|| self.tx.pop());
+        // The transmit interrupt comes when the FIFO drains, as long as there is more
+        self.uart
+            .set_tx_interrupt(self.interrupts && !self.tx.is_empty());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            while !self.tx.push(byte) {
+                self.service();
+            }
+        }
+        self.service();
+        if !self.interrupts {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            self.service();
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Put the console on `uart`, served by polling.
+pub fn init(uart: Uart) {
+    *CONSOLE.lock() = Some(Console {
+        uart,
+        rx: Ring::new(),
+        tx: Ring::new(),
+        rx_dropped: 0,
+        interrupts: false,
+    });
+    READY.store(true, Ordering::Release);
+}
+
+/// Prepare for last words, such as a panic message, to go straight to the UART while
+/// the console may be held, even by this hart in the middle of a print.
+///
+/// If the console can be had within a moment, what it has queued goes out first and
+/// its interrupt stops serving it. Returns whether the UART is set up.
+pub fn take_over() -> bool {
+    for _ in 0..TAKE_OVER_SPINS {
+        if let Some(mut guard) = CONSOLE.try_lock() {
+            if let Some(console) = guard.as_mut() {
+                console.interrupts = false;
+                console.uart.set_tx_interrupt(false);
+                console.flush();
+            }
+            break;
+        }
+        spin_loop();
+    }
+    READY.load(Ordering::Acquire)
+}
+
+/// Note the UART was set up apart from [`init`], for last words.
+pub fn mark_ready() {
+    READY.store(true, Ordering::Release);
+}
+
+/// Let the UART interrupt serve the rings, once it is routed to this hart.
+pub fn enable_interrupts() {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.interrupts = true;
+        console.uart.set_rx_interrupt(true);
+        console.service();
+    }
+}
+
+/// Serve the UART interrupt.
+pub fn handle_interrupt() {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.service();
+    }
+}
+
+pub fn write_fmt(args: fmt::Arguments) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        fmt::Write::write_fmt(console, args).unwrap();
+    }
+}
+
+/// Queue `bytes` for output.
+pub fn write(bytes: &[u8]) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.write(bytes);
+    }
+}
+
+/// Move received input into `buf`, returning how much there was.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut guard = CONSOLE.lock();
+    let Some(console) = guard.as_mut() else {
+        return 0;
+    };
+    console.service();
+    let mut len = 0;
+    while let Some(byte) = buf.get_mut(len) {
+        match console.rx.pop() {
+            Some(c) => *byte = c,
+            None => break,
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Wait until the queued output is on the wire.
+pub fn flush() {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.flush();
+    }
+}
+
+pub fn stats() -> Option<Stats> {
+    CONSOLE.lock().as_ref().map(|console| Stats {
+        line: console.uart.line_errors(),
+        rx_dropped: console.rx_dropped,
+    })
+}
@@ -0,0 +1,123 @@
+//! Memory-to-memory copies on the DMA controllers.
+//!
+//! Channel 0 is programmed for one block transfer and polled to completion, with its
+//! interrupts masked. Keeping the data cache coherent is up to the caller.
+
+use super::MachineTimer;
+
+const INTSTATUS: usize = 0x30;
+const CHABORT: usize = 0x24;
+/// Registers of channel 0.
+const CH_CTRL: usize = 0x40;
+const CH_TRANSIZE: usize = 0x44;
+const CH_SRCADDR: usize = 0x48;
+const CH_SRCADDRH: usize = 0x4c;
+const CH_DSTADDR: usize = 0x50;
+const CH_DSTADDRH: usize = 0x54;
+const CH_LLPOINTER: usize = 0x58;
+const CH_LLPOINTERH: usize = 0x5c;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+/// Mask terminal count, error and abort interrupts.
+const CTRL_INT_MASK: u32 = 0b111 << 1;
+const CTRL_DSTWIDTH_SHIFT: u32 = 18;
+const CTRL_SRCWIDTH_SHIFT: u32 = 21;
+const CTRL_SRCBURSTSIZE_SHIFT: u32 = 24;
+/// 16 transfers a burst.
+const BURST_16: u32 = 4;
+
+/// Channel 0 bits in `INTSTATUS`.
+const INT_ERROR: u32 = 1 << 0;
+const INT_ABORT: u32 = 1 << 8;
+const INT_TC: u32 = 1 << 16;
+
+/// Largest `TRANSIZE`, in transfers.
+const MAX_TRANSFERS: usize = 0x0fff_ffff;
+
+/// Addresses the controllers see as the CPU does: SDRAM and XPI flash. The core-local
+/// memories have other addresses on the system bus.
+const REACHABLE: core::ops::Range<usize> = 0x4000_0000..0x9000_0000;
+
+pub struct Dma {
+    base: usize,
+    /// Widest transfer on the controller's bus, in bytes.
+    bus_width: usize,
+}
+
+impl Dma {
+    pub fn new(base: usize, bus_width: usize) -> Self {
+        Self { base, bus_width }
+    }
+
+    /// Copy `len` bytes from `src` to `dst`, both reachable. Returns whether it worked,
+    /// giving up after `timeout` ticks of `timer`.
+    pub fn copy(
+        &self,
+        dst: usize,
+        src: usize,
+        len: usize,
+        timer: &MachineTimer,
+        timeout: u64,
+    ) -> bool {
+        if !reachable(src, len) || !reachable(dst, len) {
+            return false;
+        }
+        // Widest transfer all of source, destination and length are aligned to
+        let mut width = self.bus_width;
+        while (src | dst | len) % width != 0 {
+            width /= 2;
+        }
+        let transfers = len / width;
+        if transfers == 0 || transfers > MAX_TRANSFERS {
+            return false;
+        }
+        let width_code = width.trailing_zeros();
+        unsafe {
+            self.write(INTSTATUS, INT_ERROR | INT_ABORT | INT_TC);
+            self.write(CH_CTRL, 0);
+            self.write(CH_TRANSIZE, transfers as u32);
+            self.write(CH_SRCADDR, src as u32);
+            self.write(CH_SRCADDRH, 0);
+            self.write(CH_DSTADDR, dst as u32);
+            self.write(CH_DSTADDRH, 0);
+            self.write(CH_LLPOINTER, 0);
+            self.write(CH_LLPOINTERH, 0);
+            // Incrementing addresses on both sides, no handshake
+            self.write(
+                CH_CTRL,
+                BURST_16 << CTRL_SRCBURSTSIZE_SHIFT
+                    | width_code << CTRL_SRCWIDTH_SHIFT
+                    | width_code << CTRL_DSTWIDTH_SHIFT
+                    | CTRL_INT_MASK
+                    | CTRL_ENABLE,
+            );
+        }
+        let deadline = timer.time64() + timeout;
+        loop {
+            let status = unsafe { self.read(INTSTATUS) };
+            if status & INT_TC != 0 {
+                unsafe { self.write(INTSTATUS, INT_TC) };
+                return true;
+            }
+            if status & (INT_ERROR | INT_ABORT) != 0 || timer.time64() >= deadline {
+                unsafe {
+                    self.write(CHABORT, 1);
+                    self.write(INTSTATUS, INT_ERROR | INT_ABORT | INT_TC);
+                }
+                return false;
+            }
+        }
+    }
+
+    unsafe fn read(&self, offset: usize) -> u32 {
+        ((self.base + offset) as *const u32).read_volatile()
+    }
+
+    unsafe fn write(&self, offset: usize, value: u32) {
+        ((self.base + offset) as *mut u32).write_volatile(value)
+    }
+}
+
+fn reachable(address: usize, len: usize) -> bool {
+    REACHABLE.start <= address && address.saturating_add(len) <= REACHABLE.end
+}
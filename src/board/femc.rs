@@ -2,6 +2,8 @@ use core::mem;
 use core::slice;
 
 use super::pac::femc::{vals::SdramCmd, Femc};
+use super::sdram::{self, SdramConfig};
+use crate::riscv_spec::mcctl;
 
 pub struct Sdram {
     femc: Femc,
@@ -16,10 +18,15 @@ pub struct FemcCmd {
 pub struct SdramConfigured {
     _femc: Femc,
     base_address: usize,
+    size: usize,
 }
 
 impl Sdram {
     const BASE_ADDRESS: usize = 0x4000_0000;
+    /// The SDRAM address space of the chip, the window a part is probed in.
+    const MAX_SIZE: usize = 256 * 1024 * 1024;
+    /// Smallest part there is, 16Mbit.
+    const MIN_SIZE: usize = 2 * 1024 * 1024;
 
     pub fn new(femc: Femc) -> Self {
         let sdram = Self { femc };
@@ -117,22 +124,35 @@ impl Sdram {
         Ok(())
     }
 
-    pub fn config(self) -> SdramConfigured {
+    /// Map CS0 at the SDRAM base address, `size` bytes long.
+    fn set_window(&self, size: usize) {
+        // 4KB << SIZE, CS0 valid
+        let size = size.trailing_zeros() - 12;
+        self.femc
+            .br(0)
+            .write(|w| w.0 = Sdram::BASE_ADDRESS as u32 | size << 1 | 1);
+    }
+
+    /// Set the controller up for `config` on a FEMC clock of `clock_freq`, bring the
+    /// part up and find out its size.
+    ///
+    /// Returns `None` if the part does not hold what is written to it.
+    pub fn config(self, config: &SdramConfig, clock_freq: u32) -> Option<SdramConfigured> {
         let femc = &self.femc;
+        let regs = config.registers(clock_freq);
 
-        // Set base address: 0x40000000, 32MB, CS0 valid
-        femc.br(0)
-            .write(|w| w.0 = (Sdram::BASE_ADDRESS | 0x1B) as u32);
+        // The largest window until the size is known
+        self.set_window(Sdram::MAX_SIZE);
         // Update SDRAM control
-        femc.sdrctrl0().write(|w| w.0 = 0x00000F31);
-        femc.sdrctrl1().write(|w| w.0 = 0x00774B33);
-        femc.sdrctrl2().write(|w| w.0 = 0x01020B0B);
-        femc.sdrctrl3().write(|w| w.0 = 0x1B1B0300);
+        femc.sdrctrl0().write(|w| w.0 = regs.sdrctrl[0]);
+        femc.sdrctrl1().write(|w| w.0 = regs.sdrctrl[1]);
+        femc.sdrctrl2().write(|w| w.0 = regs.sdrctrl[2]);
+        femc.sdrctrl3().write(|w| w.0 = regs.sdrctrl[3]);
         // Config data size
         femc.datsz().write(|w| w.0 = 0);
         femc.bytemsk().write(|w| w.0 = 0);
         // Config delay cell
-        self.config_delay_cell(29);
+        self.config_delay_cell(regs.delay_cell);
 
         // Issue IP command
         let mut cmd = FemcCmd {
@@ -147,16 +167,29 @@ impl Sdram {
 
         cmd.opcode = SdramCmd::MODE_SET;
         cmd.is_write = true;
-        cmd.data = 0x33;
+        cmd.data = regs.mode;
         self.issue_ip_cmd(Sdram::BASE_ADDRESS as u32, &mut cmd);
 
         // Refresh enable
         femc.sdrctrl3().modify(|m| m.set_ren(true));
 
-        SdramConfigured {
+        // The data cache is on by now, so each probed word goes to the part and back
+        let size = unsafe {
+            sdram::probe_size(
+                Sdram::BASE_ADDRESS as *mut u32,
+                Sdram::MIN_SIZE,
+                Sdram::MAX_SIZE,
+                |word| mcctl::l1d_writeback_invalidate(word as usize, 4),
+            )
+        }?;
+        // Accesses past the part fault instead of wrapping around
+        self.set_window(size);
+
+        Some(SdramConfigured {
             _femc: self.femc,
             base_address: Sdram::BASE_ADDRESS,
-        }
+            size,
+        })
     }
 }
 
@@ -164,29 +197,32 @@ impl SdramConfigured {
     pub fn base_address(&self) -> usize {
         self.base_address
     }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
 }
 
-#[allow(unused)]
-pub unsafe fn sdram_rw_test() {
+/// Fill the first `size` bytes of SDRAM with a pattern and read them back.
+///
+/// Returns the first address that does not hold the pattern.
+pub unsafe fn sdram_rw_test(size: usize) -> Result<(), usize> {
     const TEST_PATTERN: u32 = 0xA55A5AA5;
 
     let dst = slice::from_raw_parts_mut(
         Sdram::BASE_ADDRESS as *mut u32,
-        32 * 1024 * 1024 / mem::size_of::<u32>(),
+        size / mem::size_of::<u32>(),
     );
-    let mut start = Sdram::BASE_ADDRESS;
-    let end = Sdram::BASE_ADDRESS + 32 * 1024 * 1024;
 
     dst.fill(TEST_PATTERN);
 
-    assert_eq!(
-        dst.iter().try_fold(Sdram::BASE_ADDRESS, |addr, x| {
+    dst.iter()
+        .try_fold(Sdram::BASE_ADDRESS, |addr, x| {
             if *x == TEST_PATTERN {
                 Ok(addr + mem::size_of_val(x))
             } else {
                 Err(addr)
             }
-        }),
-        Ok(end)
-    );
+        })
+        .map(|_| ())
 }
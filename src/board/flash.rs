@@ -0,0 +1,191 @@
+//! XPI NOR flash programming through the boot ROM driver.
+//!
+//! The ROM routines execute from ROM, so they can be called while the firmware runs in
+//! place from the same flash, as long as nothing is fetched from flash until they return.
+//! Interrupts are therefore masked around every call.
+
+use core::ffi::c_void;
+use core::fmt;
+
+use riscv::register::mstatus;
+
+use super::pac::xpi::Xpi;
+use crate::riscv_spec::mcctl;
+
+/// Where XPI0 flash is mapped.
+pub const FLASH_BASE: usize = 0x8000_0000;
+/// Smallest erasable unit.
+pub const SECTOR_SIZE: usize = 4 * 1024;
+
+/// `ROM_API_TABLE_ROOT` of the boot ROM, the same in the SDK's `soc/HPM6360/hpm_romapi.h`
+/// and `soc/HPM6750/hpm_romapi.h`.
+const ROM_API_TABLE: *const RomApiTable = 0x2001_FF00 as _;
+
+/// Options for `get_config`, matching what the boot ROM used to boot from this flash.
+const NOR_CONFIG_OPTION: [u32; 4] = [0xfcf9_0001, 0x0000_0005, 0x0000_1000, 0];
+/// `xpi_xfer_channel_auto`.
+const CHANNEL_AUTO: u32 = 4;
+
+type Status = u32;
+const STATUS_SUCCESS: Status = 0;
+
+#[allow(unused)]
+#[repr(C)]
+struct RomApiTable {
+    version: u32,
+    copyright: *const u8,
+    run_bootloader: usize,
+    otp_driver_if: usize,
+    xpi_driver_if: usize,
+    xpi_nor_driver_if: *const XpiNorDriver,
+}
+
+#[allow(unused)]
+#[repr(C)]
+struct XpiNorDriver {
+    version: u32,
+    get_config: unsafe extern "C" fn(*mut c_void, *mut NorConfig, *const u32) -> Status,
+    init: usize,
+    enable_write: usize,
+    get_status: usize,
+    wait_busy: usize,
+    erase: unsafe extern "C" fn(*mut c_void, u32, *const NorConfig, u32, u32) -> Status,
+    erase_chip: usize,
+    erase_sector: usize,
+    erase_block: usize,
+    program:
+        unsafe extern "C" fn(*mut c_void, u32, *const NorConfig, *const u32, u32, u32) -> Status,
+}
+
+/// `xpi_nor_config_t`, only ever filled in and read by the ROM. Sized generously.
+#[repr(C, align(4))]
+struct NorConfig([u8; 1024]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    /// The flash was not configured by [`board_init`](super::board_init).
+    NotConfigured,
+    /// The range is outside the flash or not aligned to what the operation needs.
+    BadRange,
+    /// A ROM routine returned this status.
+    Rom(u32),
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::NotConfigured => write!(f, "flash not configured"),
+            FlashError::BadRange => write!(f, "bad flash range"),
+            FlashError::Rom(status) => write!(f, "ROM driver status {status}"),
+        }
+    }
+}
+
+pub struct XpiNor {
+    base: *mut c_void,
+    config: NorConfig,
+    size: usize,
+}
+
+// The XPI registers are only touched with the board's flash lock held
+unsafe impl Send for XpiNor {}
+
+impl XpiNor {
+    /// Read back the configuration the boot ROM set up, without reprogramming the XPI.
+    pub fn new(xpi: Xpi, size: usize) -> Result<Self, FlashError> {
+        let mut flash = Self {
+            base: xpi.as_ptr() as _,
+            config: NorConfig([0; 1024]),
+            size,
+        };
+        let status = unsafe {
+            rom_call(|driver| {
+                (driver.get_config)(flash.base, &mut flash.config, NOR_CONFIG_OPTION.as_ptr())
+            })
+        };
+        check(status)?;
+        Ok(flash)
+    }
+
+    /// Erase whole sectors covering `[address, address + len)`.
+    pub fn erase(&mut self, address: usize, len: usize) -> Result<(), FlashError> {
+        let offset = self.offset(address, len)?;
+        if offset % SECTOR_SIZE != 0 || len % SECTOR_SIZE != 0 {
+            return Err(FlashError::BadRange);
+        }
+        let status = unsafe {
+            rom_call(|driver| {
+                (driver.erase)(
+                    self.base,
+                    CHANNEL_AUTO,
+                    &self.config,
+                    offset as u32,
+                    len as u32,
+                )
+            })
+        };
+        mcctl::l1d_invalidate(address, len);
+        check(status)
+    }
+
+    /// Program `data` to erased flash at `address`.
+    pub fn program(&mut self, address: usize, data: &[u8]) -> Result<(), FlashError> {
+        let offset = self.offset(address, data.len())?;
+        // The ROM wants a word aligned source, so copy through a buffer
+        let mut buf = [0u32; 64];
+        for (i, chunk) in data.chunks(core::mem::size_of_val(&buf)).enumerate() {
+            for (word, bytes) in buf.iter_mut().zip(chunk.chunks(4)) {
+                let mut b = [0xFF; 4];
+                b[..bytes.len()].copy_from_slice(bytes);
+                *word = u32::from_le_bytes(b);
+            }
+            let dst = offset + i * core::mem::size_of_val(&buf);
+            let status = unsafe {
+                rom_call(|driver| {
+                    (driver.program)(
+                        self.base,
+                        CHANNEL_AUTO,
+                        &self.config,
+                        buf.as_ptr(),
+                        dst as u32,
+                        chunk.len() as u32,
+                    )
+                })
+            };
+            check(status)?;
+        }
+        // Drop stale flash contents, so reads see the new data
+        mcctl::l1d_invalidate(address, data.len());
+        Ok(())
+    }
+
+    /// Offset of `[address, address + len)` into the flash.
+    fn offset(&self, address: usize, len: usize) -> Result<usize, FlashError> {
+        let offset = address
+            .checked_sub(FLASH_BASE)
+            .ok_or(FlashError::BadRange)?;
+        if offset + len > self.size {
+            return Err(FlashError::BadRange);
+        }
+        Ok(offset)
+    }
+}
+
+/// Call into the ROM flash driver with interrupts masked.
+unsafe fn rom_call(f: impl FnOnce(&XpiNorDriver) -> Status) -> Status {
+    let driver = &*(*ROM_API_TABLE).xpi_nor_driver_if;
+    let mie = mstatus::read().mie();
+    mstatus::clear_mie();
+    let status = f(driver);
+    if mie {
+        mstatus::set_mie();
+    }
+    status
+}
+
+fn check(status: Status) -> Result<(), FlashError> {
+    match status {
+        STATUS_SUCCESS => Ok(()),
+        status => Err(FlashError::Rom(status)),
+    }
+}
@@ -0,0 +1,110 @@
+//! A custom board around an HPM6300-series chip.
+//!
+//! Starts out wired like the HPM6360EVK, without the LED: console on UART0, SDRAM on
+//! the FEMC pins of ports A and B. Adapt the pin table, the SDRAM part and the flash
+//! size to the board, and set `MODEL` and `COMPATIBLE` to what its device tree expects.
+
+use super::clock::{clocks, resources, ClockMux};
+use super::pac;
+use super::pin::PinCtrl;
+use super::sdram::SdramConfig;
+use super::uart::UartConfig;
+use super::{Board, Console};
+
+pins!(
+    'A': [
+        (PA25, sdram_0,  25, 12),
+        (PA26, sdram_1,  26, 12),
+        (PA27, sdram_2,  27, 12),
+        (PA28, sdram_3,  28, 12),
+        (PA29, sdram_4,  29, 12),
+        (PA30, sdram_5,  30, 12),
+        (PA31, sdram_6,  31, 12),
+    ],
+    'B': [
+        (PB00, sdram_7,   0, 12),
+        (PB01, sdram_8,   1, 12),
+        (PB02, sdram_9,   2, 12),
+        (PB03, sdram_10,  3, 12),
+        (PB04, sdram_11,  4, 12),
+        (PB05, sdram_12,  5, 12),
+        (PB06, sdram_13,  6, 12),
+        (PB07, sdram_14,  7, 12),
+        (PB08, sdram_15,  8, 12),
+        (PB09, sdram_16,  9, 12),
+        (PB10, sdram_17, 10, 12),
+        (PB11, sdram_18, 11, 12),
+        (PB12, sdram_19, 12, 12),
+        (PB13, sdram_20, 13, 12),
+        (PB14, sdram_21, 14, 12),
+        (PB15, sdram_22, 15, 12),
+        (PB16, sdram_23, 16, 12),
+        (PB17, sdram_24, 17, 12),
+        (PB18, sdram_25, 18, 12),
+        (PB19, sdram_26, 19, 12),
+        (PB20, sdram_27, 20, 12),
+        (PB21, sdram_28, 21, 12),
+        (PB22, sdram_29, 22, 12),
+        (PB23, sdram_30, 23, 12),
+        (PB24, sdram_31, 24, 12),
+        (PB25, sdram_32, 25, 12),
+        (PB26, sdram_33, 26, 12),
+        (PB27, sdram_34, 27, 12),
+        (PB28, sdram_35, 28, 12),
+        (PB29, sdram_36, 29, 12),
+        (PB30, sdram_37, 30, 12),
+        (PB31, sdram_38, 31, 12),
+    ],
+    'Y': [
+        (PY06, uart0_tx, 6, 2),
+        (PY07, uart0_rx, 7, 2),
+    ]
+);
+
+impl<'a> Pins<'a> {
+    pub fn setup(&self) {
+        // Setup UART0 and SDRAM pinmux from the pin table
+        self.set_mode_alternate();
+    }
+}
+
+fn pinctrl() -> PinCtrl {
+    PinCtrl::new(pac::GPIO0, pac::IOC, pac::PIOC)
+}
+
+pub struct Hpm6300Custom;
+
+impl Board for Hpm6300Custom {
+    const MODEL: &'static str = "HPM6300 custom board";
+    const COMPATIBLE: &'static str = "hpmicro,hpm6300-custom";
+    const CONSOLE: Console = Console {
+        uart: pac::UART0,
+        clock: clocks::URT0,
+        resource: resources::URT0,
+        irq: pac::Interrupt::UART0,
+        config: UartConfig::new(115_200),
+    };
+    // PLL0CLK1, 333MHz, halved
+    const SDRAM_CLOCK: (ClockMux, u8) = (ClockMux::PLL0CLK1, 1);
+    // 4 banks of 512 columns, with the timing of the HPM SDK for the part
+    const SDRAM: SdramConfig = SdramConfig {
+        bus_width: 16,
+        cas_latency: 3,
+        column_bits: 9,
+        bank_bits: 2,
+        t_rc: 66,
+        t_rcd: 18,
+        t_rp: 18,
+        t_ras: 42,
+        t_wr: 12,
+        t_rrd: 12,
+        // 64ms over 8192 rows
+        t_ref: 7812,
+        delay_cell: 29,
+    };
+    const FLASH_SIZE: usize = 16 * 1024 * 1024;
+
+    fn init_pins() {
+        pinctrl().split().setup();
+    }
+}
@@ -0,0 +1,123 @@
+//! HPM6750EVK: HPM6750 with 32MB of 32-bit SDRAM and 16MB of flash.
+
+use super::clock::{clocks, resources, ClockMux};
+use super::pac;
+use super::pin::PinCtrl;
+use super::sdram::SdramConfig;
+use super::uart::UartConfig;
+use super::{Board, Console};
+
+pins!(
+    'B': [
+        (PB19, led,      19,  0),
+    ],
+    'C': [
+        (PC00, sdram_0,   0, 12),
+        (PC01, sdram_1,   1, 12),
+        (PC02, sdram_2,   2, 12),
+        (PC03, sdram_3,   3, 12),
+        (PC04, sdram_4,   4, 12),
+        (PC05, sdram_5,   5, 12),
+        (PC06, sdram_6,   6, 12),
+        (PC07, sdram_7,   7, 12),
+        (PC08, sdram_8,   8, 12),
+        (PC09, sdram_9,   9, 12),
+        (PC10, sdram_10, 10, 12),
+        (PC11, sdram_11, 11, 12),
+        (PC12, sdram_12, 12, 12),
+        (PC13, sdram_13, 13, 12),
+        (PC14, sdram_14, 14, 12),
+        (PC15, sdram_15, 15, 12),
+        (PC16, sdram_16, 16, 12),
+        (PC17, sdram_17, 17, 12),
+        (PC18, sdram_18, 18, 12),
+        (PC19, sdram_19, 19, 12),
+        (PC20, sdram_20, 20, 12),
+        (PC21, sdram_21, 21, 12),
+        (PC22, sdram_22, 22, 12),
+        (PC23, sdram_23, 23, 12),
+        (PC24, sdram_24, 24, 12),
+        (PC25, sdram_25, 25, 12),
+        (PC26, sdram_26, 26, 12),
+        (PC27, sdram_27, 27, 12),
+        (PC28, sdram_28, 28, 12),
+        (PC29, sdram_29, 29, 12),
+        (PC30, sdram_30, 30, 12),
+        (PC31, sdram_31, 31, 12),
+    ],
+    'D': [
+        (PD00, sdram_32,  0, 12),
+        (PD01, sdram_33,  1, 12),
+        (PD02, sdram_34,  2, 12),
+        (PD03, sdram_35,  3, 12),
+        (PD04, sdram_36,  4, 12),
+        (PD05, sdram_37,  5, 12),
+        (PD06, sdram_38,  6, 12),
+        (PD07, sdram_39,  7, 12),
+        (PD08, sdram_40,  8, 12),
+        (PD09, sdram_41,  9, 12),
+        (PD10, sdram_42, 10, 12),
+        (PD11, sdram_43, 11, 12),
+        (PD12, sdram_44, 12, 12),
+        (PD13, sdram_45, 13, 12),
+    ],
+    'Y': [
+        (PY06, uart0_tx, 6, 2),
+        (PY07, uart0_rx, 7, 2),
+    ]
+);
+
+impl<'a> Pins<'a> {
+    pub fn setup(&self) {
+        // Setup LED pinmux
+        self.led.output_enable(true).set_push_pull();
+        // Setup UART0 and SDRAM pinmux from the pin table
+        self.set_mode_alternate();
+    }
+}
+
+fn pinctrl() -> PinCtrl {
+    PinCtrl::new(pac::GPIO0, pac::IOC, pac::PIOC)
+}
+
+pub struct Hpm6750Evk;
+
+impl Board for Hpm6750Evk {
+    const MODEL: &'static str = "HPM6750EVK";
+    const COMPATIBLE: &'static str = "hpmicro,hpm6750evk";
+    const CONSOLE: Console = Console {
+        uart: pac::UART0,
+        clock: clocks::URT0,
+        resource: resources::URT0,
+        irq: pac::Interrupt::UART0,
+        config: UartConfig::new(115_200),
+    };
+    // PLL2CLK0, 333MHz, halved
+    const SDRAM_CLOCK: (ClockMux, u8) = (ClockMux::PLL2CLK0, 1);
+    // 4 banks of 512 columns, with the timing of the HPM SDK for the part
+    const SDRAM: SdramConfig = SdramConfig {
+        bus_width: 32,
+        cas_latency: 3,
+        column_bits: 9,
+        bank_bits: 2,
+        t_rc: 66,
+        t_rcd: 18,
+        t_rp: 18,
+        t_ras: 42,
+        t_wr: 12,
+        t_rrd: 12,
+        // 64ms over 8192 rows
+        t_ref: 7812,
+        delay_cell: 29,
+    };
+    const FLASH_SIZE: usize = 16 * 1024 * 1024;
+
+    fn init_pins() {
+        pinctrl().split().setup();
+    }
+
+    /// The red LED of the RGB one.
+    fn set_led(on: bool) {
+        pinctrl().split().led.set_bool(on);
+    }
+}
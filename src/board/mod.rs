@@ -1,25 +1,142 @@
-use core::{
-    fmt::{self, Write},
-    mem::MaybeUninit,
-};
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use hpm_metapac as pac;
 use hpm_rt;
-use spin::lock_api::Mutex;
+use log::{info, warn};
+use riscv::register::mhartid;
+use spin::{lock_api::Mutex, Once};
+
+use crate::profile::{self, Stage};
+use crate::riscv_spec::mcctl;
 
 mod clock;
+mod console;
+mod dma;
 mod femc;
+mod flash;
 mod mchtmr;
+#[macro_use]
 mod pin;
+mod plic;
+mod plicsw;
+mod pll;
+mod sdram;
 mod uart;
 
-use clock::{clocks, ClockConfigurator};
+#[cfg(feature = "hpm6300-custom")]
+mod hpm6300_custom;
+#[cfg(feature = "hpm6360evk")]
+mod hpm6360evk;
+#[cfg(feature = "hpm6750evk")]
+mod hpm6750evk;
+#[cfg(feature = "hpm6750evkmini")]
+mod hpm6750evkmini;
+
+use clock::{clocks, ClockConfig, ClockConfigurator, ClockMux, Clocks, Node, CONSOLE_CLOCK_FREQ};
+pub use console::{read as console_read, write as console_write};
+use dma::Dma;
 use femc::Sdram;
+pub use flash::{FlashError, XpiNor, FLASH_BASE, SECTOR_SIZE as FLASH_SECTOR_SIZE};
 pub use mchtmr::MachineTimer;
-use pin::PinCtrl;
-use uart::Uart;
+pub use plicsw::{clear as clear_ipi, send as send_ipi};
+use sdram::SdramConfig;
+use uart::{Uart, UartConfig};
+
+/// The board the firmware is built for, chosen by cargo feature.
+#[cfg(feature = "hpm6300-custom")]
+type ActiveBoard = hpm6300_custom::Hpm6300Custom;
+#[cfg(feature = "hpm6360evk")]
+type ActiveBoard = hpm6360evk::Hpm6360Evk;
+#[cfg(feature = "hpm6750evk")]
+type ActiveBoard = hpm6750evk::Hpm6750Evk;
+#[cfg(feature = "hpm6750evkmini")]
+type ActiveBoard = hpm6750evkmini::Hpm6750EvkMini;
+
+/// Board name reported in the banner and in generated device trees.
+pub const MODEL: &str = ActiveBoard::MODEL;
+pub const COMPATIBLE: &str = ActiveBoard::COMPATIBLE;
+/// Console baud rate of the board, unless the environment sets `baudrate`.
+pub const CONSOLE_BAUDRATE: u32 = ActiveBoard::CONSOLE.config.baudrate;
+pub const FLASH_SIZE: usize = ActiveBoard::FLASH_SIZE;
+/// Harts of the chip: two D45 cores on HPM6700, one on HPM6300.
+#[cfg(feature = "hpm67")]
+pub const NUM_HARTS: usize = 2;
+#[cfg(feature = "hpm63")]
+pub const NUM_HARTS: usize = 1;
+/// AXI SRAM, where the runtime places data shared between the cores of HPM6700.
+#[cfg(feature = "hpm67")]
+const SHARED_RAM: (usize, usize) = (0x0108_0000, 512 * 1024);
+
+/// What tells one board from another: wiring, memory and the chip's peripherals.
+///
+/// Each board lives in its own module and is selected by the cargo feature of the same
+/// name. Chip-level parts, such as the timer and the reset, have defaults a board may
+/// override.
+pub trait Board {
+    /// Board name reported in the banner and in generated device trees.
+    const MODEL: &'static str;
+    const COMPATIBLE: &'static str;
+    /// PLLs and CPU0 clocks, set before anything else.
+    const CLOCKS: ClockConfig = ClockConfig::ROM;
+    /// UART the console runs on.
+    const CONSOLE: Console;
+    /// Source and divider of the SDRAM clock, one less than the division.
+    const SDRAM_CLOCK: (ClockMux, u8);
+    /// The SDRAM part, set up for the clock above.
+    const SDRAM: SdramConfig;
+    /// Size of the boot flash on XPI0. build.rs reads it for the boot header, so keep it
+    /// a product of integer literals.
+    const FLASH_SIZE: usize;
+
+    /// Route the console, the SDRAM and the LED to their pins.
+    fn init_pins();
+
+    /// Turn the status LED on or off, if the board has one.
+    fn set_led(_on: bool) {}
+
+    fn timer() -> MachineTimer {
+        MachineTimer::new(pac::MCHTMR)
+    }
 
-static UART: Mutex<MaybeUninit<Uart>> = Mutex::new(MaybeUninit::uninit());
+    /// Reset the chip through the PPOR software reset.
+    fn reset() -> ! {
+        // Allow the software reset source, then fire it after a short count
+        pac::PPOR.reset_enable().modify(|w| w.0 |= 1 << 31);
+        pac::PPOR.software_reset().write(|w| w.0 = 1000);
+        loop {
+            unsafe { riscv::asm::wfi() };
+        }
+    }
+}
+
+/// A UART and its place in the clock tree.
+pub struct Console {
+    pub uart: pac::uart::Uart,
+    /// Clock node, from `clocks`.
+    pub clock: usize,
+    /// Resource linked into the clock group, from `resources`.
+    pub resource: usize,
+    pub irq: pac::Interrupt,
+    /// Frame format and the baud rate used unless the environment sets another.
+    pub config: UartConfig,
+}
+
+static FLASH: Mutex<Option<XpiNor>> = Mutex::new(None);
+static BOARD_INFO: Once<BoardInfo> = Once::new();
+/// Clock of the machine timer, 0 until [`board_init`] has set it.
+static MCHTMR_FREQ: AtomicU32 = AtomicU32::new(0);
+
+/// Hardware configuration settled by [`board_init`], passed on to the kernel.
+pub struct BoardInfo {
+    pub cpu_clock_freq: u32,
+    pub mchtmr_clock_freq: u32,
+    pub uart_base: usize,
+    pub uart_baudrate: u32,
+    pub uart_clock_freq: u32,
+    pub sdram_base: usize,
+    pub sdram_size: usize,
+}
 
 #[macro_export]
 macro_rules! print {
@@ -28,67 +145,268 @@ macro_rules! print {
     };
 }
 
+/// Prints a line in one go, so lines from different harts do not mix.
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => {{
-        $crate::board::putchar(core::format_args!($($arg)*));
-        $crate::println!();
+        $crate::board::putchar(core::format_args!("{}\n", core::format_args!($($arg)*)));
     }}
 }
 
-pub fn board_init() {
+pub fn board_init(console_baudrate: u32) {
+    hart_init();
+
+    let clock = profile::measure(Stage::ClockFreeze, || unsafe {
+        ClockConfigurator::new(pac::SYSCTL, pac::PLLCTL).freeze::<ActiveBoard>()
+    });
+
+    ActiveBoard::init_pins();
+    ActiveBoard::set_led(true);
+
+    let console = ActiveBoard::CONSOLE;
+    let uart_clock_freq = clock.freq(Node::Clock(console.clock));
+    let mut uart = Uart::new(console.uart);
+    let mut uart_config = UartConfig {
+        baudrate: console_baudrate,
+        ..console.config
+    };
+    // A baud rate out of reach falls back to the one of the board
+    let uart_error = uart.setup(&uart_config, uart_clock_freq).err();
+    if uart_error.is_some() {
+        uart_config = console.config;
+        uart.setup(&uart_config, uart_clock_freq)
+            .expect("console baud rate of the board unreachable");
+    }
+    console::init(uart);
+    if let Some(e) = uart_error {
+        warn!(
+            "Console baud rate      : {e}, using {}",
+            uart_config.baudrate
+        );
+    }
+
+    let cpu0_clock_freq = clock.freq(Node::Cpu0);
+    let mchtmr_clock_freq = clock.freq(Node::Clock(clocks::MCT0));
+    MCHTMR_FREQ.store(mchtmr_clock_freq, Ordering::Relaxed);
+    let sdram_clock_freq = clock.freq(Node::Clock(clocks::FEMC));
+    let sdram = profile::measure(Stage::SdramConfig, || {
+        Sdram::new(pac::FEMC).config(&ActiveBoard::SDRAM, sdram_clock_freq)
+    })
+    .expect("SDRAM does not respond");
+    info!("CPU0 clock frequency   : {cpu0_clock_freq}Hz");
+    info!("MCHTMR clock frequency : {mchtmr_clock_freq}Hz");
+    info!("SDRAM clock frequency  : {sdram_clock_freq}Hz");
+    info!("SDRAM base address     : {:#010x}", sdram.base_address());
+    info!(
+        "SDRAM size             : {} MiB",
+        sdram.size() / (1024 * 1024)
+    );
+
+    match XpiNor::new(pac::XPI0, FLASH_SIZE) {
+        Ok(flash) => *FLASH.lock() = Some(flash),
+        Err(e) => warn!("Flash not writable     : {e}"),
+    }
+
+    BOARD_INFO.call_once(|| BoardInfo {
+        cpu_clock_freq: cpu0_clock_freq,
+        mchtmr_clock_freq,
+        uart_base: console.uart.as_ptr() as usize,
+        uart_baudrate: uart_config.baudrate,
+        uart_clock_freq,
+        sdram_base: sdram.base_address(),
+        sdram_size: sdram.size(),
+    });
+}
+
+/// Set up what each hart has of its own: the caches and software interrupts.
+pub fn hart_init() {
+    // The cores do not snoop each other's data caches, so shared data is not cached
+    #[cfg(feature = "hpm67")]
+    {
+        use crate::riscv_spec::pma;
+        pma::set_napot(
+            SHARED_RAM.0,
+            SHARED_RAM.1,
+            pma::MEM_NON_CACHEABLE_BUFFERABLE,
+        )
+        .expect("no PMA entry left for the shared RAM");
+    }
     hpm_rt::cache::icache_enable();
     hpm_rt::cache::dcache_enable();
+    plicsw::init(riscv::register::mhartid::read());
+}
 
-    let clock = unsafe { ClockConfigurator::new(pac::SYSCTL, pac::PLLCTL).freeze() };
-
-    let pinctrl = PinCtrl::new(pac::GPIO0, pac::IOC, pac::PIOC);
-    let pins = pinctrl.split();
-    pins.setup();
-
-    let uart = Uart::new(pac::UART0);
-    uart.setup(115_200, clock.get_clk_freq(clocks::URT0));
-    *UART.lock() = MaybeUninit::new(uart);
-
-    let cpu0_clock_freq = clock.get_cpu0_clk_freq();
-    let mchtmr_clock_freq = clock.get_clk_freq(clocks::MCT0);
-    let sdram_clock_freq = clock.get_clk_freq(clocks::FEMC);
-    let sdram = Sdram::new(pac::FEMC).config();
-    println!(
-        "\
-[rustsbi pre-init] CPU0 clock frequency   : {}Hz
-[rustsbi pre-init] MCHTMT clock frequency : {}Hz
-[rustsbi pre-init] SDRAM clock frequency  : {}Hz
-[rustsbi pre-init] SDRAM base address     : {:#010x}",
-        cpu0_clock_freq,
-        mchtmr_clock_freq,
-        sdram_clock_freq,
-        sdram.base_address()
-    );
+/// Let the harts other than the boot hart run from `entry`.
+///
+/// CPU1 is held halted by the boot ROM until its entry is set with the release key and
+/// the halt request is cleared.
+#[cfg(feature = "hpm67")]
+pub fn release_secondary_harts(entry: usize) {
+    const CPU1_RELEASE_KEY: u32 = 0xC1BE_F1A9;
+    let cpu1 = pac::SYSCTL.cpu(1);
+    cpu1.gpr(0).write(|w| w.0 = entry as u32);
+    cpu1.gpr(1).write(|w| w.0 = CPU1_RELEASE_KEY);
+    cpu1.lp().modify(|w| w.set_halt(false));
 }
 
+#[cfg(feature = "hpm63")]
+pub fn release_secondary_harts(_entry: usize) {}
+
+/// Board configuration, available after [`board_init`].
+pub fn board_info() -> &'static BoardInfo {
+    BOARD_INFO.get().expect("board_init has not run")
+}
+
+/// Run `f` on the boot flash, configured by [`board_init`].
+pub fn with_flash<R>(
+    f: impl FnOnce(&mut XpiNor) -> Result<R, FlashError>,
+) -> Result<R, FlashError> {
+    let mut guard = FLASH.lock();
+    f(guard.as_mut().ok_or(FlashError::NotConfigured)?)
+}
+
+/// Print to the console; dropped before [`board_init`] sets it up.
 #[inline]
 pub fn putchar(args: fmt::Arguments) {
-    let mut guard = UART.lock();
+    console::write_fmt(args);
+}
 
-    unsafe { guard.assume_init_mut().write_fmt(args).unwrap() }
+/// Print straight to the console UART, for the panic handler.
+///
+/// Works while the console is held and before [`board_init`], when the UART is set up
+/// here at the settings of the board.
+pub fn emergency_print(args: fmt::Arguments) {
+    let console = ActiveBoard::CONSOLE;
+    let mut uart = Uart::new(console.uart);
+    if !console::take_over() {
+        unsafe { ClockConfigurator::new(pac::SYSCTL, pac::PLLCTL).enable_console::<ActiveBoard>() };
+        ActiveBoard::init_pins();
+        // Nothing to tell of a failure with
+        let _ = uart.setup(&console.config, CONSOLE_CLOCK_FREQ);
+        console::mark_ready();
+    }
+    let _ = fmt::Write::write_fmt(&mut uart, args);
 }
 
+/// Take a byte of console input, `usize::MAX` if there is none.
 #[inline]
 pub fn getchar() -> usize {
-    let mut guard = UART.lock();
-    let mut c: u8 = 0;
-
-    unsafe {
-        if guard.assume_init_mut().receive_byte(&mut c) {
-            c as _
-        } else {
-            usize::MAX
+    let mut c = [0];
+    match console::read(&mut c) {
+        0 => usize::MAX,
+        _ => c[0] as _,
+    }
+}
+
+/// Serve the console on its interrupt from now on, which this hart takes.
+pub fn enable_console_interrupt() {
+    plic::enable(mhartid::read(), ActiveBoard::CONSOLE.irq as usize);
+    console::enable_interrupts();
+}
+
+/// Serve the machine external interrupts pending for this hart.
+pub fn handle_external_interrupt() {
+    let hart = mhartid::read();
+    while let Some(irq) = plic::claim(hart) {
+        if irq == ActiveBoard::CONSOLE.irq as usize {
+            console::handle_interrupt();
         }
+        plic::complete(hart, irq);
+    }
+}
+
+/// Console input lost so far: overruns of the UART FIFO, line errors and bytes dropped
+/// on a full receive buffer, in this order. `None` for an index past them.
+pub fn console_error_count(index: usize) -> Option<u32> {
+    let stats = console::stats()?;
+    let line = stats.line;
+    [
+        line.overrun,
+        line.parity,
+        line.framing,
+        line.line_break,
+        stats.rx_dropped,
+    ]
+    .get(index)
+    .copied()
+}
+
+/// Print the console settings and the input lost so far.
+pub fn print_console() {
+    let config = UartConfig {
+        baudrate: board_info().uart_baudrate,
+        ..ActiveBoard::CONSOLE.config
+    };
+    println!("{config}");
+    if let Some(console::Stats { line, rx_dropped }) = console::stats() {
+        println!(
+            "overrun {}, parity {}, framing {}, break {}, buffer full {}",
+            line.overrun, line.parity, line.framing, line.line_break, rx_dropped
+        );
     }
 }
 
+/// Copy `src` to `dst`, on XDMA, else HDMA, else the CPU. Returns whether DMA copied.
+///
+/// The data cache is written back and invalidated around a DMA copy, so the CPU sees
+/// the result.
+pub fn copy(dst: &mut [u8], src: &[u8]) -> bool {
+    assert_eq!(dst.len(), src.len());
+    let (dst_addr, src_addr, len) = (dst.as_mut_ptr() as usize, src.as_ptr() as usize, src.len());
+    let timer = board_init_timer();
+    // At least 1 MB/s, plus some slack to start
+    let freq = board_info().mchtmr_clock_freq as u64;
+    let timeout = freq / 10 + len as u64 * freq / 1_000_000;
+
+    mcctl::l1d_writeback_invalidate(dst_addr, len);
+    for (base, bus_width) in [
+        (pac::XDMA.as_ptr() as usize, 8),
+        (pac::HDMA.as_ptr() as usize, 4),
+    ] {
+        if Dma::new(base, bus_width).copy(dst_addr, src_addr, len, &timer, timeout) {
+            // Lines fetched by speculation meanwhile are stale
+            mcctl::l1d_invalidate(dst_addr, len);
+            return true;
+        }
+    }
+    dst.copy_from_slice(src);
+    false
+}
+
+/// Print the clock tree with the frequency of each node.
+pub fn print_clocks() {
+    Clocks::new(pac::SYSCTL, pac::PLLCTL).dump::<ActiveBoard>();
+}
+
+/// Write and read back the whole SDRAM, destroying its contents.
+///
+/// Returns the first address that failed.
+pub fn sdram_test() -> Result<(), usize> {
+    unsafe { femc::sdram_rw_test(board_info().sdram_size) }
+}
+
+pub fn reset() -> ! {
+    console::flush();
+    ActiveBoard::reset()
+}
+
+/// Microseconds on the machine timer, once [`board_init`] has set its clock.
+pub fn time_us() -> Option<u64> {
+    let freq = MCHTMR_FREQ.load(Ordering::Relaxed);
+    (freq != 0).then(|| us(ActiveBoard::timer().time64(), freq as u64))
+}
+
+/// Machine timer `ticks` in microseconds. Call after [`board_init`].
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    us(ticks, board_info().mchtmr_clock_freq as u64)
+}
+
+/// Whole seconds are scaled apart from the rest, so no tick count overflows.
+fn us(ticks: u64, freq: u64) -> u64 {
+    ticks / freq * 1_000_000 + ticks % freq * 1_000_000 / freq
+}
+
 pub fn board_init_timer() -> MachineTimer {
-    MachineTimer::new(pac::MCHTMR)
+    ActiveBoard::timer()
 }
@@ -0,0 +1,48 @@
+//! Machine external interrupts on the PLIC, for the devices the firmware serves itself.
+//!
+//! Hart `n` takes machine interrupts on context `2 * n`; context `2 * n + 1` is its
+//! supervisor's and left to the kernel.
+
+use core::ptr::{read_volatile, write_volatile};
+
+const BASE: usize = 0xE400_0000;
+/// Priority of source 0, followed by the other sources.
+const PRIORITY: usize = 0x0;
+/// Enable bits of context 0, each context `ENABLE_STRIDE` after the previous one.
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// Threshold, then claim and complete register, of context 0, each context
+/// `CONTEXT_STRIDE` after the previous one.
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+fn reg(offset: usize) -> *mut u32 {
+    (BASE + offset) as *mut u32
+}
+
+fn context(hart: usize) -> usize {
+    hart * 2
+}
+
+/// Route source `irq` to the machine mode of `hart`.
+pub fn enable(hart: usize, irq: usize) {
+    let context = context(hart);
+    unsafe {
+        write_volatile(reg(PRIORITY + irq * 4), 1);
+        write_volatile(reg(THRESHOLD + context * CONTEXT_STRIDE), 0);
+        let enable = reg(ENABLE + context * ENABLE_STRIDE + irq / 32 * 4);
+        write_volatile(enable, read_volatile(enable) | 1 << (irq % 32));
+    }
+}
+
+/// Take the highest priority interrupt pending for the machine mode of `hart`.
+pub fn claim(hart: usize) -> Option<usize> {
+    let irq = unsafe { read_volatile(reg(CLAIM + context(hart) * CONTEXT_STRIDE)) };
+    (irq != 0).then_some(irq as usize)
+}
+
+/// Let source `irq`, claimed by `hart`, interrupt again.
+pub fn complete(hart: usize, irq: usize) {
+    unsafe { write_volatile(reg(CLAIM + context(hart) * CONTEXT_STRIDE), irq as u32) };
+}
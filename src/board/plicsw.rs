@@ -0,0 +1,49 @@
+//! Machine software interrupts between harts on the Andes PLIC_SW.
+//!
+//! Hart `n` listens to source `n + 1`, source 0 being reserved. Setting a source
+//! pending raises `mip.MSIP` on its hart until the hart claims and completes it.
+
+use core::ptr::{read_volatile, write_volatile};
+
+const BASE: usize = 0xE640_0000;
+/// Priority of source 1, followed by the other sources.
+const PRIORITY: usize = 0x4;
+const PENDING: usize = 0x1000;
+/// Enable bits of hart 0, each hart `ENABLE_STRIDE` after the previous one.
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// Claim and complete register of hart 0, each hart `CONTEXT_STRIDE` after the
+/// previous one.
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+fn reg(offset: usize) -> *mut u32 {
+    (BASE + offset) as *mut u32
+}
+
+/// Let `hart` take software interrupts. Runs on every hart before it waits for one.
+pub fn init(hart: usize) {
+    let source = hart + 1;
+    unsafe {
+        write_volatile(reg(PRIORITY + hart * 4), 1);
+        let enable = reg(ENABLE + hart * ENABLE_STRIDE + source / 32 * 4);
+        write_volatile(enable, read_volatile(enable) | 1 << (source % 32));
+    }
+}
+
+/// Interrupt `hart`.
+pub fn send(hart: usize) {
+    let source = hart + 1;
+    unsafe { write_volatile(reg(PENDING + source / 32 * 4), 1 << (source % 32)) };
+}
+
+/// Acknowledge the software interrupt of `hart`, which must be the running one.
+pub fn clear(hart: usize) {
+    let claim = reg(CLAIM + hart * CONTEXT_STRIDE);
+    unsafe {
+        let source = read_volatile(claim);
+        if source != 0 {
+            write_volatile(claim, source);
+        }
+    }
+}
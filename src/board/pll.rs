@@ -0,0 +1,274 @@
+//! Arithmetic of the PLLs and clock dividers, apart from their registers.
+//!
+//! Everything is exact in integers, rounded down once at the end, so no soft-float
+//! routines end up in the image. Products of two `u32` are taken in `u64`, so no input
+//! overflows, and nothing needs `u128` and its runtime routines. A frequency past
+//! `u32::MAX`, which only a PLL set up out of range can give, saturates.
+
+use core::ops::RangeInclusive;
+
+pub const XTAL24M_FREQ: u32 = 24_000_000;
+
+/// MFD as reset, ten times the reference so MFN counts tenths of a hertz.
+#[cfg(feature = "hpm63")]
+pub const PLLV2_MFD: u32 = 240_000_000;
+/// MFI the PLLCTLv2 VCO locks at.
+#[cfg(feature = "hpm63")]
+pub const PLLV2_MFI: RangeInclusive<u32> = 16..=42;
+
+/// VCO of a PLLCTLv2 PLL, in integer and fractional mode alike:
+///
+/// $$F_{vco} = F_{ref} \times (MFI + (MFN \div MFD))$$
+///
+/// A zero MFD, never set by the firmware, reads as integer mode.
+#[cfg(feature = "hpm63")]
+pub fn pllv2_freq(fref: u32, mfi: u32, mfn: u32, mfd: u32) -> u32 {
+    let (fref, mfi, mfn, mfd) = (fref as u64, mfi as u64, mfn as u64, mfd as u64);
+    // The integer part divides out exactly, only the fraction is rounded
+    let frac = match mfd {
+        0 => 0,
+        _ => fref * mfn / mfd,
+    };
+    saturate((fref * mfi).saturating_add(frac))
+}
+
+/// MFI and MFN setting a PLLCTLv2 VCO to `freq`, with MFD at [`PLLV2_MFD`].
+#[cfg(feature = "hpm63")]
+pub fn pllv2_settings(freq: u32) -> Option<(u32, u32)> {
+    let mfi = freq / XTAL24M_FREQ;
+    let mfn = freq % XTAL24M_FREQ * (PLLV2_MFD / XTAL24M_FREQ);
+    PLLV2_MFI.contains(&mfi).then_some((mfi, mfn))
+}
+
+/// Output of a PLLCTLv2 post divider, which divides by $1 + DIV \div 5$, that is
+/// multiplies by $5 \div (5 + DIV)$.
+#[cfg(feature = "hpm63")]
+pub fn pllv2_postdiv_freq(vco: u32, div: u32) -> u32 {
+    (vco as u64 * 5 / (5 + div as u64)) as u32
+}
+
+/// VCO of a PLLCTL PLL:
+///
+/// $$F_{vco} = F_{ref} \div REFDIV \times FBDIV \div POSTDIV1$$
+///
+/// FBDIV is `int` and a 24-bit fraction `frac` in 1/2<sup>24</sup>, zero in integer
+/// mode. Zero dividers, which the PLL does not run with, give zero.
+#[cfg(feature = "hpm67")]
+pub fn pllv1_freq(fref: u32, refdiv: u32, int: u32, frac: u32, postdiv: u32) -> u32 {
+    // Rounding the fraction down first rounds the same: at most
+    // (2^32 - 1)^2 + 2^32 - 1, still in `u64`
+    let frac = (fref as u64 * (frac & 0xFF_FFFF) as u64) >> 24;
+    let scaled = fref as u64 * int as u64 + frac;
+    match refdiv as u64 * postdiv as u64 {
+        0 => 0,
+        div => saturate(scaled / div),
+    }
+}
+
+/// FBDIV the PLLCTL VCO runs with in integer mode.
+#[cfg(feature = "hpm67")]
+pub const PLLV1_FBDIV: RangeInclusive<u32> = 16..=2400;
+
+/// FBDIV setting a PLLCTL PLL to `freq` in integer mode, with REFDIV and POSTDIV1 left
+/// as they are. `None` if `freq` is not a whole multiple of the step they leave, or
+/// needs an FBDIV out of range.
+#[cfg(feature = "hpm67")]
+pub fn pllv1_settings(fref: u32, refdiv: u32, postdiv: u32, freq: u32) -> Option<u32> {
+    let scaled = freq as u64 * refdiv as u64 * postdiv as u64;
+    let fbdiv = u32::try_from(scaled / fref as u64).ok()?;
+    (scaled % fref as u64 == 0 && PLLV1_FBDIV.contains(&fbdiv)).then_some(fbdiv)
+}
+
+/// Output of a clock node or PLLCTL output divider, dividing by `DIV + 1`.
+pub fn divided(freq: u32, div: u32) -> u32 {
+    (freq as u64 / (div as u64 + 1)) as u32
+}
+
+fn saturate(freq: impl TryInto<u32>) -> u32 {
+    freq.try_into().unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The exact frequency `num / den` rounded down, as the hardware defines it.
+    fn reference(num: u128, den: u128) -> u32 {
+        u32::try_from(num / den).unwrap_or(u32::MAX)
+    }
+
+    /// Every step of `step` through `range`, and the last value.
+    fn sweep(range: RangeInclusive<u32>, step: usize) -> impl Iterator<Item = u32> {
+        let end = *range.end();
+        range.step_by(step).chain([end])
+    }
+
+    #[test]
+    fn clock_node() {
+        assert_eq!(divided(XTAL24M_FREQ, 0), 24_000_000);
+        assert_eq!(divided(XTAL24M_FREQ, 23), 1_000_000);
+        assert_eq!(divided(648_000_000, 3), 162_000_000);
+        assert_eq!(divided(333_333_333, 1), 166_666_666);
+    }
+
+    /// CPU0 and AHB of the HPM6360EVK from PLL1, and the SDRAM from a fractional PLL0.
+    #[cfg(feature = "hpm63")]
+    #[test]
+    fn pllv2_tree() {
+        let (mfi, mfn) = pllv2_settings(648_000_000).unwrap();
+        assert_eq!((mfi, mfn), (27, 0));
+        let vco = pllv2_freq(XTAL24M_FREQ, mfi, mfn, PLLV2_MFD);
+        assert_eq!(vco, 648_000_000);
+        let cpu0 = divided(pllv2_postdiv_freq(vco, 0), 0);
+        assert_eq!(cpu0, 648_000_000);
+        assert_eq!(divided(cpu0, 3), 162_000_000);
+
+        let (mfi, mfn) = pllv2_settings(400_000_000).unwrap();
+        assert_eq!((mfi, mfn), (16, 160_000_000));
+        let vco = pllv2_freq(XTAL24M_FREQ, mfi, mfn, PLLV2_MFD);
+        assert_eq!(vco, 400_000_000);
+        let pll0clk1 = pllv2_postdiv_freq(vco, 1);
+        assert_eq!(pll0clk1, 333_333_333);
+        assert_eq!(divided(pll0clk1, 1), 166_666_666);
+    }
+
+    #[cfg(feature = "hpm63")]
+    #[test]
+    fn pllv2_limits() {
+        assert_eq!(pllv2_settings(16 * XTAL24M_FREQ), Some((16, 0)));
+        assert_eq!(pllv2_settings(16 * XTAL24M_FREQ - 1), None);
+        assert_eq!(
+            pllv2_settings(43 * XTAL24M_FREQ - 1).map(|(mfi, _)| mfi),
+            Some(42)
+        );
+        assert_eq!(pllv2_settings(43 * XTAL24M_FREQ), None);
+        // Integer mode
+        assert_eq!(pllv2_freq(XTAL24M_FREQ, 20, 12345, 0), 480_000_000);
+    }
+
+    #[cfg(feature = "hpm67")]
+    #[test]
+    fn pllv1_tree() {
+        let fbdiv = pllv1_settings(XTAL24M_FREQ, 1, 1, 816_000_000).unwrap();
+        assert_eq!(fbdiv, 34);
+        let vco = pllv1_freq(XTAL24M_FREQ, 1, fbdiv, 0, 1);
+        assert_eq!(vco, 816_000_000);
+        assert_eq!(divided(vco, 1), 408_000_000);
+
+        // Fractional mode, half way between 33 and 34
+        assert_eq!(pllv1_freq(XTAL24M_FREQ, 1, 33, 1 << 23, 1), 804_000_000);
+        assert_eq!(pllv1_freq(XTAL24M_FREQ, 2, 100, 0, 2), 600_000_000);
+    }
+
+    #[cfg(feature = "hpm67")]
+    #[test]
+    fn pllv1_limits() {
+        assert_eq!(pllv1_settings(XTAL24M_FREQ, 1, 1, 800_000_000), None);
+        assert_eq!(pllv1_settings(XTAL24M_FREQ, 3, 1, 800_000_000), Some(100));
+        assert_eq!(pllv1_settings(XTAL24M_FREQ, 1, 1, 15 * XTAL24M_FREQ), None);
+        assert_eq!(
+            pllv1_settings(XTAL24M_FREQ, 1, 1, 16 * XTAL24M_FREQ),
+            Some(16)
+        );
+        assert_eq!(
+            pllv1_settings(XTAL24M_FREQ, 63, 7, u32::MAX / 24 * 24),
+            None
+        );
+        assert_eq!(pllv1_freq(XTAL24M_FREQ, 0, 34, 0, 1), 0);
+        assert_eq!(pllv1_freq(XTAL24M_FREQ, 1, 34, 0, 0), 0);
+    }
+
+    #[cfg(feature = "hpm63")]
+    #[test]
+    fn pllv2_freq_sweep() {
+        // MFI is 7 bits, MFN and MFD 30
+        const FIELD: u32 = (1 << 30) - 1;
+        for fref in [XTAL24M_FREQ, u32::MAX] {
+            for mfd in [0, 1, 3, 10, XTAL24M_FREQ, PLLV2_MFD, FIELD] {
+                for mfi in 0..=127 {
+                    let mfns = sweep(0..=FIELD, 1_048_573).chain([1, mfd.saturating_sub(1), mfd]);
+                    for mfn in mfns {
+                        let (f, i, n, d) = (fref as u128, mfi as u128, mfn as u128, mfd as u128);
+                        let expected = match mfd {
+                            0 => reference(f * i, 1),
+                            _ => reference(f * (i * d + n), d),
+                        };
+                        assert_eq!(
+                            pllv2_freq(fref, mfi, mfn, mfd),
+                            expected,
+                            "fref {fref} mfi {mfi} mfn {mfn} mfd {mfd}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Settings for any frequency in range give it back to the hertz.
+    #[cfg(feature = "hpm63")]
+    #[test]
+    fn pllv2_settings_sweep() {
+        let range = PLLV2_MFI.start() * XTAL24M_FREQ..=(PLLV2_MFI.end() + 1) * XTAL24M_FREQ - 1;
+        for freq in sweep(range, 9_973) {
+            let (mfi, mfn) = pllv2_settings(freq).unwrap();
+            assert!(mfn < PLLV2_MFD);
+            assert_eq!(pllv2_freq(XTAL24M_FREQ, mfi, mfn, PLLV2_MFD), freq);
+        }
+    }
+
+    #[cfg(feature = "hpm63")]
+    #[test]
+    fn pllv2_postdiv_sweep() {
+        // DIV is 6 bits
+        for div in 0..=63 {
+            for vco in sweep(0..=u32::MAX, 65_521) {
+                assert_eq!(
+                    pllv2_postdiv_freq(vco, div),
+                    reference(vco as u128 * 5, 5 + div as u128),
+                    "vco {vco} div {div}"
+                );
+            }
+        }
+        assert_eq!(pllv2_postdiv_freq(u32::MAX, u32::MAX), 4);
+    }
+
+    #[cfg(feature = "hpm67")]
+    #[test]
+    fn pllv1_freq_sweep() {
+        // REFDIV is 6 bits, POSTDIV1 3 and the integer part of FBDIV 12
+        const FRACS: [u32; 4] = [0, 1, 1 << 23, (1 << 24) - 1];
+        let expected = |fref: u32, refdiv: u32, int: u32, frac: u32, postdiv: u32| {
+            let fbdiv = (int as u128) << 24 | frac as u128;
+            match (refdiv as u128 * postdiv as u128) << 24 {
+                0 => 0,
+                den => reference(fref as u128 * fbdiv, den),
+            }
+        };
+        for refdiv in 0..=63 {
+            for postdiv in 0..=7 {
+                for int in sweep(0..=4095, 13) {
+                    for frac in FRACS {
+                        assert_eq!(
+                            pllv1_freq(XTAL24M_FREQ, refdiv, int, frac, postdiv),
+                            expected(XTAL24M_FREQ, refdiv, int, frac, postdiv),
+                            "refdiv {refdiv} int {int} frac {frac:#x} postdiv {postdiv}"
+                        );
+                    }
+                }
+            }
+        }
+        // No input overflows
+        let max = (1 << 24) - 1;
+        for (refdiv, postdiv) in [(1, 1), (1, u32::MAX), (u32::MAX, 1), (u32::MAX, u32::MAX)] {
+            for int in [0, 1, u32::MAX] {
+                assert_eq!(
+                    pllv1_freq(u32::MAX, refdiv, int, max, postdiv),
+                    expected(u32::MAX, refdiv, int, max, postdiv),
+                    "refdiv {refdiv} int {int} postdiv {postdiv}"
+                );
+            }
+        }
+        assert_eq!(pllv1_freq(u32::MAX, 1, u32::MAX, max, 1), u32::MAX);
+        assert_eq!(pllv1_freq(u32::MAX, u32::MAX, u32::MAX, max, u32::MAX), 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! Controller setup for an SDRAM part, apart from the FEMC registers it goes into.
+
+/// The SDRAM part of a board and how it is wired, in the terms of its datasheet.
+///
+/// The controller setup is worked out from it and the FEMC clock by
+/// [`SdramConfig::registers`]. Times are in nanoseconds.
+///
+/// The size is not part of it: `Sdram::config` finds it out, so variants of a board
+/// with bigger or smaller parts of the same geometry share a configuration.
+pub struct SdramConfig {
+    /// Data bus width in bits: 8, 16 or 32.
+    pub bus_width: u32,
+    /// CAS latency in clock cycles, 1 to 3.
+    pub cas_latency: u32,
+    /// Column address bits, 8 to 12.
+    pub column_bits: u32,
+    /// Bank address bits: 1 for two banks, 2 for four.
+    pub bank_bits: u32,
+    /// Row cycle time, which auto refresh and leaving self refresh also take.
+    pub t_rc: u32,
+    /// Activate to read or write.
+    pub t_rcd: u32,
+    /// Precharge to activate.
+    pub t_rp: u32,
+    /// Activate to precharge, also the shortest time CKE is held low.
+    pub t_ras: u32,
+    /// Write recovery, last data in to precharge.
+    pub t_wr: u32,
+    /// Activate to activate in another bank.
+    pub t_rrd: u32,
+    /// Average refresh interval: the refresh period over the number of rows.
+    pub t_ref: u32,
+    /// Read data sampling delay.
+    pub delay_cell: u8,
+}
+
+/// Controller setup worked out from an [`SdramConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdramRegisters {
+    /// `SDRCTRL0` to `SDRCTRL3`: port size, geometry and timing.
+    pub sdrctrl: [u32; 4],
+    /// Mode register: burst length and CAS latency.
+    pub mode: u32,
+    /// Read data sampling delay, as configured.
+    pub delay_cell: u8,
+}
+
+impl SdramConfig {
+    /// Beats of a burst, as many as the controller issues.
+    const BURST_LENGTH: u32 = 8;
+    /// The refresh timer counts in units of `16 * PRESCALE` clock cycles.
+    const REFRESH_PRESCALE: u32 = 3;
+    /// Cycles a bank stays open while idle.
+    const IDLE_TIMEOUT: u32 = 1;
+
+    /// Whole clock cycles of `clock_freq` covering `ns`, saturating at `max`.
+    fn cycles(clock_freq: u32, ns: u32, max: u32) -> u32 {
+        let cycles = (ns as u64 * clock_freq as u64).div_ceil(1_000_000_000);
+        cycles.min(max as u64) as u32
+    }
+
+    /// Register values driving this part from a FEMC clock of `clock_freq`.
+    ///
+    /// Timings round up to whole cycles and the refresh interval down, so the part is
+    /// never driven faster than its datasheet allows.
+    pub fn registers(&self, clock_freq: u32) -> SdramRegisters {
+        let cycles = |ns, max| Self::cycles(clock_freq, ns, max);
+        let burst = Self::BURST_LENGTH.trailing_zeros();
+
+        // Column address width counts down from 12 bits; 8 bits has a flag of its own
+        let (column, column8) = match self.column_bits {
+            8 => (0, 1),
+            bits => (12 - bits, 0),
+        };
+        let sdrctrl0 = (self.bus_width / 8).trailing_zeros()
+            | burst << 4
+            | column8 << 7
+            | column << 8
+            | self.cas_latency << 10
+            | ((self.bank_bits == 1) as u32) << 14;
+
+        let sdrctrl1 = cycles(self.t_rp, 0xF)
+            | cycles(self.t_rcd, 0xF) << 4
+            | cycles(self.t_rc, 0x1F) << 8
+            | cycles(self.t_wr, 0x7) << 13
+            | cycles(self.t_ras, 0xF) << 16
+            | cycles(self.t_ras, 0xF) << 20;
+
+        let sdrctrl2 = cycles(self.t_rc, 0xFF)
+            | cycles(self.t_rc, 0xFF) << 8
+            | cycles(self.t_rrd, 0xFF) << 16
+            | Self::IDLE_TIMEOUT << 24;
+
+        // Refresh and urgent refresh thresholds alike, in prescaled ticks; one refresh
+        // per burst. Refresh itself is enabled once the part is initialised.
+        let ticks = self.t_ref as u64 * clock_freq as u64
+            / 1_000_000_000
+            / (16 * Self::REFRESH_PRESCALE as u64);
+        let ticks = ticks.min(0xFF) as u32;
+        let sdrctrl3 = Self::REFRESH_PRESCALE << 8 | ticks << 16 | ticks << 24;
+
+        SdramRegisters {
+            sdrctrl: [sdrctrl0, sdrctrl1, sdrctrl2, sdrctrl3],
+            mode: burst | self.cas_latency << 4,
+            delay_cell: self.delay_cell,
+        }
+    }
+}
+
+/// Size of the memory at `base`, a power of two from `min_size` up to `max_size`, or
+/// `None` if it does not hold what is written to it.
+///
+/// A part smaller than the window ignores the address bits above its size, so a write
+/// at `base + size` lands on `base`. The words probed are put back as they were, so an
+/// image an earlier stage loaded survives.
+///
+/// `sync` writes a word back and drops it from the data cache. It is called after each
+/// write and before each read, so the probe sees the part rather than the cache.
+pub unsafe fn probe_size(
+    base: *mut u32,
+    min_size: usize,
+    max_size: usize,
+    sync: impl Fn(*mut u32),
+) -> Option<usize> {
+    const PATTERN: u32 = 0x5AA5_A55A;
+
+    let read = |word: *mut u32| {
+        sync(word);
+        word.read_volatile()
+    };
+    let write = |word: *mut u32, value| {
+        word.write_volatile(value);
+        sync(word);
+    };
+
+    let saved = read(base);
+    write(base, PATTERN);
+    if read(base) != PATTERN {
+        return None;
+    }
+    let mut size = min_size;
+    while size < max_size {
+        let probe = base.byte_add(size);
+        let old = read(probe);
+        write(probe, !PATTERN);
+        let aliased = read(base) != PATTERN;
+        write(probe, old);
+        if aliased {
+            break;
+        }
+        size *= 2;
+    }
+    write(base, saved);
+    Some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FEMC clock of the EVKs: PLL0CLK1 at 333MHz, halved.
+    const EVK_FEMC_FREQ: u32 = 166_666_666;
+
+    /// The part of the HPM6360EVK, as in src/board/hpm6360evk.rs.
+    const HPM6360EVK: SdramConfig = SdramConfig {
+        bus_width: 16,
+        cas_latency: 3,
+        column_bits: 9,
+        bank_bits: 2,
+        t_rc: 66,
+        t_rcd: 18,
+        t_rp: 18,
+        t_ras: 42,
+        t_wr: 12,
+        t_rrd: 12,
+        t_ref: 7812,
+        delay_cell: 29,
+    };
+
+    #[test]
+    fn hpm6360evk() {
+        let regs = HPM6360EVK.registers(EVK_FEMC_FREQ);
+        assert_eq!(regs.sdrctrl, [0xF31, 0x774B33, 0x01020B0B, 0x1B1B0300]);
+        assert_eq!(regs.mode, 0x33);
+        assert_eq!(regs.delay_cell, 29);
+    }
+
+    /// The HPM6750EVK has the same timing on a 32-bit bus.
+    #[test]
+    fn hpm6750evk() {
+        let config = SdramConfig {
+            bus_width: 32,
+            ..HPM6360EVK
+        };
+        let regs = config.registers(EVK_FEMC_FREQ);
+        assert_eq!(regs.sdrctrl[0], 0xF32);
+        assert_eq!(regs.sdrctrl[1..], [0x774B33, 0x01020B0B, 0x1B1B0300]);
+    }
+
+    #[test]
+    fn geometry() {
+        let config = SdramConfig {
+            bus_width: 8,
+            cas_latency: 2,
+            column_bits: 8,
+            bank_bits: 1,
+            ..HPM6360EVK
+        };
+        let sdrctrl0 = config.registers(EVK_FEMC_FREQ).sdrctrl[0];
+        assert_eq!(sdrctrl0, 0x30 | 1 << 7 | 2 << 10 | 1 << 14);
+        let mode = config.registers(EVK_FEMC_FREQ).mode;
+        assert_eq!(mode, 0x23);
+    }
+
+    /// Timings round up to whole cycles, and saturate in their fields.
+    #[test]
+    fn timing_rounds_up() {
+        // 10ns cycles: 18ns takes 2, 66ns 7
+        let regs = HPM6360EVK.registers(100_000_000);
+        assert_eq!(regs.sdrctrl[1] & 0xF, 2);
+        assert_eq!(regs.sdrctrl[1] >> 8 & 0x1F, 7);
+        // 66ns at 1GHz does not fit the 5-bit field of SDRCTRL1
+        let regs = HPM6360EVK.registers(1_000_000_000);
+        assert_eq!(regs.sdrctrl[1] >> 8 & 0x1F, 0x1F);
+        assert_eq!(regs.sdrctrl[2] & 0xFF, 66);
+        // The refresh interval rounds down and saturates
+        assert_eq!(regs.sdrctrl[3] >> 16 & 0xFF, 162);
+        let regs = HPM6360EVK.registers(u32::MAX);
+        assert_eq!(regs.sdrctrl[3] >> 16, 0xFFFF);
+    }
+
+    /// A part of `part` bytes repeating through a window of `window`, as the SDRAM
+    /// address space shows a part smaller than itself.
+    #[cfg(target_os = "linux")]
+    struct Aliased {
+        window: *mut u8,
+        len: usize,
+    }
+
+    #[cfg(target_os = "linux")]
+    mod sys {
+        use core::ffi::{c_char, c_int, c_long, c_uint, c_void};
+
+        pub const PROT_NONE: c_int = 0;
+        pub const PROT_READ: c_int = 1;
+        pub const PROT_WRITE: c_int = 2;
+        pub const MAP_SHARED: c_int = 0x01;
+        pub const MAP_PRIVATE: c_int = 0x02;
+        pub const MAP_FIXED: c_int = 0x10;
+        pub const MAP_ANONYMOUS: c_int = 0x20;
+
+        extern "C" {
+            pub fn memfd_create(name: *const c_char, flags: c_uint) -> c_int;
+            pub fn ftruncate(fd: c_int, len: c_long) -> c_int;
+            pub fn close(fd: c_int) -> c_int;
+            pub fn mmap(
+                addr: *mut c_void,
+                len: usize,
+                prot: c_int,
+                flags: c_int,
+                fd: c_int,
+                offset: c_long,
+            ) -> *mut c_void;
+            pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Aliased {
+        fn new(part: usize, window: usize) -> Self {
+            use sys::*;
+            unsafe {
+                let fd = memfd_create(b"sdram\0".as_ptr().cast(), 0);
+                assert!(fd >= 0);
+                assert_eq!(ftruncate(fd, part as _), 0);
+                let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+                let base = mmap(core::ptr::null_mut(), window, PROT_NONE, flags, -1, 0);
+                assert_ne!(base as isize, -1);
+                for offset in (0..window).step_by(part) {
+                    let at = base.byte_add(offset);
+                    let flags = MAP_SHARED | MAP_FIXED;
+                    let mapped = mmap(at, part, PROT_READ | PROT_WRITE, flags, fd, 0);
+                    assert_eq!(mapped, at);
+                }
+                close(fd);
+                Aliased {
+                    window: base.cast(),
+                    len: window,
+                }
+            }
+        }
+
+        fn probe(&self, min_size: usize) -> Option<usize> {
+            unsafe { probe_size(self.window.cast(), min_size, self.len, |_| {}) }
+        }
+
+        fn words(&mut self) -> &mut [u32] {
+            unsafe { core::slice::from_raw_parts_mut(self.window.cast(), self.len / 4) }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Drop for Aliased {
+        fn drop(&mut self) {
+            unsafe { sys::munmap(self.window.cast(), self.len) };
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probes_aliased_part() {
+        const KIB: usize = 1024;
+        for part in [4 * KIB, 16 * KIB, 64 * KIB, 256 * KIB] {
+            let sdram = Aliased::new(part, 256 * KIB);
+            assert_eq!(sdram.probe(4 * KIB), Some(part));
+        }
+        // No smaller than the smallest part there is
+        let sdram = Aliased::new(4 * KIB, 256 * KIB);
+        assert_eq!(sdram.probe(16 * KIB), Some(16 * KIB));
+    }
+
+    /// What an earlier stage loaded is still there after probing.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probe_keeps_contents() {
+        let mut sdram = Aliased::new(32 * 1024, 128 * 1024);
+        let part = 32 * 1024 / 4;
+        for (i, word) in sdram.words()[..part].iter_mut().enumerate() {
+            *word = (i as u32).wrapping_mul(0x9E37_79B9);
+        }
+        assert_eq!(sdram.probe(4 * 1024), Some(32 * 1024));
+        for (i, &word) in sdram.words()[..part].iter().enumerate() {
+            assert_eq!(word, (i as u32).wrapping_mul(0x9E37_79B9), "word {i}");
+        }
+    }
+
+    /// Each word is synced after it is written and before it is read back.
+    #[test]
+    fn probe_syncs_around_accesses() {
+        let mut memory = [0u32; 64];
+        let base = memory.as_mut_ptr();
+        let synced = core::cell::RefCell::new([0; 64]);
+        let size = unsafe {
+            probe_size(base, 16, 256, |word| {
+                synced.borrow_mut()[word.offset_from(base) as usize] += 1;
+            })
+        };
+        assert_eq!(size, Some(256));
+        let synced = synced.into_inner();
+        // The base word: read, written, read, then read in each of the 4 probes, written
+        assert_eq!(synced[0], 8);
+        // Each probe: read, written, written back
+        for word in [4, 8, 16, 32] {
+            assert_eq!(synced[word], 3, "word {word}");
+        }
+    }
+}
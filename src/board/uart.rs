@@ -1,37 +1,183 @@
+//! 16550-style UART of HPM chips, with a programmable oversampling rate.
+
 #![allow(dead_code)]
 
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
 use super::pac::uart;
 
+/// Bytes the transmit FIFO takes once it has drained.
+const FIFO_DEPTH: usize = 16;
+/// Largest relative baud rate error, in thousandths, a link still works with.
+const BAUD_TOLERANCE: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Frame format and FIFO setup of a UART, 8 data bits always.
+#[derive(Debug, Clone, Copy)]
+pub struct UartConfig {
+    pub baudrate: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// Hardware flow control on RTS and CTS, whose pins the board routes.
+    pub flow_control: bool,
+    /// Receive FIFO level raising the data ready interrupt, as the `RFIFOT` field.
+    pub rx_fifo_trigger: u8,
+    /// Transmit FIFO level raising the empty interrupt, as the `TFIFOT` field.
+    pub tx_fifo_trigger: u8,
+}
+
+impl UartConfig {
+    /// 8N1 at `baudrate`, without flow control.
+    pub const fn new(baudrate: u32) -> Self {
+        Self {
+            baudrate,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: false,
+            rx_fifo_trigger: 0,
+            tx_fifo_trigger: 0,
+        }
+    }
+}
+
+impl fmt::Display for UartConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} 8{parity}{stop_bits}", self.baudrate)?;
+        if self.flow_control {
+            f.write_str(" RTS/CTS")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The baud rate is out of reach of the UART clock, within tolerance.
+    BaudRate { baudrate: u32, clock_freq: u32 },
+}
+
+impl fmt::Display for UartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UartError::BaudRate {
+                baudrate,
+                clock_freq,
+            } => write!(f, "{baudrate} baud unreachable from a {clock_freq}Hz clock"),
+        }
+    }
+}
+
+/// Receive errors counted since the UART was set up.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LineErrors {
+    /// Characters lost to a full receive FIFO.
+    pub overrun: u32,
+    pub parity: u32,
+    pub framing: u32,
+    pub line_break: u32,
+}
+
+/// Oversampling rate and divisor closest to `baudrate`, if within tolerance.
+///
+/// The UART samples each bit `osc` times, an even number from 8 to 32, from a clock
+/// divided by `div`. Of equally close settings, the one sampling most is taken.
+pub fn divisor(clock_freq: u32, baudrate: u32) -> Option<(u32, u32)> {
+    if baudrate == 0 {
+        return None;
+    }
+    let (clock_freq, baudrate) = (clock_freq as u64, baudrate as u64);
+    (4..=16)
+        .rev()
+        .map(|half| half * 2)
+        .filter_map(|osc| {
+            let div = (clock_freq + baudrate * osc / 2) / (baudrate * osc);
+            if !(1..=0xFFFF).contains(&div) {
+                return None;
+            }
+            let actual = clock_freq / (div * osc);
+            let error = actual.abs_diff(baudrate) * 1000 / baudrate;
+            Some((error, osc as u32, div as u32))
+        })
+        .min_by_key(|&(error, ..)| error)
+        .filter(|&(error, ..)| error <= BAUD_TOLERANCE as u64)
+        .map(|(_, osc, div)| (osc, div))
+}
+
 pub struct Uart {
     inner: uart::Uart,
+    errors: LineErrors,
 }
 
 impl Uart {
     pub fn new(uart: uart::Uart) -> Self {
-        Self { inner: uart }
+        Self {
+            inner: uart,
+            errors: LineErrors::default(),
+        }
     }
 
-    pub fn setup(&self, buadrate: u32, clock_src_freq: u32) {
+    /// Set the UART up as `config` asks, from a clock of `clock_freq`.
+    ///
+    /// Leaves the UART untouched if the baud rate cannot be reached.
+    pub fn setup(&mut self, config: &UartConfig, clock_freq: u32) -> Result<(), UartError> {
+        let (osc, div) = divisor(clock_freq, config.baudrate).ok_or(UartError::BaudRate {
+            baudrate: config.baudrate,
+            clock_freq,
+        })?;
         let uart = &self.inner;
 
-        // Disable all interrupt
-        uart.dlm().write(|w| w.set_dlm(0));
-
-        // Set DLAB to 1
-        uart.lcr().modify(|m| m.set_dlab(true));
-        // Calculate baud rate
-        let div = clock_src_freq / (buadrate * 16);
-        uart.dll().write(|m| m.set_dll(div as u8));
-        uart.dlm().write(|m| m.set_dlm((div >> 8) as u8));
-        // Set DLAB to 0
-        uart.lcr().modify(|m| m.set_dlab(false));
-
-        // Word length to 8 bits
-        uart.lcr().modify(|m| m.set_wls(3));
-        // Enable TX and RX FIFO
-        uart.fcr().modify(|m| m.set_fifoe(true))
+        // Disable all interrupts
+        uart.ier().write(|w| w.0 = 0);
+
+        // OSC of 32 is written as 0
+        uart.oscr().write(|w| w.set_osc((osc % 32) as u8));
+        uart.lcr().modify(|w| w.set_dlab(true));
+        uart.dll().write(|w| w.set_dll(div as u8));
+        uart.dlm().write(|w| w.set_dlm((div >> 8) as u8));
+        uart.lcr().modify(|w| w.set_dlab(false));
+
+        uart.lcr().write(|w| {
+            // 8 data bits
+            w.set_wls(3);
+            w.set_stb(config.stop_bits == StopBits::Two);
+            w.set_pen(config.parity != Parity::None);
+            w.set_eps(config.parity == Parity::Even);
+        });
+        uart.mcr().write(|w| {
+            w.set_afe(config.flow_control);
+            w.set_rts(config.flow_control);
+        });
+        // FCR is write-only, sharing its address with IIR
+        uart.fcr().write(|w| {
+            w.set_fifoe(true);
+            w.set_rfiforst(true);
+            w.set_tfiforst(true);
+            w.set_rfifot(config.rx_fifo_trigger);
+            w.set_tfifot(config.tx_fifo_trigger);
+        });
+        self.errors = LineErrors::default();
+        Ok(())
     }
 
     #[inline]
@@ -39,30 +185,68 @@ impl Uart {
         self.inner.lsr().read().thre()
     }
 
-    #[inline]
-    fn is_data_ready(&self) -> bool {
-        self.inner.lsr().read().dr()
-    }
-
     #[inline]
     pub fn send_byte(&self, byte: u8) {
         while !self.is_tx_fifo_empty() {}
-        self.inner.dll().write(|w| w.set_dll(byte));
+        self.inner.thr().write(|w| w.set_thr(byte));
     }
 
-    #[inline]
-    pub fn receive_byte(&self, byte: &mut u8) -> bool {
-        if self.is_data_ready() {
-            *byte = self.inner.rbr().read().rbr();
-            true
-        } else {
-            false
+    /// Top the transmit FIFO up from `next`, once it has drained.
+    pub fn fill_tx(&self, mut next: impl FnMut() -> Option<u8>) {
+        if !self.is_tx_fifo_empty() {
+            return;
+        }
+        for _ in 0..FIFO_DEPTH {
+            let Some(byte) = next() else { break };
+            self.inner.thr().write(|w| w.set_thr(byte));
         }
     }
+
+    /// Interrupt when data or a line status error is received.
+    pub fn set_rx_interrupt(&self, enable: bool) {
+        self.inner.ier().modify(|w| {
+            w.set_erbi(enable);
+            w.set_elsi(enable);
+        });
+    }
+
+    /// Interrupt when the transmit FIFO drains.
+    pub fn set_tx_interrupt(&self, enable: bool) {
+        self.inner.ier().modify(|w| w.set_ethei(enable));
+    }
+
+    /// Take a received byte, if any. Bytes received with a parity or framing error, or
+    /// as part of a break, are dropped and counted.
+    pub fn receive_byte(&mut self) -> Option<u8> {
+        loop {
+            // Reading LSR clears its error bits
+            let lsr = self.inner.lsr().read();
+            if lsr.oe() {
+                self.errors.overrun += 1;
+            }
+            if !lsr.dr() {
+                return None;
+            }
+            let byte = self.inner.rbr().read().rbr();
+            if lsr.lbreak() {
+                self.errors.line_break += 1;
+            } else if lsr.fe() {
+                self.errors.framing += 1;
+            } else if lsr.pe() {
+                self.errors.parity += 1;
+            } else {
+                return Some(byte);
+            }
+        }
+    }
+
+    pub fn line_errors(&self) -> LineErrors {
+        self.errors
+    }
 }
 
 impl Write for Uart {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
         for ch in s.bytes() {
             self.send_byte(ch);
         }
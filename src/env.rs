@@ -0,0 +1,213 @@
+//! Persistent environment of `name=value` variables for boot configuration.
+//!
+//! Stored like a U-Boot redundant environment: two flash sectors, each holding a CRC-32
+//! of the data, a generation counter and the variables as NUL-terminated `name=value`
+//! strings ending with an empty one. The valid copy with the newer generation is
+//! current, and saving overwrites the other one, so a power loss while saving leaves the
+//! previous environment in place. `tools/envtool.py` builds and parses these images.
+//!
+//! Variables used by the firmware:
+//!
+//! | Name        | Meaning                                               |
+//! |-------------|-------------------------------------------------------|
+//! | `bootargs`  | kernel command line                                   |
+//! | `bootslot`  | `a` or `b`, boot that slot bypassing boot control     |
+//! | `bootdelay` | autoboot countdown in seconds                         |
+//! | `bootxip`   | `1` to run the kernel in place from flash             |
+//! | `bootmode`  | `s` or `m`, see [`next_stage`](crate::next_stage)     |
+//! | `bootentry` | entry address, see [`next_stage`](crate::next_stage)  |
+//! | `bootdtb`   | `0` to pass no device tree in `a1`                    |
+//! | `baudrate`  | console baud rate                                     |
+//! | `loglevel`  | `off`, `error`, `warn`, `info`, `debug` or `trace`    |
+//! | `chosen.*`  | string properties added to the device tree `/chosen`  |
+
+use core::fmt;
+
+use spin::lock_api::{Mutex, MutexGuard};
+
+use crate::board::{self, FlashError, FLASH_SECTOR_SIZE};
+use crate::crc32;
+
+/// Flash sectors of the two copies.
+pub const ENV_ADDRESSES: [usize; 2] = [0x80FF_1000, 0x80FF_2000];
+/// Size of one copy, header included.
+pub const ENV_SIZE: usize = FLASH_SECTOR_SIZE;
+/// CRC-32 and generation.
+const HEADER_SIZE: usize = 5;
+const DATA_SIZE: usize = ENV_SIZE - HEADER_SIZE;
+
+static ENV: Mutex<Env> = Mutex::new(Env::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvError {
+    /// Names must be non-empty and free of `=`; neither may contain NUL.
+    BadName,
+    /// The variables do not fit into one copy.
+    NoSpace,
+    Flash(FlashError),
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvError::BadName => write!(f, "bad variable name or value"),
+            EnvError::NoSpace => write!(f, "environment full"),
+            EnvError::Flash(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+pub struct Env {
+    /// Variables, followed by zeros.
+    data: [u8; DATA_SIZE],
+    generation: u8,
+    /// Copy in flash the variables were loaded from or last saved to.
+    copy: Option<usize>,
+}
+
+impl Env {
+    const fn new() -> Self {
+        Self {
+            data: [0; DATA_SIZE],
+            generation: 0,
+            copy: None,
+        }
+    }
+
+    /// Take the current one of two stored copies, if any is valid, leaving `self` as is
+    /// otherwise. Fills `self` in place: an `Env` is too large for the stack.
+    fn import(&mut self, copies: [&[u8]; 2]) {
+        let valid = |i: usize| {
+            let copy = copies[i];
+            let crc = u32::from_le_bytes(copy[0..4].try_into().unwrap());
+            (crc == crc32::checksum(&copy[HEADER_SIZE..ENV_SIZE])).then_some(copy[4])
+        };
+        let copy = match (valid(0), valid(1)) {
+            (Some(g0), Some(g1)) => {
+                // Generations wrap around
+                if g1.wrapping_sub(g0) as i8 > 0 {
+                    1
+                } else {
+                    0
+                }
+            }
+            (Some(_), None) => 0,
+            (None, Some(_)) => 1,
+            (None, None) => return,
+        };
+        self.data
+            .copy_from_slice(&copies[copy][HEADER_SIZE..ENV_SIZE]);
+        self.generation = copies[copy][4];
+        self.copy = Some(copy);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|&(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// All variables, in the order they are stored. Entries that are not `name=value`
+    /// UTF-8 are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.data[..self.used()]
+            .split(|&b| b == 0)
+            .filter_map(|entry| core::str::from_utf8(entry).ok()?.split_once('='))
+    }
+
+    /// Set `name` to `value`, or delete it with `None`. Only changes the copy in memory.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), EnvError> {
+        if name.is_empty() || name.contains(['=', '\0']) || value.is_some_and(|v| v.contains('\0'))
+        {
+            return Err(EnvError::BadName);
+        }
+        let used = self.used();
+        let old = self.entry(name);
+        let old_len = old.as_ref().map_or(0, |range| range.len() + 1);
+        let new_len = value.map_or(0, |v| name.len() + 1 + v.len() + 1);
+        // One NUL must stay to end the list
+        if used - old_len + new_len >= DATA_SIZE {
+            return Err(EnvError::NoSpace);
+        }
+
+        if let Some(range) = old {
+            self.data.copy_within(range.end + 1..used, range.start);
+            self.data[used - old_len..used].fill(0);
+        }
+        if let Some(value) = value {
+            let mut at = used - old_len;
+            for part in [name.as_bytes(), b"=", value.as_bytes(), b"\0"] {
+                self.data[at..at + part.len()].copy_from_slice(part);
+                at += part.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the variables to the copy not holding the current ones.
+    pub fn save(&mut self) -> Result<(), EnvError> {
+        let copy = self.copy.map_or(0, |copy| 1 - copy);
+        let generation = self.generation.wrapping_add(1);
+        let address = ENV_ADDRESSES[copy];
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&crc32::checksum(&self.data).to_le_bytes());
+        header[4] = generation;
+        board::with_flash(|flash| {
+            flash.erase(address, ENV_SIZE)?;
+            // Header last, so an interrupted save is never taken for valid
+            flash.program(address + HEADER_SIZE, &self.data)?;
+            flash.program(address, &header)
+        })
+        .map_err(EnvError::Flash)?;
+        self.generation = generation;
+        self.copy = Some(copy);
+        Ok(())
+    }
+
+    /// Generation of the stored copy, `None` when running on defaults.
+    pub fn generation(&self) -> Option<u8> {
+        self.copy.map(|_| self.generation)
+    }
+
+    /// Length of the variables up to, not including, the terminating empty entry.
+    fn used(&self) -> usize {
+        let mut at = 0;
+        while at < DATA_SIZE && self.data[at] != 0 {
+            at += self.data[at..]
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(DATA_SIZE - at)
+                + 1;
+        }
+        at.min(DATA_SIZE)
+    }
+
+    /// Byte range of the `name=value` entry, without its NUL.
+    fn entry(&self, name: &str) -> Option<core::ops::Range<usize>> {
+        let mut at = 0;
+        for entry in self.data[..self.used()].split(|&b| b == 0) {
+            let range = at..at + entry.len();
+            at += entry.len() + 1;
+            if entry
+                .strip_prefix(name.as_bytes())
+                .and_then(|rest| rest.first())
+                == Some(&b'=')
+            {
+                return Some(range);
+            }
+        }
+        None
+    }
+}
+
+/// Read the environment from flash, keeping it empty if no copy is valid.
+pub fn load() {
+    let copies = ENV_ADDRESSES
+        .map(|address| unsafe { core::slice::from_raw_parts(address as *const u8, ENV_SIZE) });
+    ENV.lock().import(copies);
+}
+
+/// The environment, loaded by [`load`].
+pub fn env() -> MutexGuard<'static, Env> {
+    ENV.lock()
+}
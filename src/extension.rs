@@ -1,14 +1,193 @@
-use rustsbi::RustSBI;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::mhartid;
+use rustsbi::{HartMask, Physical, RustSBI};
+use sbi_spec::binary::SbiRet;
 use spin::Lazy;
 
-use crate::board::{board_init_timer, MachineTimer};
+use crate::board::{self, board_info, board_init_timer, MachineTimer, NUM_HARTS};
+use crate::riscv_spec::fence_i;
+use crate::trap_stack::{local_hsm, remote_hsm, HartState};
+use crate::Supervisor;
 
 #[derive(RustSBI)]
 pub struct FixedRustSBI {
     #[rustsbi(timer)]
     pub timer: MachineTimer,
+    #[rustsbi(ipi)]
+    pub ipi: Messenger,
+    #[rustsbi(fence)]
+    pub fence: Messenger,
+    #[rustsbi(hsm)]
+    pub hsm: HartManager,
+    #[rustsbi(console)]
+    pub console: DebugConsole,
 }
 
 pub static SBI: Lazy<FixedRustSBI> = Lazy::new(|| FixedRustSBI {
     timer: board_init_timer(),
+    ipi: Messenger,
+    fence: Messenger,
+    hsm: HartManager,
+    console: DebugConsole,
 });
+
+/// Work a hart is asked to do in its machine software interrupt.
+const MESSAGE_SSIP: usize = 1 << 0;
+const MESSAGE_FENCE_I: usize = 1 << 1;
+const MESSAGE_SFENCE_VMA: usize = 1 << 2;
+
+/// Messages pending for each hart.
+static MESSAGES: [AtomicUsize; NUM_HARTS] = [const { AtomicUsize::new(0) }; NUM_HARTS];
+
+/// Passes IPIs and remote fences between harts as software interrupts.
+pub struct Messenger;
+
+impl Messenger {
+    /// Post `message` to the started harts in `hart_mask`; with `wait`, return once they
+    /// have handled it.
+    fn send(&self, hart_mask: HartMask, message: usize, wait: bool) -> SbiRet {
+        let this = mhartid::read();
+        let started = |hart| remote_hsm(hart).is_some_and(|hsm| hsm.status() == HartState::Started);
+        let targets = (0..NUM_HARTS).filter(|&hart| hart_mask.has_bit(hart) && started(hart));
+        for hart in targets.clone() {
+            MESSAGES[hart].fetch_or(message, Ordering::Release);
+            board::send_ipi(hart);
+        }
+        if wait {
+            // This hart handles its own message when it returns from the call. Meanwhile
+            // it serves others, which may be waiting for it in turn.
+            for hart in targets.filter(|&hart| hart != this) {
+                while MESSAGES[hart].load(Ordering::Acquire) & message != 0 && started(hart) {
+                    if MESSAGES[this].load(Ordering::Acquire) != 0 {
+                        handle_ipi();
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        SbiRet::success(0)
+    }
+}
+
+impl rustsbi::Ipi for Messenger {
+    fn send_ipi(&self, hart_mask: HartMask) -> SbiRet {
+        self.send(hart_mask, MESSAGE_SSIP, false)
+    }
+}
+
+impl rustsbi::Fence for Messenger {
+    fn remote_fence_i(&self, hart_mask: HartMask) -> SbiRet {
+        self.send(hart_mask, MESSAGE_FENCE_I, true)
+    }
+
+    /// Flushes the whole TLB, whatever the range.
+    fn remote_sfence_vma(&self, hart_mask: HartMask, _start_addr: usize, _size: usize) -> SbiRet {
+        self.send(hart_mask, MESSAGE_SFENCE_VMA, true)
+    }
+
+    fn remote_sfence_vma_asid(
+        &self,
+        hart_mask: HartMask,
+        _start_addr: usize,
+        _size: usize,
+        _asid: usize,
+    ) -> SbiRet {
+        self.send(hart_mask, MESSAGE_SFENCE_VMA, true)
+    }
+}
+
+/// Handle the machine software interrupt of this hart.
+pub fn handle_ipi() {
+    let hart = mhartid::read();
+    board::clear_ipi(hart);
+    let messages = MESSAGES[hart].load(Ordering::Acquire);
+    if messages & MESSAGE_SSIP != 0 {
+        unsafe { riscv::register::mip::set_ssoft() };
+    }
+    if messages & MESSAGE_FENCE_I != 0 {
+        unsafe { fence_i() };
+    }
+    if messages & MESSAGE_SFENCE_VMA != 0 {
+        riscv::asm::sfence_vma_all();
+    }
+    MESSAGES[hart].fetch_and(!messages, Ordering::Release);
+}
+
+/// Starts and stops harts for the HSM extension.
+pub struct HartManager;
+
+impl rustsbi::Hsm for HartManager {
+    fn hart_start(&self, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        let Some(hsm) = remote_hsm(hartid) else {
+            return SbiRet::invalid_param();
+        };
+        match hsm.remote_start(Supervisor { start_addr, opaque }) {
+            Ok(()) => {
+                board::send_ipi(hartid);
+                SbiRet::success(0)
+            }
+            Err(HartState::Started | HartState::StartPending) => SbiRet::already_available(),
+            Err(_) => SbiRet::failed(),
+        }
+    }
+
+    /// Marks this hart stopped; the trap handler then waits for it to be started again.
+    fn hart_stop(&self) -> SbiRet {
+        local_hsm().stop();
+        SbiRet::success(0)
+    }
+
+    fn hart_get_status(&self, hartid: usize) -> SbiRet {
+        match remote_hsm(hartid) {
+            Some(hsm) => SbiRet::success(hsm.status() as usize),
+            None => SbiRet::invalid_param(),
+        }
+    }
+}
+
+/// The debug console extension on the console buffers.
+pub struct DebugConsole;
+
+impl DebugConsole {
+    /// Start of the `len` bytes the supervisor passed at `(lo, hi)`, which must be in
+    /// SDRAM.
+    fn supervisor_buffer(lo: usize, hi: usize, len: usize) -> Option<usize> {
+        let info = board_info();
+        let sdram = info.sdram_base..info.sdram_base + info.sdram_size;
+        let end = lo.checked_add(len)?;
+        (hi == 0 && sdram.contains(&lo) && end <= sdram.end).then_some(lo)
+    }
+}
+
+impl rustsbi::Console for DebugConsole {
+    fn write(&self, bytes: Physical<&[u8]>) -> SbiRet {
+        let len = bytes.num_bytes();
+        match Self::supervisor_buffer(bytes.phys_addr_lo(), bytes.phys_addr_hi(), len) {
+            Some(addr) => {
+                board::console_write(unsafe {
+                    core::slice::from_raw_parts(addr as *const u8, len)
+                });
+                SbiRet::success(len)
+            }
+            None => SbiRet::invalid_param(),
+        }
+    }
+
+    /// Takes what was received so far, without waiting for more.
+    fn read(&self, bytes: Physical<&mut [u8]>) -> SbiRet {
+        let len = bytes.num_bytes();
+        match Self::supervisor_buffer(bytes.phys_addr_lo(), bytes.phys_addr_hi(), len) {
+            Some(addr) => {
+                let buf = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+                SbiRet::success(board::console_read(buf))
+            }
+            None => SbiRet::invalid_param(),
+        }
+    }
+
+    fn write_byte(&self, byte: u8) -> SbiRet {
+        board::console_write(&[byte]);
+        SbiRet::success(0)
+    }
+}
@@ -0,0 +1,471 @@
+use core::fmt::Write;
+
+use super::*;
+
+/// Values the firmware knows better than the device tree shipped in flash.
+pub struct Fixups<'a> {
+    /// SDRAM `(base, size)`, written to `/memory`.
+    pub memory: (u64, u64),
+    /// Machine timer frequency, written to `/cpus`.
+    pub timebase_frequency: u32,
+    /// Console UART `(base address, input clock frequency)`. The firmware keeps driving
+    /// it, so its node is disabled and the kernel goes through the SBI console.
+    pub uart: (usize, u32),
+    /// Firmware region `(base, size)` added to `/reserved-memory`, `None` when the firmware
+    /// lies outside of `/memory` and needs no reservation.
+    pub reserved: Option<(u64, u64)>,
+    /// Kernel command line for `/chosen`. `None` keeps what the tree has.
+    pub bootargs: Option<&'a str>,
+    /// Initrd `(start, end)` for `/chosen`. `None` keeps what the tree has.
+    pub initrd: Option<(u64, u64)>,
+    /// More `/chosen` string properties, replacing those of the same name. Names the
+    /// fields above take care of are ignored.
+    pub chosen: &'a [(&'a str, &'a str)],
+}
+
+impl<'a> Fixups<'a> {
+    pub(super) fn chosen_extras(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.chosen
+            .iter()
+            .copied()
+            .filter(|&(name, _)| prop_bit(name) == 0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Root,
+    Memory,
+    Cpus,
+    Cpu,
+    Uart,
+    Chosen,
+    ReservedMemory,
+    Other,
+}
+
+/* Properties the fix-up pass owns */
+const REG: u8 = 1 << 0;
+const TIMEBASE_FREQUENCY: u8 = 1 << 1;
+const CLOCK_FREQUENCY: u8 = 1 << 2;
+const BOOTARGS: u8 = 1 << 3;
+const INITRD_START: u8 = 1 << 4;
+const INITRD_END: u8 = 1 << 5;
+const CHOSEN_EXTRA: u8 = 1 << 6;
+const STATUS: u8 = 1 << 7;
+
+#[derive(Clone, Copy)]
+struct Level {
+    kind: Kind,
+    /// Owned properties still to be written before the first child or the end of the node.
+    pending: u8,
+}
+
+struct Fixer<'f, 'a> {
+    fixups: &'f Fixups<'a>,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+/// Stream `src` into `dst`, replacing or adding what [`Fixups`] describes.
+/// Returns the size of the new blob.
+pub fn fixup(src: &Fdt, dst: &mut [u8], fixups: &Fixups) -> Result<usize> {
+    let root = src.root();
+    let fixer = Fixer {
+        fixups,
+        address_cells: root.property_u32("#address-cells").unwrap_or(2),
+        size_cells: root.property_u32("#size-cells").unwrap_or(1),
+    };
+    let reserved_memory = src.find("/reserved-memory");
+    let reserved_cells = (
+        reserved_memory
+            .and_then(|n| n.property_u32("#address-cells"))
+            .unwrap_or(fixer.address_cells),
+        reserved_memory
+            .and_then(|n| n.property_u32("#size-cells"))
+            .unwrap_or(fixer.size_cells),
+    );
+
+    let mut w = FdtWriter::new(dst)?;
+    w.set_boot_cpuid(src.boot_cpuid());
+    for (addr, size) in src.reservations() {
+        w.reserve_memory(addr, size)?;
+    }
+
+    let mut stack = [Level {
+        kind: Kind::Other,
+        pending: 0,
+    }; MAX_DEPTH];
+    let mut depth = 0;
+    let (mut has_memory, mut has_chosen, mut has_reserved) = (false, false, false);
+
+    for token in src.tokens() {
+        match token {
+            Token::BeginNode(name) => {
+                let parent = if depth == 0 {
+                    None
+                } else {
+                    fixer.flush(&mut w, &mut stack[depth - 1])?;
+                    Some(stack[depth - 1].kind)
+                };
+                let kind = fixer.classify(parent, name);
+                has_memory |= kind == Kind::Memory;
+                has_chosen |= kind == Kind::Chosen;
+                has_reserved |= kind == Kind::ReservedMemory;
+                stack[depth] = Level {
+                    kind,
+                    pending: fixer.owned(kind),
+                };
+                depth += 1;
+                w.begin_node(name)?;
+            }
+            Token::Property(name, value) => {
+                let level = &mut stack[depth - 1];
+                let prop = fixer.owned(level.kind) & prop_bit(name);
+                if prop != 0 {
+                    fixer.emit(&mut w, prop)?;
+                    level.pending &= !prop;
+                } else if level.kind == Kind::Chosen
+                    && fixer.fixups.chosen_extras().any(|(n, _)| n == name)
+                {
+                    // Replaced when the extras are emitted
+                } else {
+                    w.property(name, value)?;
+                }
+            }
+            Token::EndNode => {
+                let level = &mut stack[depth - 1];
+                fixer.flush(&mut w, level)?;
+                match level.kind {
+                    Kind::ReservedMemory => {
+                        if let Some(region) = fixups.reserved {
+                            fixer.firmware_node(&mut w, reserved_cells, region)?;
+                        }
+                    }
+                    Kind::Root => {
+                        if !has_memory {
+                            fixer.memory_node(&mut w)?;
+                        }
+                        if let (false, Some(region)) = (has_reserved, fixups.reserved) {
+                            fixer.reserved_memory_node(&mut w, region)?;
+                        }
+                        if !has_chosen {
+                            w.begin_node("chosen")?;
+                            fixer.emit(&mut w, fixer.owned(Kind::Chosen))?;
+                            w.end_node()?;
+                        }
+                    }
+                    _ => {}
+                }
+                depth -= 1;
+                w.end_node()?;
+            }
+        }
+    }
+    w.finish()
+}
+
+/// An address in one cell when it fits, which is what 32-bit kernels expect.
+pub(super) fn property_addr(w: &mut FdtWriter, name: &str, addr: u64) -> Result<()> {
+    match u32::try_from(addr) {
+        Ok(addr) => w.property_u32(name, addr),
+        Err(_) => w.property_u64(name, addr),
+    }
+}
+
+fn prop_bit(name: &str) -> u8 {
+    match name {
+        "reg" => REG,
+        "timebase-frequency" => TIMEBASE_FREQUENCY,
+        "clock-frequency" => CLOCK_FREQUENCY,
+        "bootargs" => BOOTARGS,
+        "linux,initrd-start" => INITRD_START,
+        "linux,initrd-end" => INITRD_END,
+        "status" => STATUS,
+        _ => 0,
+    }
+}
+
+impl<'f, 'a> Fixer<'f, 'a> {
+    fn classify(&self, parent: Option<Kind>, name: &str) -> Kind {
+        let unit_address = name
+            .split_once('@')
+            .and_then(|(_, unit)| usize::from_str_radix(unit, 16).ok());
+        match parent {
+            None => Kind::Root,
+            Some(Kind::Root) if node_name_matches(name, "memory") => Kind::Memory,
+            Some(Kind::Root) if name == "cpus" => Kind::Cpus,
+            Some(Kind::Root) if name == "chosen" => Kind::Chosen,
+            Some(Kind::Root) if name == "reserved-memory" => Kind::ReservedMemory,
+            Some(Kind::Cpus) if node_name_matches(name, "cpu") => Kind::Cpu,
+            _ if unit_address == Some(self.fixups.uart.0) => Kind::Uart,
+            _ => Kind::Other,
+        }
+    }
+
+    /// Properties of `kind` this pass replaces, and adds if missing.
+    fn owned(&self, kind: Kind) -> u8 {
+        match kind {
+            Kind::Memory => REG,
+            Kind::Cpus | Kind::Cpu => TIMEBASE_FREQUENCY,
+            Kind::Uart => CLOCK_FREQUENCY | STATUS,
+            Kind::Chosen => {
+                let bootargs = if self.fixups.bootargs.is_some() {
+                    BOOTARGS
+                } else {
+                    0
+                };
+                let initrd = if self.fixups.initrd.is_some() {
+                    INITRD_START | INITRD_END
+                } else {
+                    0
+                };
+                let extra = if self.fixups.chosen_extras().next().is_some() {
+                    CHOSEN_EXTRA
+                } else {
+                    0
+                };
+                bootargs | initrd | extra
+            }
+            _ => 0,
+        }
+    }
+
+    fn flush(&self, w: &mut FdtWriter, level: &mut Level) -> Result<()> {
+        // A CPU node only gets its timebase replaced, never added
+        if level.kind != Kind::Cpu {
+            self.emit(w, level.pending)?;
+        }
+        level.pending = 0;
+        Ok(())
+    }
+
+    fn emit(&self, w: &mut FdtWriter, props: u8) -> Result<()> {
+        let f = self.fixups;
+        if props & REG != 0 {
+            let (base, size) = f.memory;
+            w.property_reg("reg", self.address_cells, self.size_cells, base, size)?;
+        }
+        if props & TIMEBASE_FREQUENCY != 0 {
+            w.property_u32("timebase-frequency", f.timebase_frequency)?;
+        }
+        if props & CLOCK_FREQUENCY != 0 {
+            w.property_u32("clock-frequency", f.uart.1)?;
+        }
+        if props & STATUS != 0 {
+            w.property_str("status", "disabled")?;
+        }
+        if let (true, Some(bootargs)) = (props & BOOTARGS != 0, f.bootargs) {
+            w.property_str("bootargs", bootargs)?;
+        }
+        if let Some((start, end)) = f.initrd {
+            if props & INITRD_START != 0 {
+                property_addr(w, "linux,initrd-start", start)?;
+            }
+            if props & INITRD_END != 0 {
+                property_addr(w, "linux,initrd-end", end)?;
+            }
+        }
+        if props & CHOSEN_EXTRA != 0 {
+            for (name, value) in f.chosen_extras() {
+                w.property_str(name, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_node(&self, w: &mut FdtWriter) -> Result<()> {
+        let mut name = NameBuf::new();
+        w.begin_node(name.unit("memory", self.fixups.memory.0))?;
+        w.property_str("device_type", "memory")?;
+        self.emit(w, REG)?;
+        w.end_node()
+    }
+
+    fn reserved_memory_node(&self, w: &mut FdtWriter, region: (u64, u64)) -> Result<()> {
+        w.begin_node("reserved-memory")?;
+        w.property_u32("#address-cells", self.address_cells)?;
+        w.property_u32("#size-cells", self.size_cells)?;
+        w.property_empty("ranges")?;
+        self.firmware_node(w, (self.address_cells, self.size_cells), region)?;
+        w.end_node()
+    }
+
+    fn firmware_node(
+        &self,
+        w: &mut FdtWriter,
+        (address_cells, size_cells): (u32, u32),
+        (base, size): (u64, u64),
+    ) -> Result<()> {
+        let mut name = NameBuf::new();
+        w.begin_node(name.unit("rustsbi", base))?;
+        w.property_reg("reg", address_cells, size_cells, base, size)?;
+        w.property_empty("no-map")?;
+        w.end_node()
+    }
+}
+
+/// Stack buffer for short formatted strings, such as `memory@40000000`.
+pub(super) struct NameBuf {
+    buf: [u8; 48],
+    len: usize,
+}
+
+impl NameBuf {
+    pub(super) fn new() -> Self {
+        Self {
+            buf: [0; 48],
+            len: 0,
+        }
+    }
+
+    pub(super) fn unit(&mut self, name: &str, address: u64) -> &str {
+        self.format(format_args!("{name}@{address:x}"))
+    }
+
+    pub(super) fn format(&mut self, args: core::fmt::Arguments) -> &str {
+        self.len = 0;
+        self.write_fmt(args).unwrap();
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl Write for NameBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let dst = self
+            .buf
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(core::fmt::Error)?;
+        dst.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UART: usize = 0xF004_0000;
+
+    /// A board tree with memory at `0x4000_0000` and, optionally, a command line.
+    fn source(buf: &mut [u8], bootargs: Option<&str>) -> usize {
+        let mut w = FdtWriter::new(buf).unwrap();
+        let mut name = NameBuf::new();
+        w.begin_node("").unwrap();
+        w.property_u32("#address-cells", 1).unwrap();
+        w.property_u32("#size-cells", 1).unwrap();
+        w.begin_node("memory@40000000").unwrap();
+        w.property_str("device_type", "memory").unwrap();
+        w.property_reg("reg", 1, 1, 0x4000_0000, 0x100_0000)
+            .unwrap();
+        w.end_node().unwrap();
+        w.begin_node(name.unit("serial", UART as u64)).unwrap();
+        w.property_u32("clock-frequency", 24_000_000).unwrap();
+        w.property_str("status", "okay").unwrap();
+        w.end_node().unwrap();
+        w.begin_node("chosen").unwrap();
+        if let Some(bootargs) = bootargs {
+            w.property_str("bootargs", bootargs).unwrap();
+        }
+        w.end_node().unwrap();
+        w.end_node().unwrap();
+        w.finish().unwrap()
+    }
+
+    fn fixups(reserved: Option<(u64, u64)>, bootargs: Option<&str>) -> Fixups<'_> {
+        Fixups {
+            memory: (0x4000_0000, 0x200_0000),
+            timebase_frequency: 24_000_000,
+            uart: (UART, 80_000_000),
+            reserved,
+            bootargs,
+            initrd: None,
+            chosen: &[],
+        }
+    }
+
+    fn fix<'a>(src: &[u8], dst: &'a mut [u8], fixups: &Fixups) -> Fdt<'a> {
+        let size = fixup(&Fdt::new(src).unwrap(), dst, fixups).unwrap();
+        Fdt::new(&dst[..size]).unwrap()
+    }
+
+    #[test]
+    fn keeps_bootargs_without_override() {
+        let (mut src, mut dst) = ([0; 4096], [0; 4096]);
+        source(&mut src, Some("console=hvc0 root=/dev/mmcblk0p2"));
+        let fdt = fix(&src, &mut dst, &fixups(None, None));
+        let chosen = fdt.find("/chosen").unwrap();
+        assert_eq!(
+            chosen.property_str("bootargs"),
+            Some("console=hvc0 root=/dev/mmcblk0p2")
+        );
+    }
+
+    #[test]
+    fn replaces_and_adds_bootargs() {
+        let (mut src, mut dst) = ([0; 4096], [0; 4096]);
+        source(&mut src, Some("console=hvc0"));
+        let fdt = fix(&src, &mut dst, &fixups(None, Some("earlycon=sbi")));
+        let chosen = fdt.find("/chosen").unwrap();
+        assert_eq!(chosen.property_str("bootargs"), Some("earlycon=sbi"));
+
+        source(&mut src, None);
+        let fdt = fix(&src, &mut dst, &fixups(None, Some("earlycon=sbi")));
+        let chosen = fdt.find("/chosen").unwrap();
+        assert_eq!(chosen.property_str("bootargs"), Some("earlycon=sbi"));
+    }
+
+    #[test]
+    fn memory_and_clocks() {
+        let (mut src, mut dst) = ([0; 4096], [0; 4096]);
+        source(&mut src, None);
+        let fdt = fix(&src, &mut dst, &fixups(None, None));
+        let reg = fdt.find("/memory").unwrap().property("reg").unwrap();
+        assert_eq!(reg, [0x40, 0, 0, 0, 0x02, 0, 0, 0]);
+        let uart = fdt.find("/serial@f0040000").unwrap();
+        assert_eq!(uart.property_u32("clock-frequency"), Some(80_000_000));
+    }
+
+    #[test]
+    fn disables_console_uart() {
+        let (mut src, mut dst) = ([0; 4096], [0; 4096]);
+        source(&mut src, None);
+        let fdt = fix(&src, &mut dst, &fixups(None, None));
+        let uart = fdt.find("/serial@f0040000").unwrap();
+        assert_eq!(uart.property_str("status"), Some("disabled"));
+        assert_eq!(
+            uart.properties()
+                .filter(|&(name, _)| name == "status")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn reserved_memory_only_when_given() {
+        let (mut src, mut dst) = ([0; 4096], [0; 4096]);
+        source(&mut src, None);
+        let fdt = fix(&src, &mut dst, &fixups(None, None));
+        assert!(fdt.find("/reserved-memory").is_none());
+
+        let fdt = fix(&src, &mut dst, &fixups(Some((0x4000_0000, 0x1_0000)), None));
+        let firmware = fdt.find("/reserved-memory/rustsbi@40000000").unwrap();
+        assert_eq!(
+            firmware.property("reg"),
+            Some(&[0x40, 0, 0, 0, 0, 0x01, 0, 0][..])
+        );
+        assert!(firmware.property("no-map").is_some());
+    }
+
+    #[test]
+    fn rejects_out_of_range_blocks() {
+        let mut src = [0; 4096];
+        let size = source(&mut src, None);
+        assert!(Fdt::new(&src[..size - 1]).is_err());
+        // Strings block offset near the top of the address space
+        let mut bad = src;
+        bad[12..16].copy_from_slice(&0xFFFF_FFF0u32.to_be_bytes());
+        assert_eq!(Fdt::new(&bad).err(), Some(FdtError::Truncated));
+    }
+}
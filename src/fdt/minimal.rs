@@ -0,0 +1,177 @@
+use super::fixup::{property_addr, NameBuf};
+use super::*;
+
+/// Machine description used when there is no device tree to fix up.
+pub struct Platform<'a> {
+    pub model: &'a str,
+    pub compatible: &'a str,
+    pub boot_hart: u32,
+    /// Harts described under `/cpus`, numbered from 0.
+    pub harts: u32,
+    /// Raw value of `misa`, turned into the `riscv,isa` string.
+    pub misa: usize,
+}
+
+/// Of hart 0, the other harts following.
+const CPU_INTC_PHANDLE: u32 = 1;
+
+/// Write a minimal device tree, just enough for a kernel to reach its early console.
+pub fn generate(dst: &mut [u8], platform: &Platform, fixups: &Fixups) -> Result<usize> {
+    let mut w = FdtWriter::new(dst)?;
+    let mut name = NameBuf::new();
+    w.set_boot_cpuid(platform.boot_hart);
+
+    w.begin_node("")?;
+    w.property_u32("#address-cells", 1)?;
+    w.property_u32("#size-cells", 1)?;
+    w.property_str("model", platform.model)?;
+    w.property_str("compatible", platform.compatible)?;
+
+    w.begin_node("cpus")?;
+    w.property_u32("#address-cells", 1)?;
+    w.property_u32("#size-cells", 0)?;
+    w.property_u32("timebase-frequency", fixups.timebase_frequency)?;
+    for hart in 0..platform.harts {
+        w.begin_node(name.unit("cpu", hart as u64))?;
+        w.property_str("device_type", "cpu")?;
+        w.property_u32("reg", hart)?;
+        w.property_str("status", "okay")?;
+        w.property_str("compatible", "riscv")?;
+        w.property_str("riscv,isa", isa_string(platform.misa, &mut name))?;
+        w.property_str("mmu-type", "riscv,sv32")?;
+        w.begin_node("interrupt-controller")?;
+        w.property_u32("#interrupt-cells", 1)?;
+        w.property_empty("interrupt-controller")?;
+        w.property_str("compatible", "riscv,cpu-intc")?;
+        w.property_u32("phandle", CPU_INTC_PHANDLE + hart)?;
+        w.end_node()?;
+        w.end_node()?;
+    }
+    w.end_node()?;
+
+    let (base, size) = fixups.memory;
+    w.begin_node(name.unit("memory", base))?;
+    w.property_str("device_type", "memory")?;
+    w.property_reg("reg", 1, 1, base, size)?;
+    w.end_node()?;
+
+    if let Some((base, size)) = fixups.reserved {
+        w.begin_node("reserved-memory")?;
+        w.property_u32("#address-cells", 1)?;
+        w.property_u32("#size-cells", 1)?;
+        w.property_empty("ranges")?;
+        w.begin_node(name.unit("rustsbi", base))?;
+        w.property_reg("reg", 1, 1, base, size)?;
+        w.property_empty("no-map")?;
+        w.end_node()?;
+        w.end_node()?;
+    }
+
+    let (uart_base, uart_clock) = fixups.uart;
+    w.begin_node("soc")?;
+    w.property_u32("#address-cells", 1)?;
+    w.property_u32("#size-cells", 1)?;
+    w.property_str("compatible", "simple-bus")?;
+    w.property_empty("ranges")?;
+    w.begin_node(name.unit("serial", uart_base as u64))?;
+    w.property_str("compatible", "ns16550a")?;
+    w.property_reg("reg", 1, 1, uart_base as u64, 0x1000)?;
+    // 16550 compatible registers start after the identification block
+    w.property_u32("reg-offset", 0x20)?;
+    w.property_u32("reg-shift", 2)?;
+    w.property_u32("reg-io-width", 4)?;
+    w.property_u32("clock-frequency", uart_clock)?;
+    // Driven by the firmware, the kernel has the SBI console
+    w.property_str("status", "disabled")?;
+    w.end_node()?;
+    w.end_node()?;
+
+    w.begin_node("chosen")?;
+    if let Some(bootargs) = fixups.bootargs {
+        w.property_str("bootargs", bootargs)?;
+    }
+    if let Some((start, end)) = fixups.initrd {
+        property_addr(&mut w, "linux,initrd-start", start)?;
+        property_addr(&mut w, "linux,initrd-end", end)?;
+    }
+    // No `stdout-path`: the serial node is disabled, `console=hvc0` picks the SBI console
+    for (name, value) in fixups.chosen_extras() {
+        w.property_str(name, value)?;
+    }
+    w.end_node()?;
+
+    w.end_node()?;
+    w.finish()
+}
+
+/// Build an ISA string such as `rv32imac` from the extension bits of `misa`.
+fn isa_string(misa: usize, buf: &mut NameBuf) -> &str {
+    const CANONICAL_ORDER: &[u8] = b"iemafdqlcbkjtpvh";
+    let xlen = match misa >> (usize::BITS - 2) {
+        2 => 64,
+        3 => 128,
+        _ => 32,
+    };
+    let mut extensions = [0u8; 16];
+    let mut n = 0;
+    for &ext in CANONICAL_ORDER {
+        if misa & (1 << (ext - b'a')) != 0 {
+            extensions[n] = ext;
+            n += 1;
+        }
+    }
+    let extensions = core::str::from_utf8(&extensions[..n]).unwrap();
+    buf.format(format_args!("rv{xlen}{extensions}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UART: usize = 0xF004_0000;
+
+    fn generated(dst: &mut [u8]) -> Fdt<'_> {
+        let platform = Platform {
+            model: "HPM6360EVK",
+            compatible: "hpmicro,hpm6360evk",
+            boot_hart: 0,
+            harts: 1,
+            misa: 0x4000_1105,
+        };
+        let fixups = Fixups {
+            memory: (0x4000_0000, 0x200_0000),
+            timebase_frequency: 24_000_000,
+            uart: (UART, 80_000_000),
+            reserved: None,
+            bootargs: Some("earlycon=sbi console=hvc0"),
+            initrd: None,
+            chosen: &[],
+        };
+        let size = generate(dst, &platform, &fixups).unwrap();
+        Fdt::new(&dst[..size]).unwrap()
+    }
+
+    #[test]
+    fn console() {
+        let mut buf = [0; 4096];
+        let fdt = generated(&mut buf);
+        let uart = fdt.find("/soc/serial@f0040000").unwrap();
+        assert_eq!(uart.property_str("status"), Some("disabled"));
+        // Whatever `stdout-path` names must be usable by the kernel
+        let chosen = fdt.find("/chosen").unwrap();
+        if let Some(path) = chosen.property_str("stdout-path") {
+            let node = path.split(':').next().unwrap();
+            let status = fdt.find(node).unwrap().property_str("status");
+            assert_ne!(status, Some("disabled"), "{path}");
+        }
+        assert!(chosen
+            .property_str("bootargs")
+            .is_some_and(|bootargs| bootargs.contains("console=hvc0")));
+    }
+
+    #[test]
+    fn isa() {
+        let mut buf = NameBuf::new();
+        assert_eq!(isa_string(0x4000_1105, &mut buf), "rv32imac");
+    }
+}
@@ -0,0 +1,314 @@
+//! Flattened device tree (FDT) reading and writing.
+//!
+//! The reader validates the whole structure block once in [`Fdt::new`], so walking nodes
+//! and properties afterwards cannot fail. The writer emits a new blob into a caller
+//! provided buffer; editing an existing tree is done by streaming it through the writer
+//! (see [`fixup`]). When there is no tree at all, [`generate`] writes a minimal one.
+
+mod fixup;
+mod minimal;
+mod writer;
+
+pub use fixup::{fixup, Fixups};
+pub use minimal::{generate, Platform};
+pub use writer::FdtWriter;
+
+pub const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+/// Deepest node nesting accepted by the reader and the fix-up pass.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// Not a device tree blob.
+    BadMagic,
+    /// Blob version older than the format we can read.
+    BadVersion,
+    /// Header or block extends past the end of the blob.
+    Truncated,
+    /// Malformed structure block.
+    BadStructure,
+    /// Output buffer too small.
+    NoSpace,
+}
+
+type Result<T> = core::result::Result<T, FdtError>;
+
+#[inline]
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+#[inline]
+const fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Property(&'a str, &'a [u8]),
+}
+
+impl<'a> Fdt<'a> {
+    /// Validate the blob at the start of `data`. `data` may be longer than the blob.
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        let header = |i: usize| be32(data, i * 4).ok_or(FdtError::Truncated);
+        if header(0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = header(1)? as usize;
+        let off_struct = header(2)? as usize;
+        let off_strings = header(3)? as usize;
+        let version = header(5)?;
+        let last_comp_version = header(6)?;
+        let size_strings = header(8)? as usize;
+        let size_struct = header(9)? as usize;
+        if version < FDT_LAST_COMP_VERSION || last_comp_version > FDT_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+
+        let data = data.get(..total_size).ok_or(FdtError::Truncated)?;
+        // Offsets and sizes come from the blob, so their sum may not even be addressable
+        let block = |off: usize, size: usize| {
+            off.checked_add(size)
+                .and_then(|end| data.get(off..end))
+                .ok_or(FdtError::Truncated)
+        };
+        let fdt = Fdt {
+            data,
+            structs: block(off_struct, size_struct)?,
+            strings: block(off_strings, size_strings)?,
+        };
+        fdt.validate()?;
+        Ok(fdt)
+    }
+
+    /// Check every token once, so that [`Tokens`] can skip error handling.
+    fn validate(&self) -> Result<()> {
+        let mut off = 0;
+        let mut depth = 0usize;
+        loop {
+            let (token, next) = self.raw_token(off).ok_or(FdtError::BadStructure)?;
+            off = next;
+            match token {
+                Some(Token::BeginNode(_)) => {
+                    depth += 1;
+                    if depth > MAX_DEPTH {
+                        return Err(FdtError::BadStructure);
+                    }
+                }
+                Some(Token::EndNode) => {
+                    depth = depth.checked_sub(1).ok_or(FdtError::BadStructure)?;
+                }
+                Some(Token::Property(..)) if depth == 0 => return Err(FdtError::BadStructure),
+                Some(Token::Property(..)) => {}
+                None if depth == 0 => return Ok(()),
+                None => return Err(FdtError::BadStructure),
+            }
+        }
+    }
+
+    /// Decode the token at `off`. `Some((None, _))` is `FDT_END`, NOPs are skipped.
+    fn raw_token(&self, mut off: usize) -> Option<(Option<Token<'a>>, usize)> {
+        loop {
+            let tag = be32(self.structs, off)?;
+            off += 4;
+            match tag {
+                FDT_BEGIN_NODE => {
+                    let rest = self.structs.get(off..)?;
+                    let len = rest.iter().position(|&b| b == 0)?;
+                    let name = core::str::from_utf8(&rest[..len]).ok()?;
+                    return Some((Some(Token::BeginNode(name)), align4(off + len + 1)));
+                }
+                FDT_END_NODE => return Some((Some(Token::EndNode), off)),
+                FDT_PROP => {
+                    let len = be32(self.structs, off)? as usize;
+                    let name_off = be32(self.structs, off + 4)? as usize;
+                    let value = self.structs.get(off + 8..(off + 8).checked_add(len)?)?;
+                    let rest = self.strings.get(name_off..)?;
+                    let name_len = rest.iter().position(|&b| b == 0)?;
+                    let name = core::str::from_utf8(&rest[..name_len]).ok()?;
+                    return Some((Some(Token::Property(name, value)), align4(off + 8 + len)));
+                }
+                FDT_NOP => continue,
+                FDT_END => return Some((None, off)),
+                _ => return None,
+            }
+        }
+    }
+
+    /// The blob itself, without any trailing data.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn boot_cpuid(&self) -> u32 {
+        be32(self.data, 28).unwrap_or(0)
+    }
+
+    /// Entries of the memory reservation block as `(address, size)`.
+    pub fn reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let data = self.data;
+        let start = be32(data, 16).unwrap_or(0) as usize;
+        (0..)
+            .map(move |i| {
+                let entry = data.get(start + i * 16..start + i * 16 + 16)?;
+                let addr = u64::from_be_bytes(entry[..8].try_into().unwrap());
+                let size = u64::from_be_bytes(entry[8..].try_into().unwrap());
+                Some((addr, size))
+            })
+            .take_while(|e| matches!(e, Some((a, s)) if *a != 0 || *s != 0))
+            .map(Option::unwrap)
+    }
+
+    /// Every token of the structure block in order.
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens { fdt: *self, off: 0 }
+    }
+
+    pub fn root(&self) -> Node<'a> {
+        let (_, off) = self.raw_token(0).unwrap();
+        Node {
+            fdt: *self,
+            name: "",
+            off,
+        }
+    }
+
+    /// Look up a node by absolute path, e.g. `/images/kernel`.
+    pub fn find(&self, path: &str) -> Option<Node<'a>> {
+        path.split('/')
+            .filter(|s| !s.is_empty())
+            .try_fold(self.root(), |node, name| node.child(name))
+    }
+}
+
+pub struct Tokens<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let (token, off) = self.fdt.raw_token(self.off)?;
+        self.off = off;
+        token
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of the first token inside the node.
+    off: usize,
+}
+
+impl<'a> Node<'a> {
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// The part of the name after `@`, parsed as a hexadecimal address.
+    pub fn unit_address(&self) -> Option<usize> {
+        let (_, unit) = self.name.split_once('@')?;
+        usize::from_str_radix(unit, 16).ok()
+    }
+
+    fn tokens(&self) -> Tokens<'a> {
+        Tokens {
+            fdt: self.fdt,
+            off: self.off,
+        }
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        self.tokens().map_while(|t| match t {
+            Token::Property(name, value) => Some((name, value)),
+            _ => None,
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        self.property(name).and_then(|v| be32(v, 0))
+    }
+
+    /// A one- or two-cell integer property, such as `load` in a FIT image.
+    pub fn property_u64(&self, name: &str) -> Option<u64> {
+        let v = self.property(name)?;
+        match v.len() {
+            4 => be32(v, 0).map(u64::from),
+            8 => Some(u64::from_be_bytes(v.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    /// A string property, without its terminating NUL.
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        let v = self.property(name)?;
+        core::str::from_utf8(v.strip_suffix(&[0]).unwrap_or(v)).ok()
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> {
+        let fdt = self.fdt;
+        let mut off = self.off;
+        let mut depth = 0usize;
+        core::iter::from_fn(move || loop {
+            let (token, next) = fdt.raw_token(off)?;
+            off = next;
+            match token? {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if depth == 1 {
+                        return Some(Node {
+                            fdt,
+                            name,
+                            off: next,
+                        });
+                    }
+                }
+                Token::EndNode if depth == 0 => return None,
+                Token::EndNode => depth -= 1,
+                Token::Property(..) => {}
+            }
+        })
+    }
+
+    /// Find a child by full name, or by name without unit address when `name` has none.
+    pub fn child(&self, name: &str) -> Option<Node<'a>> {
+        self.children().find(|c| node_name_matches(c.name, name))
+    }
+}
+
+/// `memory@40000000` matches both `memory@40000000` and `memory`.
+pub fn node_name_matches(full: &str, name: &str) -> bool {
+    full == name || (!name.contains('@') && full.split('@').next() == Some(name))
+}
@@ -0,0 +1,281 @@
+use super::*;
+
+/// Sequential device tree builder writing into a fixed buffer.
+///
+/// Memory reservations must be added before the first node, and all properties of a
+/// node before its first child, as the FDT format requires.
+///
+/// The structure block grows up from the start of the buffer and the strings block down
+/// from its end, so the buffer only runs out when the two meet. Until [`finish`] moves
+/// the strings right after the structure, property name offsets count back from the end
+/// of the buffer.
+///
+/// [`finish`]: FdtWriter::finish
+pub struct FdtWriter<'a> {
+    buf: &'a mut [u8],
+    /// Write position of the memory reservation block, then of the structure block.
+    off: usize,
+    off_struct: usize,
+    /// Start of the last string added, the strings block extending to the end of `buf`.
+    strings_start: usize,
+    depth: usize,
+    boot_cpuid: u32,
+}
+
+impl<'a> FdtWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE + 16 {
+            return Err(FdtError::NoSpace);
+        }
+        let strings_start = buf.len();
+        Ok(Self {
+            buf,
+            off: HEADER_SIZE,
+            off_struct: 0,
+            strings_start,
+            depth: 0,
+            boot_cpuid: 0,
+        })
+    }
+
+    pub fn set_boot_cpuid(&mut self, hartid: u32) {
+        self.boot_cpuid = hartid;
+    }
+
+    /// Add an entry to the memory reservation block.
+    pub fn reserve_memory(&mut self, addr: u64, size: u64) -> Result<()> {
+        assert!(self.off_struct == 0, "reservations must precede nodes");
+        self.put(&addr.to_be_bytes())?;
+        self.put(&size.to_be_bytes())
+    }
+
+    pub fn begin_node(&mut self, name: &str) -> Result<()> {
+        if self.off_struct == 0 {
+            // Terminate the memory reservation block
+            self.put(&[0; 16])?;
+            self.off_struct = self.off;
+        }
+        self.put(&FDT_BEGIN_NODE.to_be_bytes())?;
+        self.put(name.as_bytes())?;
+        self.put(&[0])?;
+        self.pad()?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn end_node(&mut self) -> Result<()> {
+        assert!(self.depth > 0, "unbalanced end_node");
+        self.depth -= 1;
+        self.put(&FDT_END_NODE.to_be_bytes())
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) -> Result<()> {
+        let name_off = self.intern(name)?;
+        self.put(&FDT_PROP.to_be_bytes())?;
+        self.put(&(value.len() as u32).to_be_bytes())?;
+        self.put(&(name_off as u32).to_be_bytes())?;
+        self.put(value)?;
+        self.pad()
+    }
+
+    pub fn property_empty(&mut self, name: &str) -> Result<()> {
+        self.property(name, &[])
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) -> Result<()> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) -> Result<()> {
+        self.property(name, &value.to_be_bytes())
+    }
+
+    pub fn property_str(&mut self, name: &str, value: &str) -> Result<()> {
+        self.property_strs(name, &[value])
+    }
+
+    /// A string list property, such as `compatible`.
+    pub fn property_strs(&mut self, name: &str, values: &[&str]) -> Result<()> {
+        let len = values.iter().map(|s| s.len() + 1).sum::<usize>();
+        self.begin_property(name, len)?;
+        for s in values {
+            self.put(s.as_bytes())?;
+            self.put(&[0])?;
+        }
+        self.pad()
+    }
+
+    /// A `reg`-style property with a single `(address, size)` pair.
+    pub fn property_reg(
+        &mut self,
+        name: &str,
+        address_cells: u32,
+        size_cells: u32,
+        address: u64,
+        size: u64,
+    ) -> Result<()> {
+        let mut value = [0u8; 16];
+        let a = address_cells.min(2) as usize * 4;
+        let s = size_cells.min(2) as usize * 4;
+        value[..a].copy_from_slice(&address.to_be_bytes()[8 - a..]);
+        value[a..a + s].copy_from_slice(&size.to_be_bytes()[8 - s..]);
+        self.property(name, &value[..a + s])
+    }
+
+    fn begin_property(&mut self, name: &str, len: usize) -> Result<()> {
+        let name_off = self.intern(name)?;
+        self.put(&FDT_PROP.to_be_bytes())?;
+        self.put(&(len as u32).to_be_bytes())?;
+        self.put(&(name_off as u32).to_be_bytes())
+    }
+
+    /// Finish the blob and return its total size.
+    pub fn finish(mut self) -> Result<usize> {
+        assert!(self.depth == 0, "unbalanced begin_node");
+        self.put(&FDT_END.to_be_bytes())?;
+        let size_struct = self.off - self.off_struct;
+        let off_strings = self.off;
+        let strings_len = self.buf.len() - self.strings_start;
+        self.rebase_name_offsets(strings_len);
+        self.buf
+            .copy_within(self.strings_start..self.buf.len(), off_strings);
+        let total_size = off_strings + strings_len;
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            self.off_struct as u32,
+            off_strings as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings_len as u32,
+            size_struct as u32,
+        ];
+        for (i, word) in header.iter().enumerate() {
+            self.buf[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        Ok(total_size)
+    }
+
+    /// Turn the name offsets of all properties, counted back from the end of the buffer,
+    /// into offsets into the `strings_len` bytes of the strings block.
+    fn rebase_name_offsets(&mut self, strings_len: usize) {
+        let mut off = self.off_struct;
+        while let Some(token) = be32(self.buf, off) {
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = self.buf[off..].iter().position(|&b| b == 0).unwrap();
+                    off = align4(off + name + 1);
+                }
+                FDT_PROP => {
+                    let len = be32(self.buf, off).unwrap() as usize;
+                    let from_end = be32(self.buf, off + 4).unwrap() as usize;
+                    let name_off = (strings_len - from_end) as u32;
+                    self.buf[off + 4..off + 8].copy_from_slice(&name_off.to_be_bytes());
+                    off = align4(off + 8 + len);
+                }
+                FDT_END => break,
+                _ => {}
+            }
+        }
+    }
+
+    /// Where `name` is in the strings block, counted back from the end of the buffer,
+    /// adding it if not present yet.
+    fn intern(&mut self, name: &str) -> Result<usize> {
+        let mut off = self.strings_start;
+        for s in self.buf[self.strings_start..].split(|&b| b == 0) {
+            if off == self.buf.len() {
+                break;
+            }
+            if s == name.as_bytes() {
+                return Ok(self.buf.len() - off);
+            }
+            off += s.len() + 1;
+        }
+
+        let start = self
+            .strings_start
+            .checked_sub(name.len() + 1)
+            .filter(|&start| start >= self.off)
+            .ok_or(FdtError::NoSpace)?;
+        self.buf[start..start + name.len()].copy_from_slice(name.as_bytes());
+        self.buf[start + name.len()] = 0;
+        self.strings_start = start;
+        Ok(self.buf.len() - start)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        let end = self.off + bytes.len();
+        if end > self.strings_start {
+            return Err(FdtError::NoSpace);
+        }
+        self.buf[self.off..end].copy_from_slice(bytes);
+        self.off = end;
+        Ok(())
+    }
+
+    fn pad(&mut self) -> Result<()> {
+        let padding = align4(self.off) - self.off;
+        self.put(&[0; 3][..padding])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tree with `names` distinct property names of some 30 bytes each.
+    fn write(buf: &mut [u8], names: usize) -> Result<usize> {
+        let mut w = FdtWriter::new(buf)?;
+        w.reserve_memory(0x4000_0000, 0x1_0000)?;
+        w.begin_node("")?;
+        w.property_u32("#address-cells", 1)?;
+        for i in 0..names {
+            w.begin_node(&format!("node@{i:x}"))?;
+            w.property_u32(&format!("vendor,property-with-a-long-name-{i}"), i as u32)?;
+            // Interned once, used by every node
+            w.property_str("status", "okay")?;
+            w.end_node()?;
+        }
+        w.end_node()?;
+        w.finish()
+    }
+
+    #[test]
+    fn strings_share_the_buffer() {
+        // Far more strings than structure
+        let mut buf = vec![0; 64 * 1024];
+        let size = write(&mut buf, 200).unwrap();
+        let fdt = Fdt::new(&buf[..size]).unwrap();
+        assert!(fdt.strings.len() > 6000);
+        assert_eq!(
+            fdt.reservations().collect::<Vec<_>>(),
+            [(0x4000_0000, 0x1_0000)]
+        );
+        assert_eq!(fdt.root().property_u32("#address-cells"), Some(1));
+        for i in [0, 1, 199] {
+            let node = fdt.find(&format!("/node@{i:x}")).unwrap();
+            let name = format!("vendor,property-with-a-long-name-{i}");
+            assert_eq!(node.property_u32(&name), Some(i as u32));
+            assert_eq!(node.property_str("status"), Some("okay"));
+        }
+    }
+
+    #[test]
+    fn fills_the_buffer() {
+        let mut buf = vec![0; 64 * 1024];
+        let size = write(&mut buf, 50).unwrap();
+        let expected = buf[..size].to_vec();
+
+        // Structure and strings meet exactly
+        let mut buf = vec![0; size];
+        assert_eq!(write(&mut buf, 50), Ok(size));
+        assert_eq!(buf, expected);
+        let mut buf = vec![0; size - 1];
+        assert_eq!(write(&mut buf, 50), Err(FdtError::NoSpace));
+    }
+}
@@ -0,0 +1,135 @@
+//! OpenSBI `fw_dynamic_info` handoff from an earlier boot stage.
+//!
+//! A stage that loaded the next one already, such as U-Boot SPL or a debugger script,
+//! enters the firmware with `a0` hart ID, `a1` device tree and `a2` pointing at
+//! `fw_dynamic_info`, as it would enter OpenSBI's `fw_dynamic`. The firmware then boots
+//! what the structure describes instead of loading images from flash.
+//!
+//! The runtime's startup code does not keep argument registers, so the ELF entry is
+//! [`_rustsbi_entry`], which parks `a1` and `a2` in `sscratch` and `mscratch` before
+//! jumping to `_start`. Nothing else touches either until [`take`] reads them back.
+//! Booting from flash through the boot ROM enters at `_start` directly, leaving both
+//! registers with whatever the ROM or a previous run left there. So the entry also
+//! writes a tag derived from both into `mtval`, and [`take`] ignores the registers
+//! unless the tag matches, clearing all three either way.
+
+use core::arch::asm;
+
+use crate::next_stage::Mode;
+
+/// `"OSBI"`.
+const MAGIC: usize = 0x4942_534f;
+/// Newest layout understood, the one adding `boot_hart`.
+const VERSION_MAX: usize = 2;
+
+/// `next_mode` values; U-mode payloads are not supported.
+const MODE_S: usize = 1;
+const MODE_M: usize = 3;
+
+/// Mixed with `a1` and `a2` into the entry tag.
+const ENTRY_TAG: usize = 0x5253_4249;
+
+/// Skip the boot banner.
+pub const OPTION_NO_BOOT_PRINTS: usize = 1 << 0;
+
+/// Where an earlier stage may have placed the structure: ILM, DLM, AXI SRAM and SDRAM.
+const RAM: [(usize, usize); 4] = [
+    (0x0000_0000, 0x2_0000),
+    (0x0008_0000, 0x2_0000),
+    (0x0108_0000, 0x4_0000),
+    (0x4000_0000, 0x4000_0000),
+];
+
+/// Whether `[start, start + len)` lies in RAM an earlier stage may have written to.
+pub fn in_ram(start: usize, len: usize) -> bool {
+    start.checked_add(len).is_some_and(|end| {
+        RAM.iter()
+            .any(|&(base, size)| start >= base && end <= base + size)
+    })
+}
+
+#[repr(C)]
+struct FwDynamicInfo {
+    magic: usize,
+    version: usize,
+    next_addr: usize,
+    next_mode: usize,
+    options: usize,
+    /// Only in version 2 and later.
+    boot_hart: usize,
+}
+
+/// What the earlier stage asked for.
+#[derive(Debug, Clone, Copy)]
+pub struct Handoff {
+    pub version: usize,
+    pub next_addr: usize,
+    pub next_mode: Mode,
+    pub options: usize,
+    /// Hart to boot the next stage on, `None` for any.
+    pub boot_hart: Option<usize>,
+    /// Device tree passed in `a1`, zero if none.
+    pub dtb: usize,
+}
+
+/// Entry point of the ELF image: keep `a1` and `a2` for [`take`], then start as usual.
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn _rustsbi_entry() -> ! {
+    asm!(
+        "csrw sscratch, a1",
+        "csrw mscratch, a2",
+        "li   t0, {tag}",
+        "xor  t0, t0, a1",
+        "xor  t0, t0, a2",
+        "csrw mtval, t0",
+        "j {start}",
+        tag = const ENTRY_TAG,
+        start = sym crate::_start,
+        options(noreturn)
+    )
+}
+
+/// The handoff from an earlier stage, if it left a valid `fw_dynamic_info`.
+///
+/// Must run before anything else uses `mscratch`. A structure asking for a U-mode payload
+/// is not taken.
+pub fn take() -> Option<Handoff> {
+    let info: usize;
+    let dtb: usize;
+    let tag: usize;
+    unsafe {
+        asm!("csrrw {}, mscratch, zero", out(reg) info);
+        asm!("csrrw {}, sscratch, zero", out(reg) dtb);
+        asm!("csrrw {}, mtval, zero", out(reg) tag);
+    }
+    // Not entered through `_rustsbi_entry`, the registers hold nothing of ours
+    if tag != ENTRY_TAG ^ info ^ dtb {
+        return None;
+    }
+    let valid = info != 0
+        && info % core::mem::align_of::<FwDynamicInfo>() == 0
+        && in_ram(info, core::mem::size_of::<FwDynamicInfo>());
+    if !valid {
+        return None;
+    }
+    let info = unsafe { &*(info as *const FwDynamicInfo) };
+    if info.magic != MAGIC || info.version > VERSION_MAX {
+        return None;
+    }
+    let next_mode = match info.next_mode {
+        MODE_S => Mode::Supervisor,
+        MODE_M => Mode::Machine,
+        _ => return None,
+    };
+    // Version 2 may use -1 for any hart
+    let boot_hart = (info.version >= 2 && info.boot_hart != usize::MAX).then_some(info.boot_hart);
+    Some(Handoff {
+        version: info.version,
+        next_addr: info.next_addr,
+        next_mode,
+        options: info.options,
+        boot_hart,
+        dtb,
+    })
+}
@@ -0,0 +1,746 @@
+//! Decompression of kernel blobs stored in flash.
+//!
+//! Everything here works on plain slices and does not touch hardware, so it can be
+//! exercised on the host against images produced by `gzip` and `lz4`.
+
+use crate::crc32;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const LZ4_FRAME_MAGIC: u32 = 0x184D_2204;
+const LZ4_LEGACY_MAGIC: u32 = 0x184C_2102;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Stored as is.
+    None,
+    Gzip,
+    /// LZ4 frame format (`lz4`).
+    Lz4,
+    /// LZ4 legacy format (`lz4 -l`), as produced by the kernel build for `Image.lz4`.
+    Lz4Legacy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// Input ended in the middle of the stream.
+    Truncated,
+    /// Decompressed data does not fit into the destination.
+    OutputOverflow,
+    /// Malformed or unsupported stream header.
+    BadHeader,
+    /// Malformed compressed data.
+    BadData,
+    /// Checksum or length recorded in the stream does not match the output.
+    ChecksumMismatch,
+}
+
+type Result<T> = core::result::Result<T, DecompressError>;
+
+impl Compression {
+    pub fn detect(src: &[u8]) -> Self {
+        if src.starts_with(&GZIP_MAGIC) {
+            return Compression::Gzip;
+        }
+        match read_u32(src, 0) {
+            Ok(LZ4_FRAME_MAGIC) => Compression::Lz4,
+            Ok(LZ4_LEGACY_MAGIC) => Compression::Lz4Legacy,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Decompress `src` into `dst`, returning the number of bytes written.
+///
+/// Uncompressed input is copied verbatim.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    match Compression::detect(src) {
+        Compression::None => {
+            dst.get_mut(..src.len())
+                .ok_or(DecompressError::OutputOverflow)?
+                .copy_from_slice(src);
+            Ok(src.len())
+        }
+        Compression::Gzip => gunzip(src, dst),
+        Compression::Lz4 => lz4_frame(src, dst),
+        Compression::Lz4Legacy => lz4_legacy(src, dst),
+    }
+}
+
+#[inline]
+fn read_u32(src: &[u8], pos: usize) -> Result<u32> {
+    let bytes = src.get(pos..pos + 4).ok_or(DecompressError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/* ---------------------------------- gzip ---------------------------------- */
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+fn gunzip(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    // Only deflate is defined, and the top three flag bits are reserved
+    if src.len() < 18 || src[2] != 8 || src[3] & 0xE0 != 0 {
+        return Err(DecompressError::BadHeader);
+    }
+    let flags = src[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = src.get(pos..pos + 2).ok_or(DecompressError::Truncated)?;
+        pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = src.get(pos..).ok_or(DecompressError::Truncated)?;
+            let nul = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(DecompressError::Truncated)?;
+            pos += nul + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let body = src.get(pos..).ok_or(DecompressError::Truncated)?;
+    let (len, consumed) = inflate(body, dst)?;
+
+    let crc = read_u32(body, consumed)?;
+    let isize = read_u32(body, consumed + 4)?;
+    if crc != crc32::checksum(&dst[..len]) || isize != len as u32 {
+        return Err(DecompressError::ChecksumMismatch);
+    }
+    Ok(len)
+}
+
+struct BitReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+    buf: u32,
+    cnt: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(src: &'a [u8]) -> Self {
+        Self {
+            src,
+            pos: 0,
+            buf: 0,
+            cnt: 0,
+        }
+    }
+
+    #[inline]
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.cnt < n {
+            let b = *self.src.get(self.pos).ok_or(DecompressError::Truncated)?;
+            self.pos += 1;
+            self.buf |= (b as u32) << self.cnt;
+            self.cnt += 8;
+        }
+        let v = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.cnt -= n;
+        Ok(v)
+    }
+
+    /// Drop the rest of the current byte. Never more than 7 bits are buffered between calls.
+    #[inline]
+    fn align(&mut self) {
+        self.buf = 0;
+        self.cnt = 0;
+    }
+}
+
+const MAX_BITS: usize = 15;
+
+/// Canonical Huffman decoding table, in the style of zlib's `puff`.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; 288],
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut h = Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; 288],
+        };
+        for &len in lengths {
+            h.counts[len as usize] += 1;
+        }
+        h.counts[0] = 0;
+
+        // Reject over-subscribed codes, incomplete ones are legal
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - h.counts[len] as i32;
+            if left < 0 {
+                return Err(DecompressError::BadData);
+            }
+        }
+
+        let mut offs = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offs[len + 1] = offs[len] + h.counts[len];
+        }
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                h.symbols[offs[len as usize] as usize] = sym as u16;
+                offs[len as usize] += 1;
+            }
+        }
+        Ok(h)
+    }
+
+    fn decode(&self, br: &mut BitReader) -> Result<usize> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= br.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecompressError::BadData)
+    }
+}
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Inflate a raw deflate stream, returning `(bytes written, bytes consumed)`.
+fn inflate(src: &[u8], dst: &mut [u8]) -> Result<(usize, usize)> {
+    let mut br = BitReader::new(src);
+    let mut out = 0;
+
+    loop {
+        let last = br.bits(1)? != 0;
+        match br.bits(2)? {
+            0 => {
+                br.align();
+                let header = src
+                    .get(br.pos..br.pos + 4)
+                    .ok_or(DecompressError::Truncated)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(DecompressError::BadData);
+                }
+                let start = br.pos + 4;
+                let data = src
+                    .get(start..start + len as usize)
+                    .ok_or(DecompressError::Truncated)?;
+                dst.get_mut(out..out + data.len())
+                    .ok_or(DecompressError::OutputOverflow)?
+                    .copy_from_slice(data);
+                out += data.len();
+                br.pos = start + data.len();
+            }
+            1 => {
+                let mut lengths = [0u8; 288 + 30];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..288].fill(8);
+                lengths[288..].fill(5);
+                let lit = Huffman::new(&lengths[..288])?;
+                let dist = Huffman::new(&lengths[288..])?;
+                inflate_codes(&mut br, dst, &mut out, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut br)?;
+                inflate_codes(&mut br, dst, &mut out, &lit, &dist)?;
+            }
+            _ => return Err(DecompressError::BadData),
+        }
+        if last {
+            return Ok((out, br.pos));
+        }
+    }
+}
+
+fn dynamic_tables(br: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let hlit = br.bits(5)? as usize + 257;
+    let hdist = br.bits(5)? as usize + 1;
+    let hclen = br.bits(4)? as usize + 4;
+    if hlit > 286 || hdist > 30 {
+        return Err(DecompressError::BadData);
+    }
+
+    let mut lengths = [0u8; 288 + 30];
+    for &i in &CODE_LENGTH_ORDER[..hclen] {
+        lengths[i] = br.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths[..19])?;
+
+    lengths[..19].fill(0);
+    let mut i = 0;
+    while i < hlit + hdist {
+        let sym = code_lengths.decode(br)?;
+        let (value, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths
+                    .get(i.wrapping_sub(1))
+                    .ok_or(DecompressError::BadData)?;
+                (prev, 3 + br.bits(2)? as usize)
+            }
+            17 => (0, 3 + br.bits(3)? as usize),
+            _ => (0, 11 + br.bits(7)? as usize),
+        };
+        if i + repeat > hlit + hdist {
+            return Err(DecompressError::BadData);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    // A block without an end-of-block code can never terminate
+    if lengths[256] == 0 {
+        return Err(DecompressError::BadData);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..hlit])?,
+        Huffman::new(&lengths[hlit..hlit + hdist])?,
+    ))
+}
+
+fn inflate_codes(
+    br: &mut BitReader,
+    dst: &mut [u8],
+    out: &mut usize,
+    lit: &Huffman,
+    dist: &Huffman,
+) -> Result<()> {
+    loop {
+        let sym = lit.decode(br)?;
+        match sym {
+            0..=255 => {
+                *dst.get_mut(*out).ok_or(DecompressError::OutputOverflow)? = sym as u8;
+                *out += 1;
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let i = sym - 257;
+                let len = LEN_BASE[i] as usize + br.bits(LEN_EXTRA[i] as u32)? as usize;
+                let d = dist.decode(br)?;
+                if d >= DIST_BASE.len() {
+                    return Err(DecompressError::BadData);
+                }
+                let distance = DIST_BASE[d] as usize + br.bits(DIST_EXTRA[d] as u32)? as usize;
+                copy_match(dst, *out, distance, len)?;
+                *out += len;
+            }
+            _ => return Err(DecompressError::BadData),
+        }
+    }
+}
+
+/// Copy a back reference within `dst`. Source and destination may overlap.
+#[inline]
+fn copy_match(dst: &mut [u8], out: usize, distance: usize, len: usize) -> Result<()> {
+    if distance == 0 || distance > out {
+        return Err(DecompressError::BadData);
+    }
+    if out + len > dst.len() {
+        return Err(DecompressError::OutputOverflow);
+    }
+    for i in out..out + len {
+        dst[i] = dst[i - distance];
+    }
+    Ok(())
+}
+
+/* ----------------------------------- LZ4 ---------------------------------- */
+
+const LZ4_FLG_VERSION: u8 = 0b11 << 6;
+const LZ4_FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const LZ4_FLG_CONTENT_SIZE: u8 = 1 << 3;
+const LZ4_FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const LZ4_FLG_DICT_ID: u8 = 1 << 0;
+const LZ4_BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+fn lz4_frame(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let flg = *src.get(4).ok_or(DecompressError::Truncated)?;
+    if flg & LZ4_FLG_VERSION != 1 << 6 || flg & LZ4_FLG_DICT_ID != 0 {
+        return Err(DecompressError::BadHeader);
+    }
+    let descriptor_len = 2 + if flg & LZ4_FLG_CONTENT_SIZE != 0 {
+        8
+    } else {
+        0
+    };
+    let descriptor = src
+        .get(4..4 + descriptor_len)
+        .ok_or(DecompressError::Truncated)?;
+    let hc = *src
+        .get(4 + descriptor_len)
+        .ok_or(DecompressError::Truncated)?;
+    if (xxh32(descriptor, 0) >> 8) as u8 != hc {
+        return Err(DecompressError::BadHeader);
+    }
+
+    let mut pos = 4 + descriptor_len + 1;
+    let mut out = 0;
+    loop {
+        let size = read_u32(src, pos)?;
+        pos += 4;
+        if size == 0 {
+            break;
+        }
+        let len = (size & !LZ4_BLOCK_UNCOMPRESSED) as usize;
+        let block = block(src, pos, len).ok_or(DecompressError::Truncated)?;
+        pos += len;
+        if flg & LZ4_FLG_BLOCK_CHECKSUM != 0 {
+            if read_u32(src, pos)? != xxh32(block, 0) {
+                return Err(DecompressError::ChecksumMismatch);
+            }
+            pos += 4;
+        }
+        if size & LZ4_BLOCK_UNCOMPRESSED != 0 {
+            dst.get_mut(out..out + len)
+                .ok_or(DecompressError::OutputOverflow)?
+                .copy_from_slice(block);
+            out += len;
+        } else {
+            out = lz4_block(block, dst, out)?;
+        }
+    }
+
+    if flg & LZ4_FLG_CONTENT_CHECKSUM != 0 && read_u32(src, pos)? != xxh32(&dst[..out], 0) {
+        return Err(DecompressError::ChecksumMismatch);
+    }
+    if flg & LZ4_FLG_CONTENT_SIZE != 0 {
+        let size = u64::from_le_bytes(descriptor[2..10].try_into().unwrap());
+        if size != out as u64 {
+            return Err(DecompressError::ChecksumMismatch);
+        }
+    }
+    Ok(out)
+}
+
+fn lz4_legacy(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+    let mut pos = 4;
+    let mut out = 0;
+    // The legacy format has no end mark: the stream ends with the input, which for a
+    // flash slot means at the first size that cannot be a block (e.g. erased 0xFFFFFFFF).
+    while let Ok(size) = read_u32(src, pos) {
+        pos += 4;
+        if size == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+        let Some(block) = block(src, pos, size as usize).filter(|_| size != 0) else {
+            break;
+        };
+        out = lz4_block(block, dst, out)?;
+        pos += size as usize;
+    }
+    Ok(out)
+}
+
+/// The `len` bytes at `pos`, if `src` has them. Sizes come from the stream, so the end
+/// may not even be addressable.
+fn block(src: &[u8], pos: usize, len: usize) -> Option<&[u8]> {
+    src.get(pos..pos.checked_add(len)?)
+}
+
+/// Decode one LZ4 block into `dst` starting at `out`, returning the new output position.
+///
+/// Matches may reach back into previously decoded blocks, which covers linked blocks.
+fn lz4_block(src: &[u8], dst: &mut [u8], mut out: usize) -> Result<usize> {
+    let mut pos = 0;
+    loop {
+        let token = *src.get(pos).ok_or(DecompressError::Truncated)?;
+        pos += 1;
+
+        let literals = lz4_length(src, &mut pos, (token >> 4) as usize)?;
+        let data = src
+            .get(pos..pos + literals)
+            .ok_or(DecompressError::Truncated)?;
+        dst.get_mut(out..out + literals)
+            .ok_or(DecompressError::OutputOverflow)?
+            .copy_from_slice(data);
+        pos += literals;
+        out += literals;
+
+        // The last sequence carries literals only
+        if pos == src.len() {
+            return Ok(out);
+        }
+
+        let offset = src.get(pos..pos + 2).ok_or(DecompressError::Truncated)?;
+        let distance = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        let len = lz4_length(src, &mut pos, (token & 0x0F) as usize)? + 4;
+        copy_match(dst, out, distance, len)?;
+        out += len;
+    }
+}
+
+#[inline]
+fn lz4_length(src: &[u8], pos: &mut usize, mut len: usize) -> Result<usize> {
+    if len == 15 {
+        loop {
+            let b = *src.get(*pos).ok_or(DecompressError::Truncated)?;
+            *pos += 1;
+            len += b as usize;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+const XXH_P1: u32 = 2_654_435_761;
+const XXH_P2: u32 = 2_246_822_519;
+const XXH_P3: u32 = 3_266_489_917;
+const XXH_P4: u32 = 668_265_263;
+const XXH_P5: u32 = 374_761_393;
+
+/// xxHash32, used by LZ4 frames for header, block and content checksums.
+fn xxh32(data: &[u8], seed: u32) -> u32 {
+    #[inline]
+    fn round(acc: u32, lane: &[u8]) -> u32 {
+        let input = u32::from_le_bytes(lane.try_into().unwrap());
+        acc.wrapping_add(input.wrapping_mul(XXH_P2))
+            .rotate_left(13)
+            .wrapping_mul(XXH_P1)
+    }
+
+    let mut chunks = data.chunks_exact(16);
+    let mut h = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(XXH_P1).wrapping_add(XXH_P2),
+            seed.wrapping_add(XXH_P2),
+            seed,
+            seed.wrapping_sub(XXH_P1),
+        ];
+        for chunk in &mut chunks {
+            for (i, lane) in chunk.chunks_exact(4).enumerate() {
+                v[i] = round(v[i], lane);
+            }
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(XXH_P5)
+    };
+    h = h.wrapping_add(data.len() as u32);
+
+    let mut words = chunks.remainder().chunks_exact(4);
+    for word in &mut words {
+        let input = u32::from_le_bytes(word.try_into().unwrap());
+        h = h
+            .wrapping_add(input.wrapping_mul(XXH_P3))
+            .rotate_left(17)
+            .wrapping_mul(XXH_P4);
+    }
+    for &b in words.remainder() {
+        h = h
+            .wrapping_add((b as u32).wrapping_mul(XXH_P5))
+            .rotate_left(11)
+            .wrapping_mul(XXH_P1);
+    }
+
+    h ^= h >> 15;
+    h = h.wrapping_mul(XXH_P2);
+    h ^= h >> 13;
+    h = h.wrapping_mul(XXH_P3);
+    h ^ (h >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `src/kernel.bin`, compressed by the tools the way kernel builds do.
+    const IMAGE: &[u8] = include_bytes!("../../src/kernel.bin");
+    /// `gzip -9 -n`
+    const IMAGE_GZ: &[u8] = include_bytes!("../../host-test/fixtures/Image.gz");
+    /// `lz4 -9`
+    const IMAGE_LZ4: &[u8] = include_bytes!("../../host-test/fixtures/Image.lz4");
+    /// `lz4 -l -9`, as `make Image.lz4`
+    const IMAGE_LZ4_LEGACY: &[u8] = include_bytes!("../../host-test/fixtures/Image.lz4-legacy");
+    /// `lz4 -BX --content-size`, with block checksums and the size in the header
+    const IMAGE_LZ4_CHECKSUMS: &[u8] =
+        include_bytes!("../../host-test/fixtures/Image.lz4-checksums");
+    /// [`large_image`] by `gzip -9 -n`
+    const LARGE_GZ: &[u8] = include_bytes!("../../host-test/fixtures/Large.gz");
+    /// [`large_image`] by `lz4 -9 -B4 -BD -BX --content-size`: 64 KB blocks, matches
+    /// reaching into the previous ones
+    const LARGE_LZ4: &[u8] = include_bytes!("../../host-test/fixtures/Large.lz4");
+
+    /// 2 MB resembling a kernel image: text, instruction-like words, runs of zeros and spans
+    /// repeating earlier ones, so deflate switches between dynamic Huffman tables and
+    /// matches reach back across LZ4 blocks.
+    fn large_image() -> Vec<u8> {
+        const LEN: usize = 2 << 20;
+        const WORDS: [&str; 16] = [
+            "kernel", "memory", "sbi", "console", "riscv", "timer", "hart", "irq", "device",
+            "driver", "probe", "init", "page", "cache", "flush", "fault",
+        ];
+        let mut state = 0x2545_F491_u32;
+        let mut next = move |n: u32| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state % n
+        };
+        let mut image = Vec::with_capacity(LEN);
+        while image.len() < LEN {
+            match next(16) {
+                0..=9 if image.len() > 32 * 1024 => {
+                    let len = 16 + next(1024) as usize;
+                    let start = image.len() - 32 * 1024 + next(32 * 1024 - 1024) as usize;
+                    image.extend_from_within(start..start + len);
+                }
+                0..=11 => {
+                    for _ in 0..next(32) {
+                        image.extend(WORDS[next(16) as usize].as_bytes());
+                        image.push(if next(8) == 0 { b'\n' } else { b' ' });
+                    }
+                }
+                12 | 13 => {
+                    // Instructions: few opcodes and registers
+                    for _ in 0..next(64) {
+                        let inst = [0x13, 0x33, 0x03, 0x23][next(4) as usize] | next(8) << 7;
+                        image.extend(inst.to_le_bytes());
+                    }
+                }
+                14 => image.extend((0..next(8)).map(|_| next(256) as u8)),
+                _ => image.resize(image.len() + next(256) as usize, 0),
+            }
+        }
+        image.truncate(LEN);
+        image
+    }
+
+    #[test]
+    fn large_images() {
+        let image = large_image();
+        let mut dst = vec![0; image.len()];
+        for src in [LARGE_GZ, LARGE_LZ4] {
+            dst.fill(0);
+            assert_eq!(decompress(src, &mut dst), Ok(image.len()));
+            assert!(dst == image);
+        }
+        dst.pop();
+        for src in [LARGE_GZ, LARGE_LZ4] {
+            assert_eq!(
+                decompress(src, &mut dst),
+                Err(DecompressError::OutputOverflow)
+            );
+        }
+    }
+
+    fn decompressed(src: &[u8]) -> Result<Vec<u8>> {
+        let mut dst = vec![0; IMAGE.len() * 2];
+        let len = decompress(src, &mut dst)?;
+        dst.truncate(len);
+        Ok(dst)
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Compression::detect(IMAGE), Compression::None);
+        assert_eq!(Compression::detect(IMAGE_GZ), Compression::Gzip);
+        assert_eq!(Compression::detect(IMAGE_LZ4), Compression::Lz4);
+        assert_eq!(
+            Compression::detect(IMAGE_LZ4_LEGACY),
+            Compression::Lz4Legacy
+        );
+        assert_eq!(Compression::detect(&[0x1F]), Compression::None);
+    }
+
+    #[test]
+    fn images() {
+        for src in [
+            IMAGE,
+            IMAGE_GZ,
+            IMAGE_LZ4,
+            IMAGE_LZ4_LEGACY,
+            IMAGE_LZ4_CHECKSUMS,
+        ] {
+            assert_eq!(decompressed(src).unwrap(), IMAGE);
+        }
+    }
+
+    #[test]
+    fn legacy_in_erased_flash() {
+        // A slot holds the stream followed by erased flash, read as a huge block size
+        let mut slot = IMAGE_LZ4_LEGACY.to_vec();
+        slot.resize(slot.len() + 4096, 0xFF);
+        assert_eq!(decompressed(&slot).unwrap(), IMAGE);
+        slot.truncate(IMAGE_LZ4_LEGACY.len());
+        slot.extend_from_slice(&[0; 4]);
+        assert_eq!(decompressed(&slot).unwrap(), IMAGE);
+    }
+
+    #[test]
+    fn truncated() {
+        for src in [IMAGE_GZ, IMAGE_LZ4, IMAGE_LZ4_CHECKSUMS] {
+            for len in [src.len() / 2, src.len() - 1] {
+                assert!(decompressed(&src[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut gz = IMAGE_GZ.to_vec();
+        // The CRC-32 of the gzip trailer
+        let crc = gz.len() - 8;
+        gz[crc] ^= 1;
+        assert_eq!(decompressed(&gz), Err(DecompressError::ChecksumMismatch));
+
+        let mut lz4 = IMAGE_LZ4_CHECKSUMS.to_vec();
+        let last = lz4.len() - 1;
+        lz4[last] ^= 1;
+        assert_eq!(decompressed(&lz4), Err(DecompressError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn output_overflow() {
+        let mut dst = vec![0; IMAGE.len() - 1];
+        for src in [IMAGE, IMAGE_GZ, IMAGE_LZ4, IMAGE_LZ4_LEGACY] {
+            assert_eq!(
+                decompress(src, &mut dst),
+                Err(DecompressError::OutputOverflow)
+            );
+        }
+    }
+
+    #[test]
+    fn xxh32_reference() {
+        // From the xxHash test vectors
+        assert_eq!(xxh32(b"", 0), 0x02CC_5D05);
+        assert_eq!(xxh32(b"a", 0), 0x550D_7456);
+        assert_eq!(xxh32(b"abc", 0), 0x32D1_53FF);
+    }
+}
@@ -0,0 +1,194 @@
+//! Just enough ELF32 to run a statically linked payload in place.
+//!
+//! Segments linked at their own location in flash execute from there. The others, data
+//! and bss, are copied out of the file to where they are linked, and zero-filled up to
+//! their size in memory.
+
+use core::fmt;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LSB: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not a 32-bit little-endian RISC-V file.
+    NotSupported,
+    /// A header or segment lies outside the file.
+    Truncated,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ElfError::NotSupported => "not a 32-bit RISC-V ELF file",
+            ElfError::Truncated => "truncated ELF file",
+        })
+    }
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: usize,
+    /// The program header table.
+    headers: &'a [u8],
+    /// Offset of the end of the program header table.
+    headers_end: usize,
+}
+
+/// A loadable segment.
+pub struct Segment<'a> {
+    /// Where the segment is linked.
+    pub address: usize,
+    /// Contents from the file, at most `memsz` long.
+    pub data: &'a [u8],
+    /// Size in memory; what is past `data` is zeroed.
+    pub memsz: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if !is_elf(data)
+            || data[4] != CLASS_32
+            || data[5] != DATA_LSB
+            || half(data, 18) != MACHINE_RISCV
+        {
+            return Err(ElfError::NotSupported);
+        }
+        if half(data, 42) as usize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::NotSupported);
+        }
+        // Offsets come from flash nobody vouches for, so nothing here may wrap
+        let phoff = word(data, 28) as usize;
+        let phnum = half(data, 44) as usize;
+        let headers_end = (phnum * PROGRAM_HEADER_SIZE)
+            .checked_add(phoff)
+            .ok_or(ElfError::Truncated)?;
+        let headers = data.get(phoff..headers_end).ok_or(ElfError::Truncated)?;
+        // Check every segment here, so iterating over them cannot fail
+        for ph in headers.chunks_exact(PROGRAM_HEADER_SIZE) {
+            let (offset, filesz) = (word(ph, 4) as usize, word(ph, 16) as usize);
+            let end = offset.checked_add(filesz).ok_or(ElfError::Truncated)?;
+            if word(ph, 0) == PT_LOAD && end > data.len() {
+                return Err(ElfError::Truncated);
+            }
+        }
+        Ok(Self {
+            data,
+            entry: word(data, 24) as usize,
+            headers,
+            headers_end,
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        self.headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .filter_map(|ph| {
+                if word(ph, 0) != PT_LOAD {
+                    return None;
+                }
+                let offset = word(ph, 4) as usize;
+                let filesz = word(ph, 16) as usize;
+                let memsz = word(ph, 20) as usize;
+                Some(Segment {
+                    address: word(ph, 8) as usize,
+                    data: &self.data[offset..offset + filesz.min(memsz)],
+                    memsz,
+                })
+            })
+    }
+
+    /// Length of the file up to the end of the last segment.
+    pub fn size(&self) -> usize {
+        self.segments()
+            .map(|segment| segment.data.as_ptr() as usize + segment.data.len())
+            .map(|end| end - self.data.as_ptr() as usize)
+            .chain([self.headers_end])
+            .max()
+            .unwrap()
+    }
+}
+
+fn half(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn word(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header for RV32 with `phnum` program headers at `phoff`, followed by
+    /// `phnum` PT_LOAD headers of one byte each at the end of the file.
+    fn file(phoff: u32, phnum: u16) -> [u8; 256] {
+        let mut data = [0; 256];
+        data[..4].copy_from_slice(MAGIC);
+        data[4] = CLASS_32;
+        data[5] = DATA_LSB;
+        data[18..20].copy_from_slice(&MACHINE_RISCV.to_le_bytes());
+        data[24..28].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        data[28..32].copy_from_slice(&phoff.to_le_bytes());
+        data[42..44].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        data[44..46].copy_from_slice(&phnum.to_le_bytes());
+        for i in 0..phnum as usize {
+            let start = phoff as usize + i * PROGRAM_HEADER_SIZE;
+            let Some(ph) = data.get_mut(start..start + PROGRAM_HEADER_SIZE) else {
+                break;
+            };
+            ph[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[4..8].copy_from_slice(&255u32.to_le_bytes());
+            ph[16..20].copy_from_slice(&1u32.to_le_bytes());
+            ph[20..24].copy_from_slice(&1u32.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parses_segments() {
+        let data = file(HEADER_SIZE as u32, 2);
+        let elf = Elf::new(&data).unwrap();
+        assert_eq!(elf.entry, 0x8000_0000);
+        assert_eq!(elf.segments().count(), 2);
+        assert_eq!(elf.size(), 256);
+    }
+
+    #[test]
+    fn rejects_program_headers_out_of_the_file() {
+        for phoff in [0xFFFF_FFF0, u32::MAX, 250] {
+            let data = file(phoff, 2);
+            assert_eq!(
+                Elf::new(&data).err(),
+                Some(ElfError::Truncated),
+                "{phoff:#x}"
+            );
+        }
+        let data = file(HEADER_SIZE as u32, u16::MAX);
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::Truncated));
+    }
+
+    #[test]
+    fn rejects_segments_out_of_the_file() {
+        let mut data = file(HEADER_SIZE as u32, 1);
+        let ph = HEADER_SIZE;
+        data[ph + 4..ph + 8].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        data[ph + 16..ph + 20].copy_from_slice(&0x20u32.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::Truncated));
+        assert_eq!(Elf::new(&data[..40]).err(), Some(ElfError::Truncated));
+    }
+}
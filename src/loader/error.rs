@@ -0,0 +1,38 @@
+//! Why loading the next stage failed.
+
+use super::decompress::DecompressError;
+use super::elf::ElfError;
+use super::fit::FitError;
+use crate::fdt::FdtError;
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The blob could not be decompressed into its load region.
+    Decompress(DecompressError),
+    /// The device tree blob is invalid, or outgrew its load region.
+    Dtb(FdtError),
+    /// The load region overlaps the named region.
+    Overlap(&'static str),
+    /// The FIT image is invalid or fails verification.
+    Fit(FitError<'static>),
+    /// The ELF kernel is invalid.
+    Elf(ElfError),
+    /// The kernel cannot execute in place, for the given reason.
+    Xip(&'static str),
+    /// The slot holds nothing recognisable as a kernel.
+    NoKernel,
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LoadError::Decompress(e) => write!(f, "decompression failed: {e:?}"),
+            LoadError::Dtb(e) => write!(f, "bad device tree: {e:?}"),
+            LoadError::Overlap(what) => write!(f, "load region overlaps {what}"),
+            LoadError::Fit(e) => write!(f, "bad FIT image: {e}"),
+            LoadError::Elf(e) => write!(f, "bad ELF kernel: {e}"),
+            LoadError::Xip(why) => write!(f, "cannot execute in place: {why}"),
+            LoadError::NoKernel => write!(f, "no kernel image"),
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! U-Boot Flattened Image Tree (FIT) parsing.
+//!
+//! Only looks at the image; placing sub-images in memory is up to the loader. Works on
+//! plain slices, so it can be checked on the host against `mkimage` output.
+
+use super::decompress::Compression;
+use crate::fdt::{Fdt, FdtError, Node};
+use crate::{crc32, sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitError<'a> {
+    /// The image is not a valid FDT.
+    Fdt(FdtError),
+    /// No `/configurations` node, or no configuration with the requested name.
+    NoConfiguration(&'a str),
+    /// A configuration refers to an image that does not exist.
+    NoImage(&'a str),
+    /// The image has no `data`, or external data lies outside the storage.
+    NoData(&'a str),
+    /// The image carries a hash with an algorithm we cannot check.
+    UnsupportedHash(&'a str),
+    /// The image data does not match its hash.
+    BadHash(&'a str),
+    /// The image is compressed with an algorithm we cannot unpack.
+    UnsupportedCompression(&'a str),
+    /// The `compression` property does not describe the image data.
+    CompressionMismatch(&'a str),
+}
+
+type Result<'a, T> = core::result::Result<T, FitError<'a>>;
+
+impl core::fmt::Display for FitError<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FitError::Fdt(e) => write!(f, "{e:?}"),
+            FitError::NoConfiguration(name) => write!(f, "no configuration {name:?}"),
+            FitError::NoImage(name) => write!(f, "no image {name:?}"),
+            FitError::NoData(name) => write!(f, "no data for image {name:?}"),
+            FitError::UnsupportedHash(name) => write!(f, "unsupported hash in image {name:?}"),
+            FitError::BadHash(name) => write!(f, "hash mismatch in image {name:?}"),
+            FitError::UnsupportedCompression(name) => {
+                write!(f, "unsupported compression in image {name:?}")
+            }
+            FitError::CompressionMismatch(name) => {
+                write!(f, "compression does not match the data of image {name:?}")
+            }
+        }
+    }
+}
+
+pub struct Fit<'a> {
+    fdt: Fdt<'a>,
+    /// The whole storage, which also holds external image data after the FDT.
+    storage: &'a [u8],
+}
+
+/// A sub-image, such as `/images/kernel-1`.
+pub struct FitImage<'a> {
+    pub data: &'a [u8],
+    /// From the `compression` property, checked against the data.
+    pub compression: Compression,
+    pub load: Option<u64>,
+    pub entry: Option<u64>,
+}
+
+/// A configuration and the images it selects, with their hashes verified.
+pub struct FitConfig<'a> {
+    pub name: &'a str,
+    pub description: Option<&'a str>,
+    pub kernel: FitImage<'a>,
+    pub fdt: Option<FitImage<'a>>,
+    pub ramdisk: Option<FitImage<'a>>,
+}
+
+impl<'a> Fit<'a> {
+    /// `storage` starts with the FIT and may extend past it, for external data.
+    pub fn new(storage: &'a [u8]) -> Result<'a, Self> {
+        let fdt = Fdt::new(storage).map_err(FitError::Fdt)?;
+        Ok(Self { fdt, storage })
+    }
+
+    /// Select the configuration `name`, or the default one, and verify its images.
+    pub fn config(&self, name: Option<&'a str>) -> Result<'a, FitConfig<'a>> {
+        let configs = self
+            .fdt
+            .find("/configurations")
+            .ok_or(FitError::NoConfiguration("/configurations"))?;
+        let name = name
+            .or_else(|| configs.property_str("default"))
+            .ok_or(FitError::NoConfiguration("default"))?;
+        let config = configs
+            .children()
+            .find(|c| c.name() == name)
+            .ok_or(FitError::NoConfiguration(name))?;
+
+        let image = |property| {
+            config
+                .property_str(property)
+                // `kernel` and friends may be string lists; the first entry is the image
+                .map(|names| names.split('\0').next().unwrap())
+                .map(|name| self.image(name, property == "ramdisk"))
+                .transpose()
+        };
+        Ok(FitConfig {
+            name: config.name(),
+            description: config.property_str("description"),
+            kernel: image("kernel")?.ok_or(FitError::NoImage("kernel"))?,
+            fdt: image("fdt")?,
+            ramdisk: image("ramdisk")?,
+        })
+    }
+
+    /// Look up and verify an image. An `opaque` image may hold compressed data marked as
+    /// `"none"`, which is left for the kernel to unpack, like a gzipped initramfs.
+    fn image(&self, name: &'a str, opaque: bool) -> Result<'a, FitImage<'a>> {
+        let node = self
+            .fdt
+            .find("/images")
+            .and_then(|images| images.children().find(|c| c.name() == name))
+            .ok_or(FitError::NoImage(name))?;
+        let name = node.name();
+        let data = self.image_data(&node).ok_or(FitError::NoData(name))?;
+        verify(&node, data)?;
+
+        Ok(FitImage {
+            data,
+            compression: compression(&node, data, opaque)?,
+            load: node.property_u64("load"),
+            entry: node.property_u64("entry"),
+        })
+    }
+
+    /// Embedded `data`, or external data placed after the FDT by `mkimage -E`.
+    fn image_data(&self, node: &Node<'a>) -> Option<&'a [u8]> {
+        if let Some(data) = node.property("data") {
+            return Some(data);
+        }
+        let size = node.property_u32("data-size")? as usize;
+        let start = match node.property_u32("data-position") {
+            Some(position) => position as usize,
+            // Offsets count from the end of the FDT, rounded up to 4 bytes
+            None => {
+                let base = (self.fdt.total_size() + 3) & !3;
+                base.checked_add(node.property_u32("data-offset")? as usize)?
+            }
+        };
+        self.storage.get(start..start.checked_add(size)?)
+    }
+}
+
+/// The `compression` property of `image`, which has to agree with the magic of `data`.
+fn compression<'a>(image: &Node<'a>, data: &[u8], opaque: bool) -> Result<'a, Compression> {
+    let name = image.name();
+    let declared = match image.property_str("compression").unwrap_or("none") {
+        "none" => Compression::None,
+        "gzip" => Compression::Gzip,
+        "lz4" => Compression::Lz4,
+        _ => return Err(FitError::UnsupportedCompression(name)),
+    };
+    let detected = Compression::detect(data);
+    let matches = match declared {
+        Compression::None => detected == Compression::None || opaque,
+        // `mkimage` takes either LZ4 format
+        Compression::Lz4 => matches!(detected, Compression::Lz4 | Compression::Lz4Legacy),
+        _ => detected == declared,
+    };
+    if !matches {
+        return Err(FitError::CompressionMismatch(name));
+    }
+    Ok(declared)
+}
+
+/// Check `data` against every `hash*` subnode of the image.
+fn verify<'a>(image: &Node<'a>, data: &[u8]) -> Result<'a, ()> {
+    let name = image.name();
+    for hash in image.children().filter(|c| c.name().starts_with("hash")) {
+        let value = hash.property("value").ok_or(FitError::BadHash(name))?;
+        let matches = match hash.property_str("algo") {
+            Some("crc32") => value == crc32::checksum(data).to_be_bytes(),
+            Some("sha256") => value == sha256::digest(data),
+            _ => return Err(FitError::UnsupportedHash(name)),
+        };
+        if !matches {
+            return Err(FitError::BadHash(name));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `storage` starts with something that can be a FIT.
+pub fn is_fit(storage: &[u8]) -> bool {
+    storage.starts_with(&crate::fdt::FDT_MAGIC.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fdt::FdtWriter;
+
+    const IMAGE: &[u8] = include_bytes!("../kernel.bin");
+    const IMAGE_GZ: &[u8] = include_bytes!("../../host-test/fixtures/Image.gz");
+    const IMAGE_LZ4_LEGACY: &[u8] = include_bytes!("../../host-test/fixtures/Image.lz4-legacy");
+    const DTB: &[u8] = &[0xD0, 0x0D, 0xFE, 0xED, 0, 0, 0, 0x28];
+    const DTB_FIXTURE: &[u8] = include_bytes!("../../host-test/fixtures/hpm6360evk.dtb");
+    /// `host-test/fixtures/linux.its` laid out by `mkimage -f`, `-f -E` and `-f -E -p 0x1000`.
+    const ITB_FIXTURES: [&[u8]; 3] = [
+        include_bytes!("../../host-test/fixtures/linux.itb"),
+        include_bytes!("../../host-test/fixtures/linux-external.itb"),
+        include_bytes!("../../host-test/fixtures/linux-position.itb"),
+    ];
+    /// Where `mkimage -p` puts external data in these tests.
+    const POSITION: usize = 0x1000;
+
+    /// A sub-image as `mkimage -f` writes it from an image source file.
+    struct Sub<'a> {
+        name: &'a str,
+        /// Property naming the image in a configuration.
+        role: &'a str,
+        data: &'a [u8],
+        compression: &'a str,
+        hashes: &'a [&'a str],
+    }
+
+    impl<'a> Sub<'a> {
+        fn new(role: &'a str, data: &'a [u8], compression: &'a str) -> Self {
+            let name = match role {
+                "kernel" => "kernel-1",
+                "fdt" => "fdt-1",
+                _ => "ramdisk-1",
+            };
+            Self {
+                name,
+                role,
+                data,
+                compression,
+                hashes: &["crc32", "sha256"],
+            }
+        }
+    }
+
+    /// Where image data goes: in the tree, after it (`mkimage -E`), or at a fixed offset
+    /// (`mkimage -E -p`).
+    #[derive(Clone, Copy, PartialEq)]
+    enum Layout {
+        Embedded,
+        External,
+        Position,
+    }
+
+    const fn align4(n: usize) -> usize {
+        (n + 3) & !3
+    }
+
+    /// A FIT laid out like `mkimage` output, with `conf-1` holding every image and the
+    /// default, and `conf-2` holding the kernel alone.
+    fn build(images: &[Sub], layout: Layout) -> Vec<u8> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut w = FdtWriter::new(&mut buf).unwrap();
+        w.begin_node("").unwrap();
+        w.property_u32("timestamp", 0x6700_0000).unwrap();
+        w.property_str("description", "Linux for HPM6360EVK")
+            .unwrap();
+        w.property_u32("#address-cells", 1).unwrap();
+
+        w.begin_node("images").unwrap();
+        let mut offset = 0;
+        for image in images {
+            w.begin_node(image.name).unwrap();
+            w.property_str("description", image.name).unwrap();
+            let kind = match image.role {
+                "kernel" => "kernel",
+                "fdt" => "flat_dt",
+                _ => "ramdisk",
+            };
+            w.property_str("type", kind).unwrap();
+            w.property_str("arch", "riscv").unwrap();
+            w.property_str("os", "linux").unwrap();
+            w.property_str("compression", image.compression).unwrap();
+            if image.role == "kernel" {
+                w.property_u32("load", 0x4000_0000).unwrap();
+                w.property_u32("entry", 0x4000_0000).unwrap();
+            }
+            match layout {
+                Layout::Embedded => w.property("data", image.data).unwrap(),
+                Layout::External => w.property_u32("data-offset", offset as u32).unwrap(),
+                Layout::Position => {
+                    let position = (POSITION + offset) as u32;
+                    w.property_u32("data-position", position).unwrap()
+                }
+            }
+            if layout != Layout::Embedded {
+                w.property_u32("data-size", image.data.len() as u32)
+                    .unwrap();
+            }
+            offset += align4(image.data.len());
+            for (i, algo) in image.hashes.iter().enumerate() {
+                w.begin_node(&format!("hash-{}", i + 1)).unwrap();
+                match *algo {
+                    "crc32" => {
+                        let value = crc32::checksum(image.data).to_be_bytes();
+                        w.property("value", &value).unwrap()
+                    }
+                    "sha256" => w.property("value", &sha256::digest(image.data)).unwrap(),
+                    _ => w.property("value", &[0; 16]).unwrap(),
+                }
+                w.property_str("algo", algo).unwrap();
+                w.end_node().unwrap();
+            }
+            w.end_node().unwrap();
+        }
+        w.end_node().unwrap();
+
+        w.begin_node("configurations").unwrap();
+        w.property_str("default", "conf-1").unwrap();
+        w.begin_node("conf-1").unwrap();
+        w.property_str("description", "Boot Linux kernel with FDT blob")
+            .unwrap();
+        for image in images {
+            w.property_str(image.role, image.name).unwrap();
+        }
+        w.end_node().unwrap();
+        w.begin_node("conf-2").unwrap();
+        w.property_str("kernel", "kernel-1").unwrap();
+        w.end_node().unwrap();
+        w.end_node().unwrap();
+
+        w.end_node().unwrap();
+        let size = w.finish().unwrap();
+        buf.truncate(size);
+
+        let base = match layout {
+            Layout::Embedded => return buf,
+            Layout::External => align4(size),
+            Layout::Position => POSITION,
+        };
+        buf.resize(base, 0);
+        for image in images {
+            buf.extend_from_slice(image.data);
+            buf.resize(align4(buf.len()), 0);
+        }
+        buf
+    }
+
+    fn linux() -> [Sub<'static>; 3] {
+        [
+            Sub::new("kernel", IMAGE_GZ, "gzip"),
+            Sub::new("fdt", DTB, "none"),
+            Sub::new("ramdisk", IMAGE, "none"),
+        ]
+    }
+
+    #[test]
+    fn layouts() {
+        for layout in [Layout::Embedded, Layout::External, Layout::Position] {
+            let storage = build(&linux(), layout);
+            let fit = Fit::new(&storage).unwrap();
+            let config = fit.config(None).unwrap();
+            assert_eq!(config.name, "conf-1");
+            assert_eq!(config.description, Some("Boot Linux kernel with FDT blob"));
+            assert_eq!(config.kernel.data, IMAGE_GZ);
+            assert_eq!(config.kernel.compression, Compression::Gzip);
+            assert_eq!(config.kernel.load, Some(0x4000_0000));
+            assert_eq!(config.kernel.entry, Some(0x4000_0000));
+            assert_eq!(config.fdt.unwrap().data, DTB);
+            let ramdisk = config.ramdisk.unwrap();
+            assert_eq!(ramdisk.data, IMAGE);
+            assert_eq!(ramdisk.load, None);
+        }
+    }
+
+    #[test]
+    fn fixtures() {
+        for storage in ITB_FIXTURES {
+            assert!(is_fit(storage));
+            let fit = Fit::new(storage).unwrap();
+            let config = fit.config(None).unwrap();
+            assert_eq!(config.name, "conf-1");
+            assert_eq!(config.kernel.data, IMAGE_GZ);
+            assert_eq!(config.kernel.compression, Compression::Gzip);
+            assert_eq!(config.kernel.load, Some(0x4000_0000));
+            assert_eq!(config.kernel.entry, Some(0x4000_0000));
+            assert_eq!(config.fdt.unwrap().data, DTB_FIXTURE);
+            let ramdisk = config.ramdisk.unwrap();
+            assert_eq!(ramdisk.data, IMAGE_LZ4_LEGACY);
+            assert_eq!(ramdisk.compression, Compression::None);
+
+            let config = fit.config(Some("conf-2")).unwrap();
+            assert_eq!(config.kernel.data, IMAGE_GZ);
+            assert!(config.fdt.is_none() && config.ramdisk.is_none());
+        }
+    }
+
+    #[test]
+    fn fixture_hashes() {
+        // The ramdisk, checked by sha256, comes last in the file
+        for storage in ITB_FIXTURES {
+            let mut storage = storage.to_vec();
+            let end = storage.len();
+            storage[end - IMAGE_LZ4_LEGACY.len() / 2] ^= 1;
+            let fit = Fit::new(&storage).unwrap();
+            assert_eq!(fit.config(None).err(), Some(FitError::BadHash("ramdisk-1")));
+            assert!(fit.config(Some("conf-2")).is_ok());
+        }
+        // The kernel, checked by crc32 and sha256, then the device tree, checked by crc32
+        let mut storage = ITB_FIXTURES[1].to_vec();
+        let fdt = storage.len() - align4(IMAGE_LZ4_LEGACY.len()) - align4(DTB_FIXTURE.len());
+        let kernel = fdt - align4(IMAGE_GZ.len());
+        storage[kernel] ^= 1;
+        let fit = Fit::new(&storage).unwrap();
+        assert_eq!(
+            fit.config(Some("conf-2")).err(),
+            Some(FitError::BadHash("kernel-1"))
+        );
+        storage[kernel] ^= 1;
+        storage[fdt] ^= 1;
+        let fit = Fit::new(&storage).unwrap();
+        assert_eq!(fit.config(None).err(), Some(FitError::BadHash("fdt-1")));
+    }
+
+    #[test]
+    fn named_configuration() {
+        let storage = build(&linux(), Layout::External);
+        let fit = Fit::new(&storage).unwrap();
+        let config = fit.config(Some("conf-2")).unwrap();
+        assert_eq!(config.kernel.data, IMAGE_GZ);
+        assert!(config.fdt.is_none() && config.ramdisk.is_none());
+        assert_eq!(
+            fit.config(Some("conf-9")).err(),
+            Some(FitError::NoConfiguration("conf-9"))
+        );
+    }
+
+    #[test]
+    fn external_data_out_of_storage() {
+        let mut storage = build(&linux(), Layout::External);
+        storage.truncate(storage.len() - 8);
+        let fit = Fit::new(&storage).unwrap();
+        assert_eq!(fit.config(None).err(), Some(FitError::NoData("ramdisk-1")));
+    }
+
+    #[test]
+    fn hashes() {
+        let mut storage = build(&linux(), Layout::External);
+        let end = storage.len();
+        storage[end - IMAGE.len() / 2] ^= 1;
+        let fit = Fit::new(&storage).unwrap();
+        assert_eq!(fit.config(None).err(), Some(FitError::BadHash("ramdisk-1")));
+
+        let mut images = linux();
+        images[0].hashes = &["md5"];
+        let storage = build(&images, Layout::Embedded);
+        let fit = Fit::new(&storage).unwrap();
+        assert_eq!(
+            fit.config(None).err(),
+            Some(FitError::UnsupportedHash("kernel-1"))
+        );
+    }
+
+    #[test]
+    fn compression() {
+        let check = |images: &[Sub]| {
+            // Errors borrow names from the image
+            let storage = build(images, Layout::Embedded).leak();
+            let fit = Fit::new(storage).unwrap();
+            fit.config(None).map(|c| {
+                let compression = |i: Option<FitImage>| i.map(|i| i.compression);
+                let fdt = compression(c.fdt);
+                (c.kernel.compression, fdt, compression(c.ramdisk))
+            })
+        };
+        use Compression::{Gzip, Lz4, None as Plain};
+
+        assert_eq!(
+            check(&[
+                Sub::new("kernel", IMAGE_LZ4_LEGACY, "lz4"),
+                Sub::new("fdt", IMAGE_GZ, "gzip"),
+                // Left for the kernel to unpack
+                Sub::new("ramdisk", IMAGE_GZ, "none"),
+            ]),
+            Ok((Lz4, Some(Gzip), Some(Plain)))
+        );
+        assert_eq!(
+            check(&[Sub::new("kernel", IMAGE, "lzma")]),
+            Err(FitError::UnsupportedCompression("kernel-1"))
+        );
+        assert_eq!(
+            check(&[Sub::new("kernel", IMAGE, "gzip")]),
+            Err(FitError::CompressionMismatch("kernel-1"))
+        );
+        assert_eq!(
+            check(&[Sub::new("kernel", IMAGE_GZ, "lz4")]),
+            Err(FitError::CompressionMismatch("kernel-1"))
+        );
+        assert_eq!(
+            check(&[Sub::new("kernel", IMAGE_GZ, "none")]),
+            Err(FitError::CompressionMismatch("kernel-1"))
+        );
+        assert_eq!(
+            check(&[
+                Sub::new("kernel", IMAGE, "none"),
+                Sub::new("fdt", IMAGE_GZ, "none"),
+            ]),
+            Err(FitError::CompressionMismatch("fdt-1"))
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Telling a kernel from stale data, before a slot is committed to boot it.
+
+use super::decompress::Compression;
+use super::elf::{self, Elf};
+use super::fit::{self, Fit};
+use super::LoadError;
+
+/// `magic2` of the RISC-V Linux `Image` header, at offset `0x38`.
+const LINUX_IMAGE_MAGIC: &[u8] = b"RSC\x05";
+
+/// Check that `src` is a kernel: a FIT whose configuration `config` verifies, an ELF, a
+/// compressed image or a RISC-V Linux `Image`. `fit_storage` is `src` with room for
+/// external FIT data after it.
+///
+/// A raw binary without any header cannot be told from stale data, so it is refused.
+pub fn check(
+    src: &[u8],
+    fit_storage: &'static [u8],
+    config: Option<&'static str>,
+) -> Result<(), LoadError> {
+    if fit::is_fit(src) {
+        let fit = Fit::new(fit_storage).map_err(LoadError::Fit)?;
+        return fit.config(config).map(|_| ()).map_err(LoadError::Fit);
+    }
+    if elf::is_elf(src) {
+        return Elf::new(src).map(|_| ()).map_err(LoadError::Elf);
+    }
+    let linux = src.get(0x38..0x3C) == Some(LINUX_IMAGE_MAGIC);
+    if linux || Compression::detect(src) != Compression::None {
+        return Ok(());
+    }
+    Err(LoadError::NoKernel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::ElfError;
+
+    const IMAGE_GZ: &[u8] = include_bytes!("../../host-test/fixtures/Image.gz");
+
+    fn check(src: &'static [u8]) -> Result<(), LoadError> {
+        super::check(src, src, None)
+    }
+
+    #[test]
+    fn accepts_kernels() {
+        assert!(check(IMAGE_GZ).is_ok());
+        let mut linux = [0; 64];
+        linux[0x38..0x3C].copy_from_slice(LINUX_IMAGE_MAGIC);
+        assert!(check(Vec::leak(linux.to_vec())).is_ok());
+    }
+
+    #[test]
+    fn refuses_stale_data() {
+        assert!(matches!(check(&[0xFF; 256]), Err(LoadError::NoKernel)));
+        assert!(matches!(check(&[]), Err(LoadError::NoKernel)));
+    }
+
+    /// A corrupt ELF header is refused, not a panic inside the SBI call that checks it.
+    #[test]
+    fn refuses_malformed_elf() {
+        let mut header = [0; 64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = 1;
+        header[5] = 1;
+        header[18..20].copy_from_slice(&243u16.to_le_bytes());
+        header[42..44].copy_from_slice(&32u16.to_le_bytes());
+        header[44..46].copy_from_slice(&4u16.to_le_bytes());
+        // Program headers at the top of the address space
+        header[28..32].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        let src = Vec::leak(header.to_vec());
+        assert!(matches!(
+            check(src),
+            Err(LoadError::Elf(ElfError::Truncated))
+        ));
+        // Cut short of its own header
+        assert!(matches!(
+            check(&src[..16]),
+            Err(LoadError::Elf(ElfError::Truncated))
+        ));
+    }
+}
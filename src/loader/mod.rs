@@ -0,0 +1,879 @@
+use log::warn;
+use spin::lock_api::Mutex;
+
+use crate::board::{self, board_info};
+use crate::env;
+use crate::fdt::{self, Fdt, FdtError, Fixups, Platform};
+use crate::fw_dynamic;
+use crate::profile::{self, Stage};
+use crate::{
+    BOOTARGS, BOOT_TRIES, DTB_LOAD_ADDRESS, FIT_CONFIG, INITRD_LOAD_ADDRESS, INITRD_MAX_SIZE,
+    SUPERVISOR_ENTRY,
+};
+
+mod decompress;
+mod elf;
+mod error;
+mod fit;
+mod kernel;
+mod payload;
+mod slot;
+
+pub use decompress::{Compression, DecompressError};
+use elf::Elf;
+pub use error::LoadError;
+use fit::{Fit, FitImage};
+pub use slot::Slot;
+use slot::{BootControl, BOOT_CONTROL_ADDRESS};
+
+#[derive(PartialEq)]
+enum BlobType {
+    Kernel(Slot),
+    Dtb(Slot),
+    Initrd,
+}
+struct BlobInfo {
+    type_: BlobType,
+    start: usize,
+    length: usize,
+}
+/// # Blob Info Table
+///
+/// | Name         | Begin      | Length |
+/// |--------------|------------|--------|
+/// | RustSBI      | 0x80000000 | 64 KB  |
+/// | Kernel A     | 0x80010000 | 3 MB   |
+/// | DTB A        | 0x80310000 | 16 KB  |
+/// | Initrd       | 0x80314000 | 8 MB   |
+/// | Kernel B     | 0x80C00000 | 3 MB   |
+/// | DTB B        | 0x80F00000 | 16 KB  |
+/// | Boot control | 0x80FF0000 | 4 KB   |
+/// | Environment  | 0x80FF1000 | 2x4 KB |
+///
+/// The kernel may be stored as is, or compressed with gzip or LZ4. The initrd slot is
+/// optional and left erased when not used, and is shared by both boot slots.
+///
+/// With [`BootOptions::xip`] the kernel executes in place from its slot instead, which
+/// must then hold an uncompressed image or an ELF file linked to run from there.
+///
+/// Alternatively a kernel slot holds a FIT image, which may extend up to the next kernel
+/// slot or the boot-control sector and then replaces all the other blobs.
+const BLOB_TABLE: &'static [BlobInfo] = &[
+    BlobInfo {
+        type_: BlobType::Kernel(Slot::A),
+        start: 0x80010000,
+        length: 3 * 1024 * 1024,
+    },
+    BlobInfo {
+        type_: BlobType::Dtb(Slot::A),
+        start: 0x80310000,
+        length: 16 * 1024,
+    },
+    BlobInfo {
+        type_: BlobType::Initrd,
+        start: 0x80314000,
+        length: 8 * 1024 * 1024,
+    },
+    BlobInfo {
+        type_: BlobType::Kernel(Slot::B),
+        start: 0x80C00000,
+        length: 3 * 1024 * 1024,
+    },
+    BlobInfo {
+        type_: BlobType::Dtb(Slot::B),
+        start: 0x80F00000,
+        length: 16 * 1024,
+    },
+];
+
+/// Largest kernel image that fits in SDRAM before the device tree.
+const KERNEL_MAX_SIZE: usize = DTB_LOAD_ADDRESS - SUPERVISOR_ENTRY;
+/// Most `chosen.*` environment variables merged into `/chosen`.
+const CHOSEN_MAX: usize = 16;
+/// Room for the device tree after fix-ups.
+const DTB_MAX_SIZE: usize = 64 * 1024;
+/// A downloaded device tree is kept after the fixed up one, between it and the initrd.
+const DTB_DOWNLOAD_ADDRESS: usize = DTB_LOAD_ADDRESS + DTB_MAX_SIZE;
+/// A blob copied or decompressed to its load address.
+pub struct Loaded {
+    pub address: usize,
+    pub compression: Compression,
+    pub size: usize,
+}
+
+/// The device tree handed to the kernel.
+pub struct LoadedDtb {
+    pub address: usize,
+    pub size: usize,
+    /// No tree was stored in flash, so a minimal one was generated.
+    pub generated: bool,
+}
+
+/// Everything placed in memory for the kernel.
+pub struct BootImages {
+    pub entry: usize,
+    pub kernel: Loaded,
+    pub initrd: Option<Loaded>,
+    pub dtb: LoadedDtb,
+    /// The FIT configuration booted, when the kernel slot holds a FIT image.
+    pub fit_config: Option<FitBooted>,
+    pub source: Source,
+    /// Boots left to the slot before falling back, `None` once it was marked successful.
+    pub tries_remaining: Option<u8>,
+    /// The kernel executes in place from flash, where `kernel` says.
+    pub xip: bool,
+}
+
+/// Where the booted images came from.
+#[derive(Clone, Copy)]
+pub enum Source {
+    /// A boot slot in flash.
+    Slot(Slot),
+    /// Downloaded over the console.
+    Download,
+    /// Linked into the firmware by the `payload` feature.
+    Payload,
+}
+
+impl core::fmt::Display for Source {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Source::Slot(slot) => write!(f, "{slot}"),
+            Source::Download => write!(f, "serial download"),
+            Source::Payload => write!(f, "embedded payload"),
+        }
+    }
+}
+
+pub struct FitBooted {
+    pub name: &'static str,
+    pub description: Option<&'static str>,
+}
+
+/// Plain copies made while loading, and the time they took.
+#[derive(Clone, Copy)]
+pub struct CopyStats {
+    pub bytes: usize,
+    /// Of `bytes`, those copied by DMA rather than the CPU.
+    pub dma_bytes: usize,
+    /// Machine timer ticks.
+    pub ticks: u64,
+}
+
+static COPY_STATS: Mutex<CopyStats> = Mutex::new(CopyStats {
+    bytes: 0,
+    dma_bytes: 0,
+    ticks: 0,
+});
+
+/// What was copied so far.
+pub fn copy_stats() -> CopyStats {
+    *COPY_STATS.lock()
+}
+
+impl core::fmt::Display for CopyStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let freq = board_info().mchtmr_clock_freq as u64;
+        let us = board::ticks_to_us(self.ticks);
+        write!(f, "{} bytes in {us} us", self.bytes)?;
+        if let Some(kib_per_s) = (self.bytes as u64 * freq / 1024).checked_div(self.ticks) {
+            write!(f, ", {kib_per_s} KiB/s")?;
+        }
+        write!(f, ", {} bytes by DMA", self.dma_bytes)
+    }
+}
+
+/// Length limit of [`BootOptions::bootargs`].
+const BOOTARGS_MAX_LEN: usize = 256;
+
+/// Choices for this boot, changed from their defaults in the boot monitor.
+pub struct BootOptions {
+    bootargs: [u8; BOOTARGS_MAX_LEN],
+    /// Length of the kernel command line, `None` until one is set.
+    bootargs_len: Option<usize>,
+    /// Boot this slot once, leaving the boot-control record alone.
+    pub slot: Option<Slot>,
+    /// Images downloaded into SDRAM, booted instead of the flash slots.
+    pub downloaded: Downloaded,
+    /// Execute the kernel of a flash slot in place instead of copying it to SDRAM.
+    pub xip: bool,
+}
+
+/// Sizes of the images downloaded into their [`Image::download_buffer`]s.
+#[derive(Default)]
+pub struct Downloaded {
+    pub kernel: Option<usize>,
+    pub dtb: Option<usize>,
+    pub initrd: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Image {
+    Kernel,
+    Dtb,
+    Initrd,
+}
+
+impl Image {
+    /// Where a download of this image goes: the load address of the kernel and the
+    /// initrd, and a place to fix up from for the device tree.
+    pub unsafe fn download_buffer(self) -> &'static mut [u8] {
+        let (address, len) = match self {
+            Image::Kernel => (SUPERVISOR_ENTRY, KERNEL_MAX_SIZE),
+            Image::Dtb => (DTB_DOWNLOAD_ADDRESS, DTB_MAX_SIZE),
+            Image::Initrd => (INITRD_LOAD_ADDRESS, INITRD_MAX_SIZE),
+        };
+        core::slice::from_raw_parts_mut(address as *mut _, len)
+    }
+}
+
+impl Default for BootOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootOptions {
+    pub fn new() -> Self {
+        Self {
+            bootargs: [0; BOOTARGS_MAX_LEN],
+            bootargs_len: None,
+            slot: None,
+            downloaded: Downloaded::default(),
+            xip: false,
+        }
+    }
+
+    /// Kernel command line written to `/chosen`, replacing the one of the device tree.
+    /// Without one, the tree keeps its own, or gets [`BOOTARGS`] if it has none.
+    pub fn bootargs(&self) -> Option<&str> {
+        let len = self.bootargs_len?;
+        Some(core::str::from_utf8(&self.bootargs[..len]).unwrap())
+    }
+
+    /// Replace the kernel command line. Fails, keeping the old one, when `bootargs` is too long.
+    pub fn set_bootargs(&mut self, bootargs: &str) -> Result<(), usize> {
+        let dst = self
+            .bootargs
+            .get_mut(..bootargs.len())
+            .ok_or(BOOTARGS_MAX_LEN)?;
+        dst.copy_from_slice(bootargs.as_bytes());
+        self.bootargs_len = Some(bootargs.len());
+        Ok(())
+    }
+}
+
+fn blob(type_: BlobType) -> &'static BlobInfo {
+    BLOB_TABLE.iter().find(|info| info.type_ == type_).unwrap()
+}
+
+impl BlobInfo {
+    #[allow(unused)]
+    unsafe fn load(&self, load_address: *mut u8) {
+        let src: &[u8] = core::slice::from_raw_parts(self.start as *mut _, self.length);
+        let dst: &mut [u8] = core::slice::from_raw_parts_mut(load_address, self.length);
+        copy(dst, src);
+    }
+
+    unsafe fn bytes(&self) -> &'static [u8] {
+        core::slice::from_raw_parts(self.start as *const _, self.length)
+    }
+
+    /// Decompress the blob to `load_address`, writing at most `max_size` bytes.
+    unsafe fn unpack(&self, load_address: *mut u8, max_size: usize) -> Result<Loaded, LoadError> {
+        let src = self.bytes();
+        let dst: &mut [u8] = core::slice::from_raw_parts_mut(load_address, max_size);
+        let size = unpack(src, dst)?;
+
+        Ok(Loaded {
+            address: load_address as usize,
+            compression: Compression::detect(src),
+            size,
+        })
+    }
+
+    #[allow(unused)]
+    unsafe fn compare(&self, load_address: *const u8) {
+        let src: &[u8] = core::slice::from_raw_parts(self.start as *const _, self.length);
+        let dst: &[u8] = core::slice::from_raw_parts(load_address, self.length);
+
+        assert!(src.eq(dst))
+    }
+}
+
+/// Load the kernel, initrd and device tree from flash.
+///
+/// The boot slot comes from the boot-control record. A slot that has run out of tries
+/// or fails to load is given up for the other one. A slot chosen in `options` is booted
+/// as is, an embedded payload instead of any slot, and a downloaded kernel before
+/// everything else.
+pub unsafe fn load(options: &BootOptions) -> Result<BootImages, LoadError> {
+    if let Some(size) = options.downloaded.kernel {
+        return load_downloaded(size, options);
+    }
+    if let Some(kernel) = payload::kernel() {
+        return load_payload(kernel, options);
+    }
+    if let Some(slot) = options.slot {
+        return load_slot(slot, options);
+    }
+
+    let mut control = slot::read();
+    if let Some(failed) = control.count_boot() {
+        warn!(
+            "Slot {failed} ran out of boot attempts, falling back to slot {}",
+            control.active
+        );
+    }
+    store(&control);
+
+    let mut images = match load_slot(control.active, options) {
+        Err(e) => {
+            warn!(
+                "Slot {} failed to load: {e}, falling back to slot {}",
+                control.active,
+                control.active.other()
+            );
+            control.fall_back();
+            store(&control);
+            load_slot(control.active, options)?
+        }
+        images => images?,
+    };
+    images.tries_remaining = Some(control.tries_remaining).filter(|_| !control.successful);
+    Ok(images)
+}
+
+/// Stop the fallback countdown of the active slot. Called by the kernel through the
+/// vendor SBI extension once it is up.
+pub fn mark_boot_successful() -> Result<(), board::FlashError> {
+    let mut control = slot::read();
+    control.successful = true;
+    slot::write(&control)
+}
+
+/// Boot `slot` from now on, for at most [`BOOT_TRIES`] times until marked successful.
+/// Check it with [`check_slot`] first.
+pub fn set_active_slot(slot: Slot) -> Result<(), board::FlashError> {
+    slot::write(&BootControl::try_slot(slot, BOOT_TRIES))
+}
+
+/// Check that `slot` holds a kernel, before committing to boot it: a FIT whose
+/// configuration verifies, an ELF, a compressed image or a RISC-V Linux `Image`.
+///
+/// A raw binary without any header cannot be told from stale data, so it is refused.
+pub fn check_slot(slot: Slot) -> Result<(), LoadError> {
+    let src = unsafe { blob(BlobType::Kernel(slot)).bytes() };
+    kernel::check(src, unsafe { fit_storage(slot) }, FIT_CONFIG)
+}
+
+/// Persist `control`; a failure only costs the fallback, so booting goes on.
+fn store(control: &BootControl) {
+    if let Err(e) = slot::write(control) {
+        warn!("Failed to update boot control: {e}");
+    }
+}
+
+unsafe fn load_slot(slot: Slot, options: &BootOptions) -> Result<BootImages, LoadError> {
+    let info = blob(BlobType::Kernel(slot));
+
+    if fit::is_fit(info.bytes()) {
+        return load_fit(fit_storage(slot), Source::Slot(slot), options);
+    }
+
+    let (kernel, entry) = if options.xip {
+        profile::measure(Stage::KernelCopy, || map_kernel(slot))?
+    } else {
+        let kernel = profile::measure(Stage::KernelCopy, || load_kernel(slot))?;
+        (kernel, SUPERVISOR_ENTRY)
+    };
+    let initrd = load_initrd()?;
+    let dtb = blob(BlobType::Dtb(slot)).bytes();
+    let dtb = place_dtb(
+        Some(dtb).filter(|dtb| !is_erased(dtb)),
+        DTB_LOAD_ADDRESS,
+        initrd.as_ref(),
+        options.bootargs(),
+    )?;
+    Ok(BootImages {
+        entry,
+        kernel,
+        initrd,
+        dtb,
+        fit_config: None,
+        source: Source::Slot(slot),
+        tries_remaining: None,
+        xip: options.xip,
+    })
+}
+
+/// The kernel region of `slot` as FIT storage, extended up to whatever follows it in
+/// flash so that external data fits.
+unsafe fn fit_storage(slot: Slot) -> &'static [u8] {
+    let info = blob(BlobType::Kernel(slot));
+    let end = BLOB_TABLE
+        .iter()
+        .filter(|other| matches!(other.type_, BlobType::Kernel(_)))
+        .map(|other| other.start)
+        .chain([BOOT_CONTROL_ADDRESS])
+        .filter(|&start| start > info.start)
+        .min()
+        .unwrap();
+    core::slice::from_raw_parts(info.start as *const _, end - info.start)
+}
+
+/// Boot a kernel downloaded to its load address. Without a downloaded device tree, the
+/// one of the boot slot in flash is used.
+unsafe fn load_downloaded(
+    kernel_size: usize,
+    options: &BootOptions,
+) -> Result<BootImages, LoadError> {
+    let kernel = Loaded {
+        address: SUPERVISOR_ENTRY,
+        compression: Compression::None,
+        size: kernel_size,
+    };
+    let initrd = options.downloaded.initrd.map(|size| Loaded {
+        address: INITRD_LOAD_ADDRESS,
+        compression: Compression::None,
+        size,
+    });
+    let dtb = match options.downloaded.dtb {
+        Some(size) => Some(core::slice::from_raw_parts(
+            DTB_DOWNLOAD_ADDRESS as *const _,
+            size,
+        )),
+        None => {
+            let slot = options.slot.unwrap_or_else(|| slot::read().active);
+            Some(blob(BlobType::Dtb(slot)).bytes()).filter(|dtb| !is_erased(dtb))
+        }
+    };
+    let dtb = place_dtb(dtb, DTB_LOAD_ADDRESS, initrd.as_ref(), options.bootargs())?;
+    Ok(BootImages {
+        entry: SUPERVISOR_ENTRY,
+        kernel,
+        initrd,
+        dtb,
+        fit_config: None,
+        source: Source::Download,
+        tries_remaining: None,
+        xip: false,
+    })
+}
+
+/// Fix up the device tree an earlier stage passed at `dtb`, or generate one if it passed
+/// none or no tree in RAM, to [`DTB_LOAD_ADDRESS`] as for images loaded here. The earlier stage keeps the
+/// images it loaded clear of that region.
+pub unsafe fn place_handoff_dtb(dtb: usize, options: &BootOptions) -> Result<LoadedDtb, LoadError> {
+    let src = match dtb {
+        0 => None,
+        dtb => {
+            // `magic` and `totalsize` of the header, read only from RAM, then the whole tree
+            let header = fw_dynamic::in_ram(dtb, 8).then(|| {
+                let header = dtb as *const u32;
+                let magic = u32::from_be(header.read_unaligned());
+                (magic, u32::from_be(header.add(1).read_unaligned()) as usize)
+            });
+            match header {
+                Some((fdt::FDT_MAGIC, size)) if fw_dynamic::in_ram(dtb, size) => {
+                    if size > DTB_MAX_SIZE {
+                        return Err(LoadError::Dtb(FdtError::NoSpace));
+                    }
+                    // Moved out of the way first, as it may lie where the fixed up tree goes
+                    core::ptr::copy(dtb as *const u8, DTB_DOWNLOAD_ADDRESS as *mut u8, size);
+                    Some(core::slice::from_raw_parts(
+                        DTB_DOWNLOAD_ADDRESS as *const u8,
+                        size,
+                    ))
+                }
+                _ => {
+                    warn!("No device tree at {dtb:#x} from the previous stage, generating one");
+                    None
+                }
+            }
+        }
+    };
+    place_dtb(src, DTB_LOAD_ADDRESS, None, options.bootargs())
+}
+
+/// Boot the payload linked into the firmware, with its device tree or a generated one.
+unsafe fn load_payload(
+    kernel: &'static [u8],
+    options: &BootOptions,
+) -> Result<BootImages, LoadError> {
+    if fit::is_fit(kernel) {
+        return load_fit(kernel, Source::Payload, options);
+    }
+    let dst: &mut [u8] =
+        core::slice::from_raw_parts_mut(SUPERVISOR_ENTRY as *mut _, KERNEL_MAX_SIZE);
+    let size = profile::measure(Stage::KernelCopy, || unpack(kernel, dst))?;
+    let kernel = Loaded {
+        address: SUPERVISOR_ENTRY,
+        compression: Compression::detect(kernel),
+        size,
+    };
+    let dtb = place_dtb(payload::dtb(), DTB_LOAD_ADDRESS, None, options.bootargs())?;
+    Ok(BootImages {
+        entry: SUPERVISOR_ENTRY,
+        kernel,
+        initrd: None,
+        dtb,
+        fit_config: None,
+        source: Source::Payload,
+        tries_remaining: None,
+        xip: false,
+    })
+}
+
+unsafe fn load_kernel(slot: Slot) -> Result<Loaded, LoadError> {
+    let info = blob(BlobType::Kernel(slot));
+    assert!(info.start + info.length <= blob(BlobType::Dtb(slot)).start);
+
+    info.unpack(SUPERVISOR_ENTRY as *mut _, KERNEL_MAX_SIZE)
+}
+
+/// Prepare the kernel of `slot` to execute in place from flash, returning it and its
+/// entry.
+///
+/// An ELF kernel gets the segments not linked at their place in flash, its writable data,
+/// copied out to where they are linked. Any other image runs from the start of the slot,
+/// and must be stored uncompressed.
+unsafe fn map_kernel(slot: Slot) -> Result<(Loaded, usize), LoadError> {
+    let info = blob(BlobType::Kernel(slot));
+    let src = info.bytes();
+
+    if !elf::is_elf(src) {
+        if Compression::detect(src) != Compression::None {
+            return Err(LoadError::Xip("the image is compressed"));
+        }
+        let size = src.iter().rposition(|&b| b != 0xFF).map_or(0, |i| i + 1);
+        let kernel = Loaded {
+            address: info.start,
+            compression: Compression::None,
+            size,
+        };
+        return Ok((kernel, info.start));
+    }
+
+    let elf = Elf::new(src).map_err(LoadError::Elf)?;
+    for segment in elf.segments() {
+        let stored = segment.data.as_ptr() as usize;
+        if segment.address == stored && segment.data.len() == segment.memsz {
+            continue;
+        }
+        check_load_region(
+            segment.address,
+            segment.memsz,
+            &[
+                ("device tree", DTB_LOAD_ADDRESS, DTB_MAX_SIZE),
+                ("initrd", INITRD_LOAD_ADDRESS, INITRD_MAX_SIZE),
+            ],
+        )?;
+        let dst: &mut [u8] =
+            core::slice::from_raw_parts_mut(segment.address as *mut _, segment.memsz);
+        let (data, bss) = dst.split_at_mut(segment.data.len());
+        copy(data, segment.data);
+        bss.fill(0);
+    }
+    let kernel = Loaded {
+        address: info.start,
+        compression: Compression::None,
+        size: elf.size(),
+    };
+    Ok((kernel, elf.entry))
+}
+
+/// Load the initrd, if one is stored, to [`INITRD_LOAD_ADDRESS`].
+///
+/// A compressed initrd is unpacked here, so that its exact size is known for the
+/// device tree.
+unsafe fn load_initrd() -> Result<Option<Loaded>, LoadError> {
+    let info = blob(BlobType::Initrd);
+
+    check_load_region(
+        INITRD_LOAD_ADDRESS,
+        INITRD_MAX_SIZE,
+        &[
+            ("kernel", SUPERVISOR_ENTRY, KERNEL_MAX_SIZE),
+            ("device tree", DTB_LOAD_ADDRESS, DTB_MAX_SIZE),
+        ],
+    )?;
+    let mut src = info.bytes();
+    // Compressed streams know where they end, a plain archive ends before the erased tail
+    if Compression::detect(src) == Compression::None {
+        let len = src.iter().rposition(|&b| b != 0xFF).map_or(0, |i| i + 1);
+        src = &src[..len];
+    }
+    if src.is_empty() {
+        return Ok(None);
+    }
+    let dst: &mut [u8] =
+        core::slice::from_raw_parts_mut(INITRD_LOAD_ADDRESS as *mut _, INITRD_MAX_SIZE);
+    let size = profile::measure(Stage::InitrdCopy, || unpack(src, dst))?;
+
+    Ok(Some(Loaded {
+        address: INITRD_LOAD_ADDRESS,
+        compression: Compression::detect(src),
+        size,
+    }))
+}
+
+/// Load the images of the selected FIT configuration to their `load` addresses.
+///
+/// Images without `load` go where the blob table would put them.
+unsafe fn load_fit(
+    storage: &'static [u8],
+    source: Source,
+    options: &BootOptions,
+) -> Result<BootImages, LoadError> {
+    let fit = Fit::new(storage).map_err(LoadError::Fit)?;
+    let config = fit.config(FIT_CONFIG).map_err(LoadError::Fit)?;
+
+    let kernel_address = config.kernel.load.map_or(SUPERVISOR_ENTRY, |a| a as usize);
+    let kernel = profile::measure(Stage::KernelCopy, || {
+        load_fit_image(&config.kernel, kernel_address, &[])
+    })?;
+    let kernel_region = ("kernel", kernel.address, kernel.size);
+
+    let initrd = match &config.ramdisk {
+        Some(ramdisk) => {
+            let address = ramdisk.load.map_or(INITRD_LOAD_ADDRESS, |a| a as usize);
+            Some(profile::measure(Stage::InitrdCopy, || {
+                load_fit_image(ramdisk, address, &[kernel_region])
+            })?)
+        }
+        None => None,
+    };
+
+    let dtb_address = config
+        .fdt
+        .as_ref()
+        .and_then(|fdt| fdt.load)
+        .map_or(DTB_LOAD_ADDRESS, |a| a as usize);
+    let mut regions = [kernel_region, ("initrd", 0, 0)];
+    if let Some(initrd) = &initrd {
+        regions[1].1 = initrd.address;
+        regions[1].2 = initrd.size;
+    }
+    check_load_region(dtb_address, DTB_MAX_SIZE, &regions)?;
+    let fdt = match &config.fdt {
+        // Unpacked next to the tree, where a downloaded one would go, to be fixed up from there
+        Some(fdt) if fdt.compression != Compression::None => {
+            let [kernel, initrd] = regions;
+            let regions = [kernel, initrd, ("device tree", dtb_address, DTB_MAX_SIZE)];
+            let unpacked = load_fit_image(fdt, DTB_DOWNLOAD_ADDRESS, &regions)?;
+            check_load_region(DTB_DOWNLOAD_ADDRESS, unpacked.size, &regions)?;
+            Some(core::slice::from_raw_parts(
+                DTB_DOWNLOAD_ADDRESS as *const _,
+                unpacked.size,
+            ))
+        }
+        Some(fdt) => Some(fdt.data),
+        None => None,
+    };
+    let dtb = place_dtb(fdt, dtb_address, initrd.as_ref(), options.bootargs())?;
+
+    Ok(BootImages {
+        entry: config.kernel.entry.map_or(kernel_address, |e| e as usize),
+        kernel,
+        initrd,
+        dtb,
+        fit_config: Some(FitBooted {
+            name: config.name,
+            description: config.description,
+        }),
+        source,
+        tries_remaining: None,
+        xip: false,
+    })
+}
+
+/// Copy or decompress a FIT sub-image to `address`, staying clear of `others`.
+unsafe fn load_fit_image(
+    image: &FitImage<'static>,
+    address: usize,
+    others: &[(&'static str, usize, usize)],
+) -> Result<Loaded, LoadError> {
+    let board = board_info();
+    // The unpacked size is only known afterwards: never write past SDRAM, into the
+    // firmware or into an image placed before
+    let (firmware, _) = crate::firmware_region();
+    let limit = others
+        .iter()
+        .map(|&(_, start, _)| start)
+        .chain([firmware])
+        .filter(|&start| start > address)
+        .fold(
+            board.sdram_base.saturating_add(board.sdram_size),
+            usize::min,
+        );
+    check_load_region(address, 1, others)?;
+    let dst: &mut [u8] =
+        core::slice::from_raw_parts_mut(address as *mut _, limit.saturating_sub(address));
+
+    let size = match image.compression {
+        Compression::None => copy_into(dst, image.data)?,
+        _ => decompress::decompress(image.data, dst).map_err(LoadError::Decompress)?,
+    };
+    check_load_region(address, size, others)?;
+    Ok(Loaded {
+        address,
+        compression: image.compression,
+        size,
+    })
+}
+
+/// Decompress `src` into `dst`, or copy it there when stored as is. Returns the size.
+fn unpack(src: &[u8], dst: &mut [u8]) -> Result<usize, LoadError> {
+    match Compression::detect(src) {
+        Compression::None => copy_into(dst, src),
+        _ => decompress::decompress(src, dst).map_err(LoadError::Decompress),
+    }
+}
+
+/// Copy `src` to the start of `dst`, returning its length.
+fn copy_into(dst: &mut [u8], src: &[u8]) -> Result<usize, LoadError> {
+    let dst = dst
+        .get_mut(..src.len())
+        .ok_or(LoadError::Decompress(DecompressError::OutputOverflow))?;
+    copy(dst, src);
+    Ok(src.len())
+}
+
+/// Copy with DMA where possible, accounting for [`copy_stats`].
+fn copy(dst: &mut [u8], src: &[u8]) {
+    let timer = board::board_init_timer();
+    let start = timer.time64();
+    let dma = board::copy(dst, src);
+    let ticks = timer.time64() - start;
+
+    let mut stats = COPY_STATS.lock();
+    stats.bytes += src.len();
+    if dma {
+        stats.dma_bytes += src.len();
+    }
+    stats.ticks += ticks;
+}
+
+/// Check that `[start, start + len)` lies in SDRAM and clear of `others`
+/// and the firmware.
+fn check_load_region(
+    start: usize,
+    len: usize,
+    others: &[(&'static str, usize, usize)],
+) -> Result<(), LoadError> {
+    let board = board_info();
+    let (base, size) = crate::firmware_region();
+    let firmware = ("firmware", base, size);
+    let end = start
+        .checked_add(len)
+        .ok_or(LoadError::Overlap("the end of SDRAM"))?;
+    for &(name, base, size) in others.iter().chain([&firmware]) {
+        // A region wrapping around the address space overlaps everything
+        let other_end = base.checked_add(size).ok_or(LoadError::Overlap(name))?;
+        if start < other_end && base < end {
+            return Err(LoadError::Overlap(name));
+        }
+    }
+    let sdram_end = board
+        .sdram_base
+        .checked_add(board.sdram_size)
+        .ok_or(LoadError::Overlap("the end of SDRAM"))?;
+    if start < board.sdram_base || end > sdram_end {
+        return Err(LoadError::Overlap("the end of SDRAM"));
+    }
+    Ok(())
+}
+
+fn is_erased(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0xFF)
+}
+
+/// Write the device tree to `address`, fixing up `src` to match the board.
+///
+/// Without a source tree, a minimal one is generated instead. `bootargs` replaces the
+/// command line of the tree; without it, [`BOOTARGS`] only goes where there is none.
+unsafe fn place_dtb(
+    src: Option<&[u8]>,
+    address: usize,
+    initrd: Option<&Loaded>,
+    bootargs: Option<&str>,
+) -> Result<LoadedDtb, LoadError> {
+    let src = src.map(Fdt::new).transpose().map_err(LoadError::Dtb)?;
+    let bootargs = bootargs.or_else(|| {
+        let has_bootargs = src
+            .as_ref()
+            .and_then(|fdt| fdt.find("/chosen"))
+            .and_then(|chosen| chosen.property("bootargs"))
+            .is_some();
+        (!has_bootargs).then_some(BOOTARGS)
+    });
+    let dst: &mut [u8] = core::slice::from_raw_parts_mut(address as *mut _, DTB_MAX_SIZE);
+    let env = env::env();
+    let mut chosen = [("", ""); CHOSEN_MAX];
+    let vars = env
+        .iter()
+        .filter_map(|(name, value)| Some((name.strip_prefix("chosen.")?, value)));
+    let mut count = 0;
+    for (entry, var) in chosen.iter_mut().zip(vars) {
+        *entry = var;
+        count += 1;
+    }
+    let fixups = fixups(
+        initrd.map(|initrd| (initrd.address, initrd.address + initrd.size)),
+        bootargs,
+        &chosen[..count],
+    );
+
+    let Some(src) = src else {
+        let platform = Platform {
+            model: board::MODEL,
+            compatible: board::COMPATIBLE,
+            boot_hart: riscv::register::mhartid::read() as u32,
+            harts: board::NUM_HARTS as u32,
+            misa: riscv::register::misa::read().map_or(0, |misa| misa.bits()),
+        };
+        let size = profile::measure(Stage::DtbFixup, || fdt::generate(dst, &platform, &fixups))
+            .map_err(LoadError::Dtb)?;
+        return Ok(LoadedDtb {
+            address,
+            size,
+            generated: true,
+        });
+    };
+
+    let size = profile::measure(Stage::DtbFixup, || fdt::fixup(&src, dst, &fixups))
+        .map_err(LoadError::Dtb)?;
+    Ok(LoadedDtb {
+        address,
+        size,
+        generated: false,
+    })
+}
+
+fn fixups<'a>(
+    initrd: Option<(usize, usize)>,
+    bootargs: Option<&'a str>,
+    chosen: &'a [(&'a str, &'a str)],
+) -> Fixups<'a> {
+    let board = board_info();
+    let memory = (board.sdram_base as u64, board.sdram_size as u64);
+    let (base, size) = crate::firmware_region();
+    let firmware = (base as u64, size as u64);
+    Fixups {
+        memory,
+        timebase_frequency: board.mchtmr_clock_freq,
+        uart: (board.uart_base, board.uart_clock_freq),
+        reserved: overlap(firmware, memory),
+        bootargs,
+        initrd: initrd.map(|(start, end)| (start as u64, end as u64)),
+        chosen,
+    }
+}
+
+/// The part of region `a` inside region `b`, both `(base, size)`.
+fn overlap(a: (u64, u64), b: (u64, u64)) -> Option<(u64, u64)> {
+    let start = a.0.max(b.0);
+    let end = (a.0 + a.1).min(b.0 + b.1);
+    (start < end).then(|| (start, end - start))
+}
@@ -0,0 +1,24 @@
+//! Kernel and device tree linked into the firmware by the `payload` feature.
+//!
+//! `build.rs` copies them into `OUT_DIR` from the files named by `RUSTSBI_PAYLOAD_KERNEL`,
+//! `src/kernel.bin` by default, and `RUSTSBI_PAYLOAD_DTB`. An empty file stands for none.
+
+#[cfg(feature = "payload")]
+const KERNEL: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/payload-kernel.bin"));
+#[cfg(feature = "payload")]
+const DTB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/payload-dtb.bin"));
+
+#[cfg(not(feature = "payload"))]
+const KERNEL: &[u8] = &[];
+#[cfg(not(feature = "payload"))]
+const DTB: &[u8] = &[];
+
+/// The embedded kernel, raw, compressed or as a FIT image.
+pub fn kernel() -> Option<&'static [u8]> {
+    Some(KERNEL).filter(|kernel| !kernel.is_empty())
+}
+
+/// The embedded device tree.
+pub fn dtb() -> Option<&'static [u8]> {
+    Some(DTB).filter(|dtb| !dtb.is_empty())
+}
@@ -0,0 +1,180 @@
+//! A/B boot slots and the boot-control record choosing between them.
+//!
+//! The record lives in its own flash sector as a log of 16-byte entries. An update only
+//! programs the next erased entry, and the sector is erased once it is full, so a boot
+//! costs no erase cycle most of the time. The last valid entry is the current record; an
+//! empty or unreadable log means slot A, trusted.
+//!
+//! Entry layout, little endian: magic `"RSBC"`, active slot (0 or 1), tries remaining,
+//! successful flag, a zero byte, CRC-32 of the preceding 8 bytes, 4 zero bytes.
+
+use core::fmt;
+
+use crate::board::{self, FlashError, FLASH_SECTOR_SIZE};
+use crate::crc32;
+
+/// Flash sector holding the boot-control log.
+pub const BOOT_CONTROL_ADDRESS: usize = 0x80FF_0000;
+
+const ENTRY_SIZE: usize = 16;
+const MAGIC: u32 = u32::from_le_bytes(*b"RSBC");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// `a` or `b`, in either case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "a" | "A" => Some(Slot::A),
+            "b" | "B" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Slot::A => "A",
+            Slot::B => "B",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootControl {
+    pub active: Slot,
+    /// Boots left before falling back, counted only while `successful` is unset.
+    pub tries_remaining: u8,
+    /// The active slot has booted far enough to say it works.
+    pub successful: bool,
+}
+
+impl BootControl {
+    /// What an empty log stands for.
+    const DEFAULT: Self = Self {
+        active: Slot::A,
+        tries_remaining: 0,
+        successful: true,
+    };
+
+    /// Make `slot` active, with `tries` boots to prove itself.
+    pub fn try_slot(slot: Slot, tries: u8) -> Self {
+        Self {
+            active: slot,
+            tries_remaining: tries,
+            successful: false,
+        }
+    }
+
+    /// Account for the boot about to happen.
+    ///
+    /// Returns the slot given up on when the active one has run out of tries.
+    pub fn count_boot(&mut self) -> Option<Slot> {
+        if self.successful {
+            return None;
+        }
+        match self.tries_remaining.checked_sub(1) {
+            Some(tries) => {
+                self.tries_remaining = tries;
+                None
+            }
+            None => {
+                let failed = self.active;
+                self.fall_back();
+                Some(failed)
+            }
+        }
+    }
+
+    /// Give up on the active slot and trust the other one again.
+    pub fn fall_back(&mut self) {
+        *self = Self {
+            active: self.active.other(),
+            ..Self::DEFAULT
+        };
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        entry[4] = self.active as u8;
+        entry[5] = self.tries_remaining;
+        entry[6] = self.successful as u8;
+        let crc = crc32::checksum(&entry[0..8]);
+        entry[8..12].copy_from_slice(&crc.to_le_bytes());
+        entry
+    }
+
+    fn decode(entry: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(8) != crc32::checksum(&entry[0..8]) {
+            return None;
+        }
+        Some(Self {
+            active: Slot::from_index(entry[4] as usize)?,
+            tries_remaining: entry[5],
+            successful: entry[6] != 0,
+        })
+    }
+}
+
+/// The current record, and the offset of the first erased entry if the log is not full.
+fn scan(log: &[u8]) -> (BootControl, Option<usize>) {
+    let mut control = BootControl::DEFAULT;
+    for (i, entry) in log.chunks_exact(ENTRY_SIZE).enumerate() {
+        if entry.iter().all(|&b| b == 0xFF) {
+            return (control, Some(i * ENTRY_SIZE));
+        }
+        // Entries torn by a power loss are skipped
+        if let Some(c) = BootControl::decode(entry) {
+            control = c;
+        }
+    }
+    (control, None)
+}
+
+fn log() -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(BOOT_CONTROL_ADDRESS as *const u8, FLASH_SECTOR_SIZE) }
+}
+
+pub fn read() -> BootControl {
+    scan(log()).0
+}
+
+/// Append `control` to the log, unless it is the current record already.
+pub fn write(control: &BootControl) -> Result<(), FlashError> {
+    let (current, free) = scan(log());
+    if current == *control {
+        return Ok(());
+    }
+    board::with_flash(|flash| {
+        let offset = match free {
+            Some(offset) => offset,
+            None => {
+                flash.erase(BOOT_CONTROL_ADDRESS, FLASH_SECTOR_SIZE)?;
+                0
+            }
+        };
+        flash.program(BOOT_CONTROL_ADDRESS + offset, &control.encode())
+    })
+}
@@ -0,0 +1,76 @@
+//! Firmware log on the console, each line stamped with the machine timer.
+//!
+//! Levels above the `max-level-*` cargo feature are compiled out. Of the rest, the
+//! `loglevel` environment variable picks at boot, `info` unless set, and the vendor SBI
+//! extension at run time.
+
+use core::fmt;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::board;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        board::putchar(format_args!(
+            "[{}] [rustsbi] {level} {}\n",
+            Timestamp(board::time_us()),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Seconds and microseconds, or dashes before the timer is running.
+struct Timestamp(Option<u64>);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(us) => write!(f, "{:>5}.{:06}", us / 1_000_000, us % 1_000_000),
+            None => f.write_str("    -.------"),
+        }
+    }
+}
+
+/// Log at `level`, or at the default level if it is `None`.
+pub fn init(level: Option<LevelFilter>) {
+    // Only fails if already set, by an earlier call
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level.unwrap_or(DEFAULT_LEVEL));
+}
+
+/// Level with the number the vendor SBI extension uses: 0 for off, then 1 for errors
+/// through 5 for trace.
+pub fn level_from_index(index: usize) -> Option<LevelFilter> {
+    use LevelFilter::*;
+    [Off, Error, Warn, Info, Debug, Trace].get(index).copied()
+}
+
+/// Change the level, returning the one before.
+pub fn set_level(level: LevelFilter) -> LevelFilter {
+    let old = log::max_level();
+    log::set_max_level(level);
+    old
+}
@@ -4,25 +4,61 @@
 #![deny(warnings)]
 
 mod board;
+mod crc32;
+mod env;
 mod extension;
+mod fdt;
+mod fw_dynamic;
 mod loader;
+mod logger;
+mod monitor;
+mod next_stage;
 mod pmp;
+mod profile;
 mod riscv_spec;
+mod sha256;
 mod trap;
 mod trap_stack;
+mod vendor;
+mod ymodem;
 mod constants {
+    use crate::next_stage::{Arguments, Mode, NextStage};
+
     /// 特权软件入口。
     pub(crate) const SUPERVISOR_ENTRY: usize = 0x4000_0000;
     /// 设备树加载地址。
     pub(crate) const DTB_LOAD_ADDRESS: usize = 0x4030_0000;
+    /// initrd 加载地址，位于设备树之后。
+    pub(crate) const INITRD_LOAD_ADDRESS: usize = 0x4040_0000;
+    /// initrd 最大长度。
+    pub(crate) const INITRD_MAX_SIZE: usize = 8 * 1024 * 1024;
+    /// 启动的 FIT 配置，`None` 表示使用镜像中的默认配置。
+    pub(crate) const FIT_CONFIG: Option<&str> = None;
+    /// 新启动槽位在被标记为成功之前允许的启动次数，用尽后回退到另一个槽位。
+    pub(crate) const BOOT_TRIES: u8 = 3;
+    /// 自动启动前等待按键进入监控程序的秒数，为 0 时直接启动。
+    pub(crate) const AUTOBOOT_DELAY: u32 = 3;
+    /// 是否在 flash 中就地执行内核，而不是复制到 SDRAM，可由环境变量覆盖。
+    pub(crate) const XIP_BOOT: bool = false;
+    /// 下一阶段的特权态、入口和参数约定，可由环境变量覆盖。
+    pub(crate) const NEXT_STAGE: NextStage = NextStage {
+        mode: Mode::Supervisor,
+        entry: None,
+        arguments: Arguments::HartIdDtb,
+    };
+    /// 设备树 `/chosen` 中没有内核命令行、且未由环境变量或监视器指定时使用的默认值。
+    pub(crate) const BOOTARGS: &str = "earlycon=sbi console=hvc0";
     /// 每个硬件线程设置 16KiB 栈空间。
     pub(crate) const LEN_STACK_PER_HART: usize = 16 * 1024;
 }
 
 use core::arch::asm;
+use log::{info, log_enabled, warn, Level, LevelFilter};
 use riscv::register::{mcause, mtval};
 
 use constants::*;
+use next_stage::Mode;
+use profile::Stage;
 use riscv_spec::*;
 use trap_stack::local_hsm;
 
@@ -35,47 +71,115 @@ struct Supervisor {
 
 #[hpm_rt::entry]
 fn main() -> ! {
+    profile::mark(Stage::Startup);
+    // 取得上一阶段通过 fw_dynamic_info 传递的信息，须在使用 mscratch 之前
+    let handoff = fw_dynamic::take();
     let hartid = riscv::register::mhartid::read();
 
-    board::board_init();
-
-    // Print startup messages
-    print!(
-        "\
-[rustsbi] RustSBI version {rustsbi_version}, adapting to RISC-V SBI v2.0.0
-{logo}
-[rustsbi] Implementation     : RustSBI-HPM Version {impl_version}
-[rustsbi] Platform Name      : {model}
-[rustsbi] Boot HART          : {hartid}
-[rustsbi] Firmware Address   : {firmware_address:#010x}
-[rustsbi] Supervisor Address : {SUPERVISOR_ENTRY:#010x}
-[rustsbi] Devicetree Address : {DTB_LOAD_ADDRESS:#010x}
-",
-        rustsbi_version = rustsbi::VERSION,
-        logo = rustsbi::LOGO,
-        impl_version = env!("CARGO_PKG_VERSION"),
-        model = "HPM6360EVK",
-        firmware_address = _start as usize,
+    // 读取环境变量，其中的波特率用于初始化串口
+    env::load();
+    let baudrate = env::env()
+        .get("baudrate")
+        .and_then(|baudrate| baudrate.parse().ok())
+        .unwrap_or(board::CONSOLE_BAUDRATE);
+    // 日志级别同样来自环境变量；上一阶段要求不打印启动信息时，只保留警告和错误
+    let quiet = handoff.is_some_and(|h| h.options & fw_dynamic::OPTION_NO_BOOT_PRINTS != 0);
+    let loglevel = env::env()
+        .get("loglevel")
+        .and_then(|level| level.parse().ok());
+    logger::init(match quiet {
+        true => Some(loglevel.unwrap_or(LevelFilter::Warn).min(LevelFilter::Warn)),
+        false => loglevel,
+    });
+    profile::measure(Stage::BoardInit, || board::board_init(baudrate));
+
+    let supervisor_address = handoff.map_or(SUPERVISOR_ENTRY, |h| h.next_addr);
+
+    // 打印启动信息
+    info!(
+        "RustSBI version {}, adapting to RISC-V SBI v2.0.0",
+        rustsbi::VERSION
     );
+    if log_enabled!(Level::Info) {
+        println!("{}", rustsbi::LOGO);
+    }
+    info!(
+        "Implementation     : RustSBI-HPM Version {}",
+        env!("CARGO_PKG_VERSION")
+    );
+    info!("Platform Name      : {}", board::MODEL);
+    info!("Boot HART          : {hartid}");
+    info!("Firmware Address   : {:#010x}", _start as usize);
+    info!("Supervisor Address : {supervisor_address:#010x}");
+    info!("Devicetree Address : {DTB_LOAD_ADDRESS:#010x}");
     // 初始化 PMP
-    pmp::set_pmp();
-    // 显示 PMP 配置
-    pmp::print_pmps();
-    // 设置陷入栈
+    let mut next_stage = NEXT_STAGE.with_env();
+    if let Some(handoff) = &handoff {
+        next_stage.mode = handoff.next_mode;
+        next_stage.entry = Some(handoff.next_addr);
+    }
+    profile::measure(Stage::PmpSetup, || pmp::set_pmp(next_stage.mode));
+    // 设置陷入栈，释放其他 hart，它们等待 hart_start
+    trap_stack::init();
     trap_stack::prepare_for_trap();
-    unsafe {
-        // 加载内核
-        loader::load_kernel();
-        // 加载设备树
-        loader::load_dtb()
+    board::release_secondary_harts(trap_stack::secondary_entry as usize);
+    // 由上一阶段加载时直接启动它指定的镜像，否则从 flash 或串口加载；设备树的修正两者相同
+    let (mut options, autoboot_delay) = boot_options();
+    let (entry, dtb) = match &handoff {
+        Some(handoff) => {
+            info!(
+                "Previous Stage     : fw_dynamic_info version {}, options {:#x}",
+                handoff.version, handoff.options
+            );
+            if let Some(boot_hart) = handoff.boot_hart.filter(|&h| h != hartid) {
+                warn!("Boot hart {boot_hart} requested, booting on hart {hartid}");
+            }
+            let dtb = unsafe { loader::place_handoff_dtb(handoff.dtb, &options) }
+                .unwrap_or_else(|e| panic!("failed to place the device tree: {e}"));
+            print_dtb(&dtb);
+            (handoff.next_addr, dtb.address)
+        }
+        None => load_images(next_stage.mode, &mut options, autoboot_delay),
     };
+    // 显示 PMP 配置，镜像已放置好，包括 XIP 内核的窗口
+    pmp::print_pmps(|entry| info!("{entry}"));
+    let entry = next_stage.entry.unwrap_or(entry);
+    let opaque = next_stage.opaque(dtb);
+    info!("Next Stage         : {next_stage}, entry {entry:#010x}");
+    profile::mark(Stage::Handoff);
+    profile::print();
+    info!("Starting kernel ...");
+    // 写回数据缓存并使指令缓存失效，下一阶段才能取到加载的镜像
+    mcctl::l1d_writeback_all();
+    unsafe { fence_i() };
+    // M 态的下一阶段直接跳转，不再经过 RustSBI
+    if next_stage.mode == Mode::Machine {
+        unsafe { next_stage::enter_machine(entry, opaque) }
+    }
     // 设置内核入口
     local_hsm().prepare(Supervisor {
-        start_addr: SUPERVISOR_ENTRY,
-        opaque: DTB_LOAD_ADDRESS,
+        start_addr: entry,
+        opaque,
     });
+    // 内核运行期间由串口中断收发控制台数据
+    board::enable_console_interrupt();
+    enter_trap_handler()
+}
+
+/// 其他 hart 的主函数，运行在自己的栈上：等待 `hart_start` 后进入陷入处理。
+extern "C" fn secondary_main() -> ! {
+    board::hart_init();
+    trap_stack::prepare_for_trap();
+    unsafe { mie::write(mie::MSIE) };
+    trap_stack::wait_for_start();
+    // 与启动 hart 相同的 PMP 配置
+    pmp::replicate();
+    enter_trap_handler()
+}
+
+/// 设置委托和陷入入口后跳转到陷入处理，由它启动准备好的下一阶段。
+fn enter_trap_handler() -> ! {
     // 准备启动调度
-    println!("\nStarting kernel ...\n");
     unsafe {
         asm!("csrw mideleg,    {}", in(reg) !0);
         asm!("csrw medeleg,    {}", in(reg) !0);
@@ -94,30 +198,142 @@ fn main() -> ! {
     }
 }
 
+/// 从环境变量得到启动选项和自动启动倒计时。
+fn boot_options() -> (loader::BootOptions, u32) {
+    match env::env().generation() {
+        Some(generation) => info!("Environment        : generation {generation}"),
+        None => info!("Environment        : defaults"),
+    }
+    let mut options = loader::BootOptions::new();
+    options.xip = XIP_BOOT;
+    let mut autoboot_delay = AUTOBOOT_DELAY;
+    {
+        let env = env::env();
+        if let Some(bootargs) = env.get("bootargs") {
+            if let Err(max) = options.set_bootargs(bootargs) {
+                warn!("bootargs longer than {max} bytes, ignored");
+            }
+        }
+        if let Some(name) = env.get("bootslot") {
+            options.slot = loader::Slot::from_name(name);
+            if options.slot.is_none() {
+                warn!("bootslot {name:?} is neither a nor b, ignored");
+            }
+        }
+        if let Some(delay) = env.get("bootdelay").and_then(|delay| delay.parse().ok()) {
+            autoboot_delay = delay;
+        }
+        match env.get("bootxip") {
+            Some("1") => options.xip = true,
+            Some("0") => options.xip = false,
+            Some(xip) => warn!("bootxip {xip:?} is neither 0 nor 1, ignored"),
+            None => {}
+        }
+    }
+    (options, autoboot_delay)
+}
+
+/// 经过监控程序后加载镜像，返回入口和设备树地址。
+fn load_images(
+    mode: Mode,
+    options: &mut loader::BootOptions,
+    autoboot_delay: u32,
+) -> (usize, usize) {
+    // 倒计时，按键进入监控程序
+    monitor::autoboot(options, autoboot_delay);
+    // 加载内核、initrd 和设备树
+    let images =
+        unsafe { loader::load(options) }.unwrap_or_else(|e| panic!("failed to load images: {e}"));
+    match images.tries_remaining {
+        Some(tries) => info!(
+            "Boot Slot          : {}, {tries} tries remaining",
+            images.source
+        ),
+        None => info!("Boot Slot          : {}", images.source),
+    }
+    if let Some(config) = &images.fit_config {
+        info!(
+            "FIT Configuration  : {} ({})",
+            config.name,
+            config.description.unwrap_or("no description")
+        );
+    }
+    if images.xip {
+        info!(
+            "Kernel Image       : in place, {} bytes at {:#010x}",
+            images.kernel.size, images.kernel.address
+        );
+        // 就地执行的内核需要访问 flash 中的窗口
+        let start = images.kernel.address;
+        pmp::set_xip_window(mode, start..start + images.kernel.size);
+    } else {
+        info!(
+            "Kernel Image       : {:?}, {} bytes at {:#010x}",
+            images.kernel.compression, images.kernel.size, images.kernel.address
+        );
+    }
+    if let Some(initrd) = &images.initrd {
+        info!(
+            "Initrd             : {:?}, {} bytes at {:#010x}",
+            initrd.compression, initrd.size, initrd.address
+        );
+    }
+    print_dtb(&images.dtb);
+    let copied = loader::copy_stats();
+    if copied.bytes > 0 {
+        info!("Copy Throughput    : {copied}");
+    }
+    (images.entry, images.dtb.address)
+}
+
+fn print_dtb(dtb: &loader::LoadedDtb) {
+    info!(
+        "Devicetree         : {}, {} bytes at {:#010x}",
+        if dtb.generated {
+            "generated"
+        } else {
+            "fixed up"
+        },
+        dtb.size,
+        dtb.address
+    );
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!(
-        "[rustsbi-panic] hart {} {info}",
+    // 绕过控制台的锁：panic 可能发生在打印途中，也可能早于 board_init
+    board::emergency_print(format_args!(
+        "[rustsbi-panic] hart {} {info}\n",
         riscv::register::mhartid::read()
-    );
-    println!(
+    ));
+    board::emergency_print(format_args!(
         "-----------------------------
 > mcause:  {:?}
 > mdcause: {:#010x}
 > mstatus: {:#010x}
 > mepc:    {:#010x}
 > mtval:   {:#010x}
------------------------------",
+-----------------------------\n",
         mcause::read().cause(),
         mdcause::read(),
         mstatus::read(),
         mepc::read(),
         mtval::read()
-    );
-    println!("[rustsbi-panic] system shutdown scheduled due to RustSBI panic");
+    ));
+    board::emergency_print(format_args!(
+        "[rustsbi-panic] system shutdown scheduled due to RustSBI panic\n"
+    ));
     loop {}
 }
 
 extern "C" {
     fn _start();
+    /// 固件镜像（含内嵌的 payload）在加载位置的结束地址，由 build.rs 生成的链接脚本定义。
+    static _rustsbi_image_end: u8;
+}
+
+/// 固件镜像自身占用的空间 `(起始地址, 长度)`，在设备树中保留，加载镜像时避开。
+fn firmware_region() -> (usize, usize) {
+    let end = unsafe { core::ptr::addr_of!(_rustsbi_image_end) } as usize;
+    (_start as usize, end - _start as usize)
 }
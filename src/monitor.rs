@@ -0,0 +1,329 @@
+//! Boot monitor: an autoboot countdown that drops into a small command shell on the
+//! console, for bringing up boards without reflashing.
+
+use crate::board::{self, board_info, MachineTimer};
+use crate::loader::{BootOptions, Image, Slot};
+use crate::{env, pmp, print, println, ymodem};
+
+const PROMPT: &str = "rustsbi> ";
+const LINE_MAX_LEN: usize = 256;
+
+const HELP: &str = "\
+help                      show this help
+md <addr> [len]           dump memory bytes, 64 by default
+mw <addr> <value> [count] write 32-bit words
+clocks                    show the clock tree
+uart                      show console settings and receive errors
+pmp                       show PMP entries
+sdramtest                 test the whole SDRAM, destroying its contents
+loady <kernel|dtb|initrd> download an image into SDRAM over YMODEM or XMODEM-1K,
+                          to boot instead of the flash slots
+bootargs [args...]        show or replace the kernel command line
+printenv [name]           show environment variables
+setenv <name> [value]     set or, without a value, delete a variable
+saveenv                   write the environment to flash
+slot [a|b]                show or choose the boot slot for this boot
+reset                     reset the chip
+boot                      continue booting
+Numbers are decimal, or hexadecimal with a 0x prefix.";
+
+/// Count `delay` seconds down, and run the shell if a key is pressed meanwhile.
+///
+/// The `y` key downloads a kernel and boots it right away.
+pub fn autoboot(options: &mut BootOptions, delay: u32) {
+    if delay == 0 {
+        return;
+    }
+    match countdown(delay) {
+        None => return,
+        Some(b'y') if download(options, Image::Kernel) => return,
+        Some(_) => {}
+    }
+    println!("{HELP}");
+    let mut line = Line::new();
+    loop {
+        print!("{PROMPT}");
+        if let Continue::Boot = run(line.read(), options) {
+            break;
+        }
+    }
+}
+
+/// Returns the key pressed before the time ran out.
+fn countdown(delay: u32) -> Option<u8> {
+    let timer = board::board_init_timer();
+    let ticks_per_second = board_info().mchtmr_clock_freq as u64;
+    let start = timer.time64();
+    let mut shown = None;
+    loop {
+        let elapsed = ((timer.time64() - start) / ticks_per_second) as u32;
+        if elapsed >= delay {
+            println!("\rHit any key to stop autoboot: 0");
+            return None;
+        }
+        if shown != Some(elapsed) {
+            print!("\rHit any key to stop autoboot: {}", delay - elapsed);
+            shown = Some(elapsed);
+        }
+        let c = board::getchar();
+        if c != usize::MAX {
+            println!();
+            return Some(c as u8);
+        }
+    }
+}
+
+enum Continue {
+    Shell,
+    Boot,
+}
+
+fn run(line: &str, options: &mut BootOptions) -> Continue {
+    let (command, rest) = line
+        .trim()
+        .split_once(' ')
+        .map_or((line.trim(), ""), |(c, r)| (c, r.trim()));
+    let mut args = rest.split_whitespace();
+    match command {
+        "" => {}
+        "help" => println!("{HELP}"),
+        "md" => match (args.next().and_then(parse), args.next().map(parse)) {
+            (Some(addr), None) => dump(addr, 64),
+            (Some(addr), Some(Some(len))) => dump(addr, len),
+            _ => println!("usage: md <addr> [len]"),
+        },
+        "mw" => match (
+            args.next().and_then(parse),
+            args.next().and_then(parse),
+            args.next().map(parse).unwrap_or(Some(1)),
+        ) {
+            (Some(addr), Some(value), Some(count)) if addr % 4 == 0 => {
+                if count
+                    .checked_mul(4)
+                    .and_then(|len| addr.checked_add(len))
+                    .is_none()
+                {
+                    println!("mw: range wraps around the address space");
+                    return Continue::Shell;
+                }
+                for i in 0..count {
+                    unsafe { ((addr + i * 4) as *mut u32).write_volatile(value as u32) };
+                }
+            }
+            _ => println!("usage: mw <addr> <value> [count], addr 4-byte aligned"),
+        },
+        "clocks" => board::print_clocks(),
+        "uart" => board::print_console(),
+        "pmp" => pmp::print_pmps(|entry| println!("{entry}")),
+        "sdramtest" => match board::sdram_test() {
+            Ok(()) => println!("SDRAM test passed"),
+            Err(addr) => println!("SDRAM test failed at {addr:#010x}"),
+        },
+        "loady" => {
+            let image = match rest {
+                "kernel" => Image::Kernel,
+                "dtb" => Image::Dtb,
+                "initrd" => Image::Initrd,
+                _ => {
+                    println!("usage: loady <kernel|dtb|initrd>");
+                    return Continue::Shell;
+                }
+            };
+            download(options, image);
+        }
+        "bootargs" if rest.is_empty() => match options.bootargs() {
+            Some(bootargs) => println!("{bootargs}"),
+            None => println!("(from the device tree, or {:?})", crate::BOOTARGS),
+        },
+        "bootargs" => {
+            if let Err(max) = options.set_bootargs(rest) {
+                println!("bootargs longer than {max} bytes");
+            }
+        }
+        "printenv" => {
+            let env = env::env();
+            match rest {
+                "" => env
+                    .iter()
+                    .for_each(|(name, value)| println!("{name}={value}")),
+                name => match env.get(name) {
+                    Some(value) => println!("{name}={value}"),
+                    None => println!("{name} is not set"),
+                },
+            }
+        }
+        "setenv" => {
+            let (name, value) = rest
+                .split_once(' ')
+                .map_or((rest, None), |(n, v)| (n, Some(v.trim())));
+            if let Err(e) = env::env().set(name, value) {
+                println!("setenv: {e}");
+            }
+        }
+        "saveenv" => match env::env().save() {
+            Ok(()) => println!("Environment saved"),
+            Err(e) => println!("saveenv: {e}"),
+        },
+        "slot" => match rest {
+            "" => match options.slot {
+                Some(slot) => println!("{slot}"),
+                None => println!("from boot control"),
+            },
+            name => match Slot::from_name(name) {
+                Some(slot) => options.slot = Some(slot),
+                None => println!("usage: slot [a|b]"),
+            },
+        },
+        "reset" => board::reset(),
+        "boot" => return Continue::Boot,
+        other => println!("unknown command {other:?}, try help"),
+    }
+    Continue::Shell
+}
+
+/// Receive `image` into SDRAM and remember it in `options`. Returns whether it worked.
+fn download(options: &mut BootOptions, image: Image) -> bool {
+    println!("Ready for YMODEM or XMODEM-1K, start the transfer now");
+    let buf = unsafe { image.download_buffer() };
+    match ymodem::receive(&mut Console::new(), buf) {
+        Ok(received) => {
+            println!(
+                "Received {} ({} bytes)",
+                received.name().unwrap_or("file"),
+                received.size
+            );
+            let size = Some(received.size);
+            match image {
+                Image::Kernel => options.downloaded.kernel = size,
+                Image::Dtb => options.downloaded.dtb = size,
+                Image::Initrd => options.downloaded.initrd = size,
+            }
+            true
+        }
+        Err(e) => {
+            println!("Download failed: {e}");
+            false
+        }
+    }
+}
+
+/// The console as the transport for [`ymodem::receive`].
+struct Console {
+    timer: MachineTimer,
+    ticks_per_ms: u64,
+}
+
+impl Console {
+    fn new() -> Self {
+        Self {
+            timer: board::board_init_timer(),
+            ticks_per_ms: board_info().mchtmr_clock_freq as u64 / 1000,
+        }
+    }
+}
+
+impl ymodem::Port for Console {
+    fn read(&mut self, timeout_ms: u32) -> Option<u8> {
+        let deadline = self.timer.time64() + timeout_ms as u64 * self.ticks_per_ms;
+        loop {
+            let c = board::getchar();
+            if c != usize::MAX {
+                return Some(c as u8);
+            }
+            if self.timer.time64() >= deadline {
+                return None;
+            }
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        // Only ever control characters and 'C', which go out as one byte
+        print!("{}", byte as char);
+    }
+}
+
+fn parse(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Hex dump of `[addr, addr + len)`, 16 bytes a line.
+fn dump(addr: usize, len: usize) {
+    let Some(last) = addr.checked_add(len) else {
+        println!("md: range wraps around the address space");
+        return;
+    };
+    for line in (addr..last).step_by(16) {
+        let end = line.saturating_add(16).min(last);
+        let mut bytes = [0u8; 16];
+        for (i, b) in (line..end).zip(&mut bytes) {
+            *b = unsafe { (i as *const u8).read_volatile() };
+        }
+        let bytes = &bytes[..end - line];
+        print!("{line:08x}:");
+        for b in bytes {
+            print!(" {b:02x}");
+        }
+        print!("{:1$} |", "", (16 - bytes.len()) * 3);
+        for &b in bytes {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            print!("{c}");
+        }
+        println!("|");
+    }
+}
+
+/// Line editor with echo and backspace.
+struct Line {
+    buf: [u8; LINE_MAX_LEN],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Self {
+            buf: [0; LINE_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    fn read(&mut self) -> &str {
+        self.len = 0;
+        loop {
+            let c = board::getchar();
+            if c == usize::MAX {
+                continue;
+            }
+            match c as u8 {
+                b'\r' | b'\n' => {
+                    println!();
+                    break;
+                }
+                // Backspace, delete
+                0x08 | 0x7F if self.len > 0 => {
+                    self.len -= 1;
+                    print!("\x08 \x08");
+                }
+                // Ctrl-C drops the line
+                0x03 => {
+                    println!("^C");
+                    self.len = 0;
+                    break;
+                }
+                c @ 0x20..=0x7E if self.len < LINE_MAX_LEN => {
+                    self.buf[self.len] = c;
+                    self.len += 1;
+                    print!("{}", c as char);
+                }
+                _ => {}
+            }
+        }
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
@@ -0,0 +1,119 @@
+//! The stage booted after the firmware, and how control is handed to it.
+//!
+//! A supervisor stage (Linux, U-Boot) runs on top of the firmware, which stays resident
+//! for SBI calls and traps. A machine-mode stage (nommu Linux, Zephyr) takes the whole
+//! machine: the firmware jumps to it and is gone.
+//!
+//! Defaults come from `constants`, and may be overridden by the environment:
+//!
+//! | Name        | Meaning                                               |
+//! |-------------|-------------------------------------------------------|
+//! | `bootmode`  | `s` or `m`, privilege mode of the next stage          |
+//! | `bootentry` | entry address in hexadecimal, instead of the image's  |
+//! | `bootdtb`   | `0` to pass zero in `a1` instead of the device tree   |
+
+use core::arch::asm;
+use core::fmt;
+
+use log::warn;
+
+use crate::env;
+use crate::riscv_spec::{fence_i, mie, mstatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Supervisor,
+    Machine,
+}
+
+impl Mode {
+    /// `s` or `m`, in either case.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "s" | "S" => Some(Mode::Supervisor),
+            "m" | "M" => Some(Mode::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// What the next stage finds in its argument registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arguments {
+    /// `a0` hart ID, `a1` device tree, as Linux, U-Boot and Zephyr expect.
+    HartIdDtb,
+    /// `a0` hart ID, `a1` zero, for images carrying their own device tree.
+    HartId,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NextStage {
+    pub mode: Mode,
+    /// Jump here instead of the entry of the loaded image.
+    pub entry: Option<usize>,
+    pub arguments: Arguments,
+}
+
+impl NextStage {
+    /// `self`, with what the environment overrides.
+    pub fn with_env(mut self) -> Self {
+        let env = env::env();
+        if let Some(name) = env.get("bootmode") {
+            match Mode::from_name(name) {
+                Some(mode) => self.mode = mode,
+                None => warn!("bootmode {name:?} is neither s nor m, ignored"),
+            }
+        }
+        if let Some(dtb) = env.get("bootdtb") {
+            match dtb {
+                "1" => self.arguments = Arguments::HartIdDtb,
+                "0" => self.arguments = Arguments::HartId,
+                _ => warn!("bootdtb {dtb:?} is neither 0 nor 1, ignored"),
+            }
+        }
+        if let Some(entry) = env.get("bootentry") {
+            let hex = entry.trim_start_matches("0x").trim_start_matches("0X");
+            match usize::from_str_radix(hex, 16) {
+                Ok(entry) => self.entry = Some(entry),
+                Err(_) => warn!("bootentry {entry:?} is not an address, ignored"),
+            }
+        }
+        self
+    }
+
+    /// `a1` for a device tree at `dtb`.
+    pub fn opaque(&self, dtb: usize) -> usize {
+        match self.arguments {
+            Arguments::HartIdDtb => dtb,
+            Arguments::HartId => 0,
+        }
+    }
+}
+
+impl fmt::Display for NextStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.mode {
+            Mode::Supervisor => "S-mode",
+            Mode::Machine => "M-mode",
+        })?;
+        f.write_str(match self.arguments {
+            Arguments::HartIdDtb => ", a0 hart ID, a1 devicetree",
+            Arguments::HartId => ", a0 hart ID",
+        })
+    }
+}
+
+/// Jump to `entry` in M-mode with nothing delegated and interrupts off, never to return.
+pub unsafe fn enter_machine(entry: usize, opaque: usize) -> ! {
+    mstatus::update(|bits| *bits &= !mstatus::MIE);
+    mie::write(0);
+    asm!("csrw mideleg, zero", "csrw medeleg, zero");
+    fence_i();
+    asm!(
+        "jr {entry}",
+        entry = in(reg) entry,
+        in("a0") riscv::register::mhartid::read(),
+        in("a1") opaque,
+        options(noreturn),
+    )
+}
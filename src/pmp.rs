@@ -1,17 +1,133 @@
-use crate::println;
+use core::fmt;
+
+use crate::board::{FLASH_BASE, FLASH_SIZE};
+use crate::next_stage::Mode;
+use crate::riscv_spec::pma;
 use riscv::register::*;
+use spin::lock_api::Mutex;
+
+/// 启动 hart 上最后的配置：下一阶段的特权态和就地执行的窗口，供其他 hart 照搬。
+static BOOT_HART_CONFIG: Mutex<Option<(Mode, Option<core::ops::Range<usize>>)>> = Mutex::new(None);
 
-pub fn set_pmp() {
-    unsafe {
-        // 1. SDRAM
-        pmpcfg0::set_pmp(0, Range::OFF, Permission::NONE, false);
-        pmpaddr0::write((0x4000_0000) >> 2);
-        pmpcfg0::set_pmp(1, Range::TOR, Permission::RWX, false);
-        pmpaddr1::write(usize::MAX >> 2);
+/// SDRAM 的起始地址，S 态可以访问从这里开始的地址空间。
+const SDRAM_BASE: usize = 0x4000_0000;
+
+pub fn set_pmp(mode: Mode) {
+    *BOOT_HART_CONFIG.lock() = Some((mode, None));
+    // M 态的下一阶段不受 PMP 限制，由它自己配置
+    if mode == Mode::Machine {
+        return;
+    }
+    let mut regions = Regions::new();
+    // 1. SDRAM 及之后的地址空间
+    regions.add(SDRAM_BASE..usize::MAX, Permission::RWX);
+}
+
+/// 就地执行的内核：flash 窗口设为可缓存，S 态只能读取和执行 `window`，不能访问 flash 的其他部分。
+pub fn set_xip_window(mode: Mode, window: core::ops::Range<usize>) {
+    assert!(
+        FLASH_BASE <= window.start && window.start < window.end,
+        "XIP window {window:#x?} outside of flash"
+    );
+    assert!(
+        window.end <= FLASH_BASE + FLASH_SIZE,
+        "XIP window {window:#x?} outside of flash"
+    );
+    *BOOT_HART_CONFIG.lock() = Some((mode, Some(window.clone())));
+    pma::set_napot(FLASH_BASE, FLASH_SIZE, pma::MEM_WB_READ_ALLOC)
+        .expect("no PMA entry left for the XIP window");
+    if mode == Mode::Machine {
+        return;
     }
+    let mut regions = Regions::new();
+    // 1. SDRAM
+    regions.add(SDRAM_BASE..FLASH_BASE, Permission::RWX);
+    // 2. flash 中内核所在窗口
+    regions.add(window, Permission::RX);
+    // 3. 外设
+    regions.add(FLASH_BASE + FLASH_SIZE..usize::MAX, Permission::RWX);
+}
+
+/// 按顺序分配 PMP 表项：每段区域占用一对 TOR 表项，地址须递增且互不重叠。
+struct Regions {
+    next: usize,
+    end: usize,
 }
 
-pub(crate) fn print_pmps() {
+impl Regions {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            end: SDRAM_BASE,
+        }
+    }
+
+    /// S 态以权限 `perm` 访问 `range`，`usize::MAX` 结尾表示直到地址空间末尾。
+    fn add(&mut self, range: core::ops::Range<usize>, perm: Permission) {
+        assert!(
+            self.end <= range.start && range.start < range.end,
+            "PMP region {range:#x?} overlaps the one before"
+        );
+        assert!(
+            self.next + 2 <= PMP_ENTRIES,
+            "no PMP entry left for {range:#x?}"
+        );
+        let top = match range.end {
+            usize::MAX => usize::MAX >> 2,
+            end => end.div_ceil(4),
+        };
+        unsafe {
+            set_entry(self.next, Range::OFF, Permission::NONE, range.start >> 2);
+            set_entry(self.next + 1, Range::TOR, perm, top);
+        }
+        self.next += 2;
+        self.end = range.end;
+    }
+}
+
+const PMP_ENTRIES: usize = 16;
+
+unsafe fn set_entry(i: usize, range: Range, perm: Permission, addr: usize) {
+    match i / 4 {
+        0 => pmpcfg0::set_pmp(i % 4, range, perm, false),
+        1 => pmpcfg1::set_pmp(i % 4, range, perm, false),
+        2 => pmpcfg2::set_pmp(i % 4, range, perm, false),
+        3 => pmpcfg3::set_pmp(i % 4, range, perm, false),
+        _ => unreachable!(),
+    }
+    match i {
+        0x0 => pmpaddr0::write(addr),
+        0x1 => pmpaddr1::write(addr),
+        0x2 => pmpaddr2::write(addr),
+        0x3 => pmpaddr3::write(addr),
+        0x4 => pmpaddr4::write(addr),
+        0x5 => pmpaddr5::write(addr),
+        0x6 => pmpaddr6::write(addr),
+        0x7 => pmpaddr7::write(addr),
+        0x8 => pmpaddr8::write(addr),
+        0x9 => pmpaddr9::write(addr),
+        0xa => pmpaddr10::write(addr),
+        0xb => pmpaddr11::write(addr),
+        0xc => pmpaddr12::write(addr),
+        0xd => pmpaddr13::write(addr),
+        0xe => pmpaddr14::write(addr),
+        0xf => pmpaddr15::write(addr),
+        _ => unreachable!(),
+    }
+}
+
+/// 在此 hart 上应用启动 hart 的配置。
+pub fn replicate() {
+    let config = BOOT_HART_CONFIG.lock().clone();
+    match config {
+        Some((mode, None)) => set_pmp(mode),
+        Some((mode, Some(window))) => set_xip_window(mode, window),
+        None => {}
+    }
+}
+
+/// Describe each PMP entry in use to `out`.
+pub(crate) fn print_pmps(mut out: impl FnMut(fmt::Arguments)) {
     const ITEM_PER_CFG: usize = core::mem::size_of::<usize>();
     const CFG_STEP: usize = ITEM_PER_CFG / core::mem::size_of::<u32>();
 
@@ -23,6 +139,7 @@ pub(crate) fn print_pmps() {
             match (cfg >> 3) & 0b11 {
                 0b00 => {}
                 0b01 => dump_pmp(
+                    &mut out,
                     base + i_addr,
                     pmpaddr(base + i_addr - 1) << 2,
                     pmpaddr(base + i_addr) << 2,
@@ -30,14 +147,14 @@ pub(crate) fn print_pmps() {
                 ),
                 0b10 => {
                     let s = pmpaddr(base + i_addr);
-                    dump_pmp(base + i_addr, s << 2, (s + 1) << 2, cfg);
+                    dump_pmp(&mut out, base + i_addr, s << 2, (s + 1) << 2, cfg);
                 }
                 0b11 => {
                     let addr = pmpaddr(base + i_addr);
                     let len = 1usize << (addr.trailing_ones() + 2);
                     let s = (addr & !(len - 1)) << 2;
                     let e = s + len;
-                    dump_pmp(base + i_addr, s, e, cfg);
+                    dump_pmp(&mut out, base + i_addr, s, e, cfg);
                 }
                 _ => unreachable!(),
             };
@@ -48,13 +165,13 @@ pub(crate) fn print_pmps() {
 }
 
 #[inline]
-fn dump_pmp(i: usize, s: usize, e: usize, cfg: usize) {
-    println!(
-        "[rustsbi] pmp{i:02}: {s:#010x}..{e:#010x} ({}{}{})",
+fn dump_pmp(out: &mut impl FnMut(fmt::Arguments), i: usize, s: usize, e: usize, cfg: usize) {
+    out(format_args!(
+        "pmp{i:02}: {s:#010x}..{e:#010x} ({}{}{})",
         if cfg & 0b100 != 0 { "x" } else { "-" },
         if cfg & 0b010 != 0 { "w" } else { "-" },
         if cfg & 0b001 != 0 { "r" } else { "-" },
-    );
+    ));
 }
 
 fn pmpcfg(i: usize) -> usize {
@@ -0,0 +1,100 @@
+//! Boot-time profile: when each firmware stage started and how long it took.
+//!
+//! Times are taken from the machine timer, which counts from reset, so they line up with
+//! timestamps of the stages before and after the firmware. Unlike `mcycle`, it keeps its
+//! 24MHz rate while board init changes the CPU clock. The kernel reads them back through the
+//! vendor SBI extension.
+
+use core::fmt;
+
+use log::info;
+use spin::lock_api::Mutex;
+
+use crate::board;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// From reset through the boot ROM to `main`.
+    Startup,
+    BoardInit,
+    ClockFreeze,
+    SdramConfig,
+    PmpSetup,
+    KernelCopy,
+    InitrdCopy,
+    DtbFixup,
+    /// Up to jumping to the next stage, a point in time.
+    Handoff,
+}
+
+const STAGES: [Stage; 9] = [
+    Stage::Startup,
+    Stage::BoardInit,
+    Stage::ClockFreeze,
+    Stage::SdramConfig,
+    Stage::PmpSetup,
+    Stage::KernelCopy,
+    Stage::InitrdCopy,
+    Stage::DtbFixup,
+    Stage::Handoff,
+];
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Startup => "startup",
+            Stage::BoardInit => "board init",
+            Stage::ClockFreeze => "clock freeze",
+            Stage::SdramConfig => "SDRAM config",
+            Stage::PmpSetup => "PMP setup",
+            Stage::KernelCopy => "kernel copy",
+            Stage::InitrdCopy => "initrd copy",
+            Stage::DtbFixup => "DTB fixups",
+            Stage::Handoff => "handoff",
+        })
+    }
+}
+
+/// Start and end machine timer tick of each stage, `None` until it ran. A stage running
+/// twice, like a kernel copy retried on the other slot, adds up.
+static PROFILE: Mutex<[Option<(u64, u64)>; STAGES.len()]> = Mutex::new([None; STAGES.len()]);
+
+/// Run `f` as `stage`.
+pub fn measure<R>(stage: Stage, f: impl FnOnce() -> R) -> R {
+    let timer = board::board_init_timer();
+    let start = timer.time64();
+    let result = f();
+    let end = timer.time64();
+    let mut profile = PROFILE.lock();
+    let entry = &mut profile[stage as usize];
+    *entry = Some(match *entry {
+        Some((first, last)) => (first, last + end - start),
+        None => (start, end),
+    });
+    result
+}
+
+/// Mark the end of `stage`, which started at reset or, for the handoff, ends now.
+pub fn mark(stage: Stage) {
+    let now = board::board_init_timer().time64();
+    let start = match stage {
+        Stage::Handoff => now,
+        _ => 0,
+    };
+    PROFILE.lock()[stage as usize] = Some((start, now));
+}
+
+/// Start and duration of the stage with index `index` in microseconds, if it ran.
+pub fn get(index: usize) -> Option<(u64, u64)> {
+    let (start, end) = (*PROFILE.lock().get(index)?)?;
+    Some((board::ticks_to_us(start), board::ticks_to_us(end - start)))
+}
+
+pub fn print() {
+    info!("Boot Profile       : stage          start (us)   time (us)");
+    for (i, stage) in STAGES.iter().enumerate() {
+        if let Some((start, time)) = get(i) {
+            info!("                     {stage:<14} {start:>10} {time:>11}");
+        }
+    }
+}
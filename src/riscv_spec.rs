@@ -107,3 +107,138 @@ pub mod mdcause {
 pub unsafe fn fence_i() {
     core::arch::asm!("fence.i");
 }
+
+/// Andes cache maintenance CSRs.
+pub mod mcctl {
+    use core::arch::asm;
+
+    /// Data cache line size.
+    pub const CACHE_LINE_SIZE: usize = 64;
+
+    const L1D_VA_INVAL: usize = 0;
+    const L1D_VA_WBINVAL: usize = 2;
+    const L1D_WB_ALL: usize = 7;
+
+    /// Drop cached contents of `[addr, addr + len)`, losing what was not written back.
+    pub fn l1d_invalidate(addr: usize, len: usize) {
+        lines(addr, len, L1D_VA_INVAL);
+    }
+
+    /// Write back and drop cached contents of `[addr, addr + len)`.
+    pub fn l1d_writeback_invalidate(addr: usize, len: usize) {
+        lines(addr, len, L1D_VA_WBINVAL);
+    }
+
+    /// Write every dirty data cache line back to memory.
+    #[inline(always)]
+    pub fn l1d_writeback_all() {
+        unsafe { asm!("csrw 0x7cc, {}", in(reg) L1D_WB_ALL) };
+    }
+
+    fn lines(addr: usize, len: usize, command: usize) {
+        let mut line = addr & !(CACHE_LINE_SIZE - 1);
+        while line < addr + len {
+            command_line(line, command);
+            line += CACHE_LINE_SIZE;
+        }
+    }
+
+    #[inline(always)]
+    fn command_line(addr: usize, command: usize) {
+        unsafe {
+            // mcctlbeginaddr, mcctlcommand
+            asm!("csrw 0x7cb, {}", in(reg) addr, options(nomem));
+            asm!("csrw 0x7cc, {}", in(reg) command);
+        }
+    }
+}
+
+/// Andes physical memory attribute CSRs.
+pub mod pma {
+    use core::arch::asm;
+
+    /// Naturally aligned power-of-two region.
+    pub const NAPOT: usize = 3;
+    /// Memory that is not cached, with writes buffered.
+    pub const MEM_NON_CACHEABLE_BUFFERABLE: usize = 3;
+    /// Write-back memory, allocating on read misses.
+    pub const MEM_WB_READ_ALLOC: usize = 9;
+
+    /// Entries of the PMA unit.
+    const ENTRIES: usize = 16;
+
+    /// Give the power-of-two region at `base` memory type `mem_type`. Takes the entry
+    /// already holding the region, or else the last free one: the runtime allocates from
+    /// the first entry up. Returns the entry, `None` when all are in use.
+    pub fn set_napot(base: usize, size: usize, mem_type: usize) -> Option<usize> {
+        let addr = (base | (size / 2 - 1)) >> 2;
+        let kind = |i: usize| (read_cfg(i / 4) >> (i % 4 * 8)) & 0b11;
+        let entry = (0..ENTRIES)
+            .rev()
+            .find(|&i| kind(i) == NAPOT && read_addr(i) == addr)
+            .or_else(|| (0..ENTRIES).rev().find(|&i| kind(i) == 0))?;
+        let shift = entry % 4 * 8;
+        let cfg = (read_cfg(entry / 4) & !(0xff << shift)) | ((NAPOT | mem_type << 2) << shift);
+        write_addr(entry, addr);
+        write_cfg(entry / 4, cfg);
+        Some(entry)
+    }
+
+    /// `pmacfg0` to `pmacfg3` hold four entries each.
+    fn read_cfg(i: usize) -> usize {
+        let bits: usize;
+        unsafe {
+            match i {
+                0 => asm!("csrr {}, 0xbc0", out(reg) bits, options(nomem)),
+                1 => asm!("csrr {}, 0xbc1", out(reg) bits, options(nomem)),
+                2 => asm!("csrr {}, 0xbc2", out(reg) bits, options(nomem)),
+                3 => asm!("csrr {}, 0xbc3", out(reg) bits, options(nomem)),
+                _ => unreachable!(),
+            }
+        }
+        bits
+    }
+
+    fn write_cfg(i: usize, bits: usize) {
+        unsafe {
+            match i {
+                0 => asm!("csrw 0xbc0, {}", in(reg) bits, options(nomem)),
+                1 => asm!("csrw 0xbc1, {}", in(reg) bits, options(nomem)),
+                2 => asm!("csrw 0xbc2, {}", in(reg) bits, options(nomem)),
+                3 => asm!("csrw 0xbc3, {}", in(reg) bits, options(nomem)),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// `pmaaddr0` to `pmaaddr15`, one entry each.
+    macro_rules! pmaaddr {
+        ($($n:literal => $csr:literal),*) => {
+            fn read_addr(i: usize) -> usize {
+                let bits: usize;
+                unsafe {
+                    match i {
+                        $($n => asm!(concat!("csrr {}, ", $csr), out(reg) bits, options(nomem)),)*
+                        _ => unreachable!(),
+                    }
+                }
+                bits
+            }
+
+            fn write_addr(i: usize, bits: usize) {
+                unsafe {
+                    match i {
+                        $($n => asm!(concat!("csrw ", $csr, ", {}"), in(reg) bits, options(nomem)),)*
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        };
+    }
+
+    pmaaddr!(
+        0 => "0xbd0", 1 => "0xbd1", 2 => "0xbd2", 3 => "0xbd3", 4 => "0xbd4", 5 => "0xbd5",
+        6 => "0xbd6", 7 => "0xbd7", 8 => "0xbd8", 9 => "0xbd9", 10 => "0xbda", 11 => "0xbdb",
+        12 => "0xbdc", 13 => "0xbdd", 14 => "0xbde", 15 => "0xbdf"
+    );
+}
@@ -6,16 +6,24 @@ use riscv::register::{
 use riscv_decode::{decode, Instruction};
 use rustsbi::RustSBI;
 
-use crate::extension::SBI;
-use crate::local_hsm;
+use spin::lock_api::Mutex;
+
+use crate::board::{self, NUM_HARTS};
+use crate::extension::{self, SBI};
 use crate::riscv_spec::*;
-use crate::{board, print};
+use crate::trap_stack::{local_hsm, wait_for_start};
+use crate::{print, vendor};
 
-static mut S_LR_ADDR: usize = 0;
+/// 各 hart 的 lr 保留地址，0 表示没有保留。
+static mut S_LR_ADDR: [usize; NUM_HARTS] = [0; NUM_HARTS];
 /// `csrrw zero, time, zero`
 const BKPT_INST: usize = 0xc0101073;
-static mut BKPT_INST_ADDR: usize = 0;
-static mut BKPT_RESERVED_INST: usize = 0;
+/// 各 hart 为模拟 sc 设置的断点地址，0 表示没有断点。
+static mut BKPT_INST_ADDR: [usize; NUM_HARTS] = [0; NUM_HARTS];
+/// 各 hart 断点处原来的指令。多个 hart 可能在同一处设置断点，由最后一个清除的恢复。
+static mut BKPT_RESERVED_INST: [usize; NUM_HARTS] = [0; NUM_HARTS];
+/// 原子指令模拟和断点在各 hart 之间互斥。
+static EMULATION: Mutex<()> = Mutex::new(());
 
 macro_rules! amo {
     ($ctx:expr, $inst:ident, $operation:expr) => {{
@@ -26,9 +34,19 @@ macro_rules! amo {
             write_register($ctx, $inst.rd(), a);
         }
         *(tmp as *mut _) = $operation(a, b);
+        cancel_reservations(tmp);
     }};
 }
 
+/// 写入 `addr` 使所有 hart 对它的保留失效。
+unsafe fn cancel_reservations(addr: usize) {
+    for reserved in S_LR_ADDR.iter_mut() {
+        if *reserved == addr {
+            *reserved = 0;
+        }
+    }
+}
+
 #[inline]
 fn boot(mut ctx: FastContext, start_addr: usize, opaque: usize) -> FastResult {
     unsafe {
@@ -80,8 +98,10 @@ fn illegal_instruction_handler(mut ctx: FastContext) -> Result<FastResult, FastC
             _ => return Err(ctx),
         },
         Ok(Instruction::Csrrw(csr)) => unsafe {
-            if csr.csr() as usize == CSR_TIME && mepc::read() == BKPT_INST_ADDR {
-                clear_breakpoint();
+            let _guard = EMULATION.lock();
+            // 断点也可能是别的 hart 设置的，同样模拟原来的 sc
+            if csr.csr() as usize == CSR_TIME && breakpoint(mepc::read()).is_some() {
+                clear_breakpoint(riscv::register::mhartid::read());
                 return Ok(ctx.continue_with(atomic_emulation_wrapper, ()));
             } else {
                 return Err(ctx);
@@ -96,7 +116,7 @@ fn illegal_instruction_handler(mut ctx: FastContext) -> Result<FastResult, FastC
 unsafe fn find_next_sc(addr: usize) -> Result<usize, ()> {
     let mut addr = addr;
     for _ in 0..16 {
-        let inst = (addr as *const u32).read();
+        let inst = read_inst(addr);
         if let Ok(Instruction::ScW(_)) = decode(inst) {
             return Ok(addr);
         } else if (inst & 0xFF) != 0b11 {
@@ -109,20 +129,41 @@ unsafe fn find_next_sc(addr: usize) -> Result<usize, ()> {
     Err(())
 }
 
-unsafe fn set_breakpoint(addr: usize) {
-    let addr = addr as *mut usize;
-    BKPT_RESERVED_INST = addr.read();
-    BKPT_INST_ADDR = addr as usize;
-    *addr = BKPT_INST;
+/// 设置在 `addr` 的断点处原来的指令。
+unsafe fn breakpoint(addr: usize) -> Option<usize> {
+    let hart = BKPT_INST_ADDR
+        .iter()
+        .position(|&bkpt| bkpt == addr && addr != 0)?;
+    Some(BKPT_RESERVED_INST[hart])
+}
+
+/// 读取 `addr` 处的指令，跳过断点。
+unsafe fn read_inst(addr: usize) -> u32 {
+    match breakpoint(addr) {
+        Some(inst) => inst as u32,
+        None => (addr as *const u32).read_unaligned(),
+    }
+}
+
+unsafe fn set_breakpoint(hart: usize, addr: usize) {
+    let reserved = match breakpoint(addr) {
+        Some(inst) => inst,
+        None => (addr as *const usize).read(),
+    };
+    BKPT_RESERVED_INST[hart] = reserved;
+    BKPT_INST_ADDR[hart] = addr;
+    *(addr as *mut usize) = BKPT_INST;
     fence_i();
 }
 
-unsafe fn clear_breakpoint() {
-    if BKPT_INST_ADDR != 0 {
-        let addr = BKPT_INST_ADDR as *mut usize;
-        *addr = BKPT_RESERVED_INST;
-        BKPT_INST_ADDR = 0;
-        fence_i();
+unsafe fn clear_breakpoint(hart: usize) {
+    let addr = BKPT_INST_ADDR[hart];
+    if addr != 0 {
+        BKPT_INST_ADDR[hart] = 0;
+        if breakpoint(addr).is_none() {
+            *(addr as *mut usize) = BKPT_RESERVED_INST[hart];
+            fence_i();
+        }
     }
 }
 
@@ -178,34 +219,36 @@ fn read_register(ctx: &mut EntireContextSeparated, r: u32) -> usize {
 }
 
 unsafe fn atomic_emulation(mut ctx: EntireContextSeparated) -> EntireResult {
-    let inst = (mepc::read() as *const u32).read_unaligned();
+    let _guard = EMULATION.lock();
+    let hart = riscv::register::mhartid::read();
+    let inst = read_inst(mepc::read());
     let decoded_inst = decode(inst);
     match decoded_inst {
         Ok(Instruction::LrW(lr)) => {
             let rs1 = lr.rs1();
             let rd = lr.rd();
-            S_LR_ADDR = read_register(&mut ctx, rs1);
-            let tmp: usize = *(S_LR_ADDR as *const _);
+            S_LR_ADDR[hart] = read_register(&mut ctx, rs1);
+            let tmp: usize = *(S_LR_ADDR[hart] as *const _);
             write_register(&mut ctx, rd, tmp);
 
             // Clear old breakpoint and set a new one
-            clear_breakpoint();
+            clear_breakpoint(hart);
             let sc_inst_addr = find_next_sc(mepc::read()).unwrap_or_else(|_| {
                 panic!("[rustsbi] unable to find matching sc instruction");
             });
-            set_breakpoint(sc_inst_addr);
+            set_breakpoint(hart, sc_inst_addr);
         }
         Ok(Instruction::ScW(sc)) => {
             let rs1 = sc.rs1();
             let rs2 = sc.rs2();
             let rd = sc.rd();
             let tmp: usize = read_register(&mut ctx, rs1);
-            if tmp != S_LR_ADDR {
+            if tmp != S_LR_ADDR[hart] {
                 write_register(&mut ctx, rd, 1);
             } else {
-                *(S_LR_ADDR as *mut _) = read_register(&mut ctx, rs2);
+                *(tmp as *mut _) = read_register(&mut ctx, rs2);
                 write_register(&mut ctx, rd, 0);
-                S_LR_ADDR = 0;
+                cancel_reservations(tmp);
             }
         }
         Ok(Instruction::AmoswapW(amo)) => {
@@ -268,7 +311,7 @@ pub extern "C" fn fast_handler(
                     *bits &= !mstatus::MPP;
                     *bits |= mstatus::MPIE | mstatus::MPP_SUPERVISOR;
                 });
-                mie::write(mie::MSIE | mie::MTIE);
+                mie::write(mie::MSIE | mie::MTIE | mie::MEIE);
                 break boot(ctx, supervisor.start_addr, supervisor.opaque);
             }
             _ => match mcause::read().cause() {
@@ -278,19 +321,24 @@ pub extern "C" fn fast_handler(
                     let mut ret = SBI.handle_ecall(a7, a6, [ctx.a0(), a1, a2, a3, a4, a5]);
                     if ret.is_ok() {
                         match (a7, a6) {
-                            // 关闭
-                            (hsm::EID_HSM, hsm::HART_STOP) => continue,
+                            // 关闭，等待再次启动
+                            (hsm::EID_HSM, hsm::HART_STOP) => {
+                                wait_for_start();
+                                continue;
+                            }
                             // 不可恢复挂起
                             (hsm::EID_HSM, hsm::HART_SUSPEND)
                                 if matches!(ctx.a0() as u32, hsm::suspend_type::NON_RETENTIVE) =>
                             {
                                 break boot(ctx, a1, a2);
                             }
-                            // legacy console 探测
+                            // legacy console 与厂商拓展探测
                             (base::EID_BASE, base::PROBE_EXTENSION)
                                 if matches!(
                                     ctx.a0(),
-                                    legacy::LEGACY_CONSOLE_PUTCHAR | legacy::LEGACY_CONSOLE_GETCHAR
+                                    legacy::LEGACY_CONSOLE_PUTCHAR
+                                        | legacy::LEGACY_CONSOLE_GETCHAR
+                                        | vendor::EID_HPM
                                 ) =>
                             {
                                 ret.value = 1;
@@ -308,6 +356,9 @@ pub extern "C" fn fast_handler(
                                 ret.error = board::getchar();
                                 ret.value = a1;
                             }
+                            vendor::EID_HPM => {
+                                ret = vendor::handle_ecall(a6, [ctx.a0(), a1, a2, a3, a4, a5]);
+                            }
                             _ => unimplemented!(
                                 "EID: {:#010x} FID: {:#010x} is not implemented!",
                                 a7,
@@ -337,6 +388,18 @@ pub extern "C" fn fast_handler(
                     ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
                     break ctx.continue_with(atomic_emulation_wrapper, ());
                 }
+                // 其他 hart 发来的 IPI 和远程 fence
+                T::Interrupt(I::MachineSoft) => {
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    extension::handle_ipi();
+                    break ctx.restore();
+                }
+                // 控制台串口等 M 态自己处理的外部中断
+                T::Interrupt(I::MachineExternal) => {
+                    ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
+                    board::handle_external_interrupt();
+                    break ctx.restore();
+                }
                 T::Interrupt(I::MachineTimer) => {
                     ctx.regs().a = [ctx.a0(), a1, a2, a3, a4, a5, a6, a7];
                     SBI.timer.set_timecmp(u64::MAX);
@@ -1,21 +1,76 @@
-use core::cell::{RefCell, UnsafeCell};
+use core::arch::asm;
 use core::mem::forget;
 use core::ptr::NonNull;
 
 use fast_trap::{FlowContext, FreeTrapStack};
+use riscv::register::mhartid;
+use spin::lock_api::Mutex;
 
+use crate::board::{self, NUM_HARTS};
 use crate::trap::fast_handler;
 use crate::{constants::LEN_STACK_PER_HART, Supervisor};
 
-static mut ROOT_STACK: Stack = Stack::ZERO;
+/// 每个 hart 一个栈，栈底存放其硬件线程状态。
+static mut ROOT_STACK: [Stack; NUM_HARTS] = [Stack::ZERO; NUM_HARTS];
 
+/// 初始化所有 hart 的状态，由启动 hart 在释放其他 hart 之前调用。
+pub(crate) fn init() {
+    unsafe {
+        for stack in ROOT_STACK.iter_mut() {
+            stack.hart_context_mut().init();
+        }
+    }
+}
+
+/// 在此 hart 的栈上准备陷入处理。
 pub(crate) fn prepare_for_trap() {
-    unsafe { ROOT_STACK.load_as_stack() };
+    unsafe { ROOT_STACK[mhartid::read()].load_as_stack() };
 }
 
 /// 获取此 hart 的 local hsm 对象。
 pub(crate) fn local_hsm() -> &'static HsmCell<Supervisor> {
-    unsafe { &ROOT_STACK.hart_context().hsm }
+    remote_hsm(mhartid::read()).unwrap()
+}
+
+/// 获取 `hartid` 的 hsm 对象，hart 不存在时返回 `None`。
+pub(crate) fn remote_hsm(hartid: usize) -> Option<&'static HsmCell<Supervisor>> {
+    unsafe {
+        ROOT_STACK
+            .get(hartid)
+            .map(|stack| &stack.hart_context().hsm)
+    }
+}
+
+/// 在 WFI 中等待此 hart 被 `hart_start` 启动。
+pub(crate) fn wait_for_start() {
+    let hartid = mhartid::read();
+    while local_hsm().status() != HartState::StartPending {
+        unsafe { riscv::asm::wfi() };
+        board::clear_ipi(hartid);
+    }
+}
+
+/// 其他 hart 的入口：切换到自己的栈，进入 `secondary_main`。
+#[naked]
+pub(crate) unsafe extern "C" fn secondary_entry() -> ! {
+    asm!(
+        ".option push",
+        ".option norelax",
+        "la   gp, __global_pointer$",
+        ".option pop",
+        // sp = ROOT_STACK[hartid] 的栈顶
+        "csrr a0, mhartid",
+        "addi t0, a0, 1",
+        "li   t1, {len}",
+        "mul  t0, t0, t1",
+        "la   sp, {stack}",
+        "add  sp, sp, t0",
+        "j    {main}",
+        len = const LEN_STACK_PER_HART,
+        stack = sym ROOT_STACK,
+        main = sym crate::secondary_main,
+        options(noreturn)
+    )
 }
 
 struct Stack([u8; LEN_STACK_PER_HART]);
@@ -26,14 +81,17 @@ impl Stack {
 
     /// 从栈上取出硬件线程状态。
     #[inline]
-    fn hart_context(&mut self) -> &mut HartContext {
+    fn hart_context(&self) -> &HartContext {
+        unsafe { &*self.0.as_ptr().cast() }
+    }
+
+    #[inline]
+    fn hart_context_mut(&mut self) -> &mut HartContext {
         unsafe { &mut *self.0.as_mut_ptr().cast() }
     }
 
     fn load_as_stack(&'static mut self) {
-        let hart = self.hart_context();
-        let context_ptr = hart.context_ptr();
-        hart.init();
+        let context_ptr = self.hart_context_mut().context_ptr();
         let range = self.0.as_ptr_range();
         forget(
             FreeTrapStack::new(
@@ -48,8 +106,9 @@ impl Stack {
     }
 }
 
+/// 与 SBI HSM 扩展的状态编号一致。
 #[allow(unused)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum HartState {
     Started,
     Stopped,
@@ -60,35 +119,54 @@ pub enum HartState {
     ResumePending,
 }
 
+/// hart 的状态和启动参数，可由其他 hart 访问。
 pub(crate) struct HsmCell<T> {
-    status: RefCell<HartState>,
-    inner: UnsafeCell<Option<T>>,
+    inner: Mutex<(HartState, Option<T>)>,
 }
 
 impl<T> HsmCell<T> {
     fn new() -> Self {
         Self {
-            status: RefCell::new(HartState::Stopped),
-            inner: UnsafeCell::new(None),
+            inner: Mutex::new((HartState::Stopped, None)),
         }
     }
 
+    /// 此 hart 取出启动参数，进入已启动状态。
     pub fn start(&self) -> Result<T, HartState> {
-        let status = *self.status.borrow();
-        match status {
+        let mut inner = self.inner.lock();
+        match inner.0 {
             HartState::StartPending => {
-                *self.status.borrow_mut() = HartState::Started;
-                Ok(unsafe { self.inner.get().as_mut().unwrap() }
-                    .take()
-                    .unwrap())
+                inner.0 = HartState::Started;
+                Ok(inner.1.take().unwrap())
             }
-            _ => Err(*self.status.borrow()),
+            status => Err(status),
         }
     }
 
+    /// 此 hart 准备以 `v` 启动。
     pub fn prepare(&self, v: T) {
-        *self.status.borrow_mut() = HartState::StartPending;
-        unsafe { self.inner.get().as_mut().unwrap() }.replace(v);
+        *self.inner.lock() = (HartState::StartPending, Some(v));
+    }
+
+    /// 其他 hart 请求以 `v` 启动已停止的此 hart。
+    pub fn remote_start(&self, v: T) -> Result<(), HartState> {
+        let mut inner = self.inner.lock();
+        match inner.0 {
+            HartState::Stopped => {
+                *inner = (HartState::StartPending, Some(v));
+                Ok(())
+            }
+            status => Err(status),
+        }
+    }
+
+    /// 此 hart 停止。
+    pub fn stop(&self) {
+        self.inner.lock().0 = HartState::Stopped;
+    }
+
+    pub fn status(&self) -> HartState {
+        self.inner.lock().0
     }
 }
 
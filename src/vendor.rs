@@ -0,0 +1,67 @@
+//! RustSBI-HPM vendor SBI extension.
+
+use log::warn;
+use sbi_spec::binary::SbiRet;
+
+use crate::board;
+use crate::loader::{self, Slot};
+use crate::{logger, profile};
+
+/// Extension ID: the vendor range `0x09`, followed by "HPM".
+pub const EID_HPM: usize = 0x0948_504D;
+
+/// Mark the running boot as successful, stopping the slot fallback countdown.
+const MARK_BOOT_SUCCESSFUL: usize = 0;
+/// Boot the slot in `a0` (0 for A, 1 for B) from the next reset, on trial. Fails with
+/// `SBI_ERR_INVALID_PARAM` when the slot holds no kernel.
+const SET_ACTIVE_SLOT: usize = 1;
+/// Microseconds from reset to the start of firmware boot stage `a0`.
+const BOOT_STAGE_START: usize = 2;
+/// Microseconds firmware boot stage `a0` took.
+const BOOT_STAGE_TIME: usize = 3;
+/// Console input lost so far, by kind `a0`: 0 for overruns of the UART FIFO, 1 to 3
+/// for parity, framing and break errors, 4 for bytes dropped on a full receive buffer.
+const CONSOLE_ERRORS: usize = 4;
+/// Set the firmware log level to `a0`, 0 for off and 1 for errors through 5 for trace,
+/// returning the level before.
+const SET_LOG_LEVEL: usize = 5;
+
+pub fn handle_ecall(fid: usize, param: [usize; 6]) -> SbiRet {
+    let result = match fid {
+        MARK_BOOT_SUCCESSFUL => loader::mark_boot_successful(),
+        SET_ACTIVE_SLOT => match Slot::from_index(param[0]) {
+            Some(slot) => match loader::check_slot(slot) {
+                Ok(()) => loader::set_active_slot(slot),
+                Err(e) => {
+                    warn!("Not activating slot {slot}: {e}");
+                    return SbiRet::invalid_param();
+                }
+            },
+            None => return SbiRet::invalid_param(),
+        },
+        BOOT_STAGE_START | BOOT_STAGE_TIME => {
+            return match profile::get(param[0]) {
+                Some((start, _)) if fid == BOOT_STAGE_START => SbiRet::success(start as usize),
+                Some((_, time)) => SbiRet::success(time as usize),
+                None => SbiRet::invalid_param(),
+            }
+        }
+        CONSOLE_ERRORS => {
+            return match board::console_error_count(param[0]) {
+                Some(count) => SbiRet::success(count as usize),
+                None => SbiRet::invalid_param(),
+            }
+        }
+        SET_LOG_LEVEL => {
+            return match logger::level_from_index(param[0]) {
+                Some(level) => SbiRet::success(logger::set_level(level) as usize),
+                None => SbiRet::invalid_param(),
+            }
+        }
+        _ => return SbiRet::not_supported(),
+    };
+    match result {
+        Ok(()) => SbiRet::success(0),
+        Err(_) => SbiRet::failed(),
+    }
+}
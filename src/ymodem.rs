@@ -0,0 +1,715 @@
+//! YMODEM and XMODEM-1K/CRC receiver.
+//!
+//! Receives one file into a buffer. The sender's first block tells the protocols apart:
+//! YMODEM starts with a block 0 carrying the file name and size, XMODEM directly with
+//! data block 1. The transport is behind [`Port`], so the protocol can be run on the host
+//! against `sz`.
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Sent instead of NAK to ask for CRC-16 blocks.
+const CRC_REQUEST: u8 = b'C';
+
+/// Times the receiver asks the sender to start, one second apart.
+const START_RETRIES: u32 = 60;
+/// Bad or missing blocks tolerated before giving up.
+const MAX_ERRORS: u32 = 10;
+const BYTE_TIMEOUT_MS: u32 = 1000;
+/// How long the sender may pause between blocks.
+const BLOCK_TIMEOUT_MS: u32 = 10_000;
+
+/// Byte transport to the sender.
+pub trait Port {
+    /// The next byte, or `None` when none arrives within `timeout_ms`.
+    fn read(&mut self, timeout_ms: u32) -> Option<u8>;
+    fn write(&mut self, byte: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The sender never started, or stopped sending.
+    Timeout,
+    /// The sender cancelled the transfer, or sent no file.
+    Cancelled,
+    /// Too many corrupt blocks in a row.
+    TooManyErrors,
+    /// A block arrived out of sequence.
+    OutOfSequence,
+    /// The file does not fit into the buffer.
+    TooLarge,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Timeout => "timed out",
+            Error::Cancelled => "cancelled",
+            Error::TooManyErrors => "too many errors",
+            Error::OutOfSequence => "block out of sequence",
+            Error::TooLarge => "file too large",
+        })
+    }
+}
+
+pub struct Received {
+    /// File size from the YMODEM header, or everything received for XMODEM, which
+    /// includes the padding of the last block.
+    pub size: usize,
+    name: [u8; 64],
+    name_len: usize,
+}
+
+impl Received {
+    /// File name from the YMODEM header.
+    pub fn name(&self) -> Option<&str> {
+        match self.name_len {
+            0 => None,
+            len => core::str::from_utf8(&self.name[..len]).ok(),
+        }
+    }
+}
+
+enum Packet<'a> {
+    Block(u8, &'a [u8]),
+    Eot,
+    Cancel,
+}
+
+enum BadPacket {
+    Timeout,
+    Corrupt,
+}
+
+/// Receive a file into `dst`.
+pub fn receive(port: &mut impl Port, dst: &mut [u8]) -> Result<Received, Error> {
+    let mut buf = [0u8; 1024];
+    let mut received = Received {
+        size: 0,
+        name: [0; 64],
+        name_len: 0,
+    };
+    let mut ymodem = false;
+    let mut header_size = None;
+    let mut started = false;
+    let mut expected: u8 = 0;
+    let mut offset = 0;
+    let mut errors = 0;
+    let mut eots = 0;
+
+    port.write(CRC_REQUEST);
+    loop {
+        let timeout = if started { BLOCK_TIMEOUT_MS } else { 1000 };
+        match read_packet(port, &mut buf, timeout) {
+            Ok(Packet::Block(0, data)) if !started => {
+                // YMODEM header; an empty one ends a batch without a file
+                if data[0] == 0 {
+                    port.write(ACK);
+                    return Err(Error::Cancelled);
+                }
+                let size = parse_header(data, &mut received);
+                if size.is_some_and(|size| size > dst.len()) {
+                    cancel(port);
+                    return Err(Error::TooLarge);
+                }
+                header_size = size;
+                ymodem = true;
+                started = true;
+                expected = 1;
+                port.write(ACK);
+                port.write(CRC_REQUEST);
+            }
+            Ok(Packet::Block(seq, data)) if seq == expected || (!started && seq == 1) => {
+                started = true;
+                // Only what the header announced has to fit, the rest is padding
+                let wanted = header_size.map_or(data.len(), |size| {
+                    size.saturating_sub(offset).min(data.len())
+                });
+                let Some(dst) = dst.get_mut(offset..offset + wanted) else {
+                    cancel(port);
+                    return Err(Error::TooLarge);
+                };
+                dst.copy_from_slice(&data[..wanted]);
+                offset += wanted;
+                expected = seq.wrapping_add(1);
+                errors = 0;
+                port.write(ACK);
+            }
+            // Our ACK got lost, the sender repeats the block
+            Ok(Packet::Block(seq, _)) if started && seq == expected.wrapping_sub(1) => {
+                port.write(ACK);
+            }
+            Ok(Packet::Block(..)) => {
+                cancel(port);
+                return Err(Error::OutOfSequence);
+            }
+            // NAK the first EOT, in case it was line noise
+            Ok(Packet::Eot) if started && eots == 0 => {
+                eots += 1;
+                port.write(NAK);
+            }
+            Ok(Packet::Eot) if started => {
+                port.write(ACK);
+                break;
+            }
+            Ok(Packet::Eot) | Ok(Packet::Cancel) => return Err(Error::Cancelled),
+            Err(BadPacket::Timeout) if !started => {
+                errors += 1;
+                if errors >= START_RETRIES {
+                    cancel(port);
+                    return Err(Error::Timeout);
+                }
+                port.write(CRC_REQUEST);
+            }
+            Err(_) => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(port);
+                    return Err(Error::TooManyErrors);
+                }
+                purge(port);
+                port.write(NAK);
+            }
+        }
+    }
+
+    // YMODEM: acknowledge the empty header that closes the batch
+    if ymodem {
+        port.write(CRC_REQUEST);
+        if let Ok(Packet::Block(0, _)) = read_packet(port, &mut buf, BLOCK_TIMEOUT_MS) {
+            port.write(ACK);
+        }
+    }
+    received.size = header_size.unwrap_or(offset);
+    Ok(received)
+}
+
+fn read_packet<'a>(
+    port: &mut impl Port,
+    buf: &'a mut [u8; 1024],
+    timeout_ms: u32,
+) -> Result<Packet<'a>, BadPacket> {
+    let len = match port.read(timeout_ms).ok_or(BadPacket::Timeout)? {
+        SOH => 128,
+        STX => 1024,
+        EOT => return Ok(Packet::Eot),
+        // Two in a row, so a single corrupted byte does not end the transfer
+        CAN => match port.read(BYTE_TIMEOUT_MS) {
+            Some(CAN) => return Ok(Packet::Cancel),
+            _ => return Err(BadPacket::Corrupt),
+        },
+        _ => return Err(BadPacket::Corrupt),
+    };
+    let mut byte = || port.read(BYTE_TIMEOUT_MS).ok_or(BadPacket::Timeout);
+    let seq = byte()?;
+    let seq_complement = byte()?;
+    for b in buf[..len].iter_mut() {
+        *b = byte()?;
+    }
+    let crc = u16::from_be_bytes([byte()?, byte()?]);
+    if seq != !seq_complement || crc != crc16(&buf[..len]) {
+        return Err(BadPacket::Corrupt);
+    }
+    Ok(Packet::Block(seq, &buf[..len]))
+}
+
+/// Parse `name\0size ...` from block 0 into `received`, returning the size if given.
+fn parse_header(data: &[u8], received: &mut Received) -> Option<usize> {
+    let name_end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let name = &data[..name_end];
+    received.name_len = name.len().min(received.name.len());
+    received.name[..received.name_len].copy_from_slice(&name[..received.name_len]);
+
+    let fields = data.get(name_end + 1..)?;
+    let size_end = fields
+        .iter()
+        .position(|b| !b.is_ascii_digit())
+        .unwrap_or(fields.len());
+    core::str::from_utf8(&fields[..size_end]).ok()?.parse().ok()
+}
+
+/// Drop whatever is still coming in, so the next block starts in sync.
+fn purge(port: &mut impl Port) {
+    while port.read(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+fn cancel(port: &mut impl Port) {
+    for _ in 0..2 {
+        port.write(CAN);
+    }
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Padding `sz` puts after the end of the file, CP/M end of file.
+    const CPMEOF: u8 = 0x1A;
+
+    /// One exchange of a transfer: once the receiver has written `expect`, the sender
+    /// answers with `send`. Reads time out while the receiver is not where expected.
+    struct Transcript {
+        steps: VecDeque<(Vec<u8>, Vec<u8>)>,
+        written: Vec<u8>,
+        pending: VecDeque<u8>,
+    }
+
+    impl Transcript {
+        fn new(steps: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+            Self {
+                steps: steps.into(),
+                written: Vec::new(),
+                pending: VecDeque::new(),
+            }
+        }
+
+        /// Check that the receiver went through every step, answering the last one.
+        fn finish(self) {
+            let rest: Vec<_> = self.steps.iter().map(|(expect, _)| expect).collect();
+            assert!(self.pending.is_empty(), "unread: {:02x?}", self.pending);
+            assert_eq!(rest, [&self.written], "steps left over");
+        }
+    }
+
+    impl Port for Transcript {
+        fn read(&mut self, _timeout_ms: u32) -> Option<u8> {
+            if self.pending.is_empty() && self.steps.front()?.0 == self.written {
+                let (_, send) = self.steps.pop_front().unwrap();
+                self.written.clear();
+                self.pending.extend(send);
+            }
+            self.pending.pop_front()
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.written.push(byte);
+        }
+    }
+
+    /// A framed block, with `data` padded to 128 or 1024 bytes.
+    fn packet(seq: u8, data: &[u8], pad: u8) -> Vec<u8> {
+        let len = if data.len() <= 128 { 128 } else { 1024 };
+        let mut payload = data.to_vec();
+        payload.resize(len, pad);
+        let mut packet = vec![if len == 128 { SOH } else { STX }, seq, !seq];
+        packet.extend(&payload);
+        packet.extend(crc16(&payload).to_be_bytes());
+        packet
+    }
+
+    /// Block 0 as `sz` sends it: name, then size, mtime and mode.
+    fn header(name: &str, size: usize) -> Vec<u8> {
+        let info = format!("{name}\0{size} 14671577320 100644 0 1 {size}");
+        packet(0, info.as_bytes(), 0)
+    }
+
+    /// Data blocks as `sz` sends them: 1K blocks, and a 128 byte one for a short tail.
+    fn blocks(file: &[u8]) -> Vec<Vec<u8>> {
+        file.chunks(1024)
+            .zip(1u8..)
+            .map(|(chunk, seq)| packet(seq, chunk, CPMEOF))
+            .collect()
+    }
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    /// The end of a transfer: the first EOT is NAKed in case it was noise.
+    fn eot(ymodem: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut steps = vec![(vec![NAK], vec![EOT])];
+        if ymodem {
+            steps.push((vec![ACK, CRC_REQUEST], packet(0, &[], 0)));
+        }
+        steps.push((vec![ACK], vec![]));
+        steps
+    }
+
+    #[test]
+    fn crc16_check() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn ymodem_batch() {
+        let file = file(2100);
+        let blocks = blocks(&file);
+        assert_eq!(blocks[2][0], SOH);
+        let mut steps = vec![
+            (vec![CRC_REQUEST], header("Image", file.len())),
+            (vec![ACK, CRC_REQUEST], blocks[0].clone()),
+            (vec![ACK], blocks[1].clone()),
+            (vec![ACK], blocks[2].clone()),
+            (vec![ACK], vec![EOT]),
+        ];
+        steps.extend(eot(true));
+        let mut port = Transcript::new(steps);
+        let mut dst = [0u8; 4096];
+
+        let received = receive(&mut port, &mut dst).unwrap();
+        port.finish();
+        assert_eq!(received.size, file.len());
+        assert_eq!(received.name(), Some("Image"));
+        assert_eq!(&dst[..file.len()], file);
+        // Padding of the last block stays out of the buffer
+        assert!(dst[file.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn retransmit() {
+        let file = file(3000);
+        let blocks = blocks(&file);
+        let mut corrupt = blocks[1].clone();
+        corrupt[100] ^= 0x40;
+        let mut steps = vec![
+            (vec![CRC_REQUEST], header("Image", file.len())),
+            (vec![ACK, CRC_REQUEST], blocks[0].clone()),
+            // Block 2 arrives corrupted and is asked for again
+            (vec![ACK], corrupt),
+            (vec![NAK], blocks[1].clone()),
+            // The sender missed our ACK and repeats block 2
+            (vec![ACK], blocks[1].clone()),
+            (vec![ACK], blocks[2].clone()),
+            (vec![ACK], vec![EOT]),
+        ];
+        steps.extend(eot(true));
+        let mut port = Transcript::new(steps);
+        let mut dst = [0u8; 4096];
+
+        let received = receive(&mut port, &mut dst).unwrap();
+        port.finish();
+        assert_eq!(received.size, file.len());
+        assert_eq!(&dst[..file.len()], file);
+    }
+
+    #[test]
+    fn xmodem() {
+        let file = file(100);
+        let mut steps = vec![
+            (vec![CRC_REQUEST], blocks(&file)[0].clone()),
+            (vec![ACK], vec![EOT]),
+        ];
+        steps.extend(eot(false));
+        let mut port = Transcript::new(steps);
+        let mut dst = [0u8; 4096];
+
+        let received = receive(&mut port, &mut dst).unwrap();
+        port.finish();
+        // Without a header, the padding counts as data
+        assert_eq!(received.size, 128);
+        assert_eq!(received.name(), None);
+        assert_eq!(&dst[..100], file);
+        assert!(dst[100..128].iter().all(|&b| b == CPMEOF));
+    }
+
+    #[test]
+    fn empty_batch() {
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], packet(0, &[], 0)),
+            (vec![ACK], vec![]),
+        ]);
+        assert_eq!(
+            receive(&mut port, &mut [0; 16]).err(),
+            Some(Error::Cancelled)
+        );
+        port.finish();
+    }
+
+    #[test]
+    fn sender_cancels() {
+        let file = file(2048);
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], header("Image", file.len())),
+            (vec![ACK, CRC_REQUEST], blocks(&file)[0].clone()),
+            (vec![ACK], vec![CAN, CAN]),
+            (vec![], vec![]),
+        ]);
+        let mut dst = [0u8; 4096];
+        assert_eq!(receive(&mut port, &mut dst).err(), Some(Error::Cancelled));
+        port.finish();
+    }
+
+    #[test]
+    fn too_large() {
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], header("Image", 4097)),
+            (vec![CAN, CAN], vec![]),
+        ]);
+        let mut dst = [0u8; 4096];
+        assert_eq!(receive(&mut port, &mut dst).err(), Some(Error::TooLarge));
+        port.finish();
+
+        // XMODEM has no size up front, the block that does not fit ends it
+        let file = file(2048);
+        let blocks = blocks(&file);
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], blocks[0].clone()),
+            (vec![ACK], blocks[1].clone()),
+            (vec![CAN, CAN], vec![]),
+        ]);
+        let mut dst = [0u8; 1500];
+        assert_eq!(receive(&mut port, &mut dst).err(), Some(Error::TooLarge));
+        port.finish();
+    }
+
+    #[test]
+    fn out_of_sequence() {
+        let file = file(4096);
+        let blocks = blocks(&file);
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], blocks[0].clone()),
+            (vec![ACK], blocks[2].clone()),
+            (vec![CAN, CAN], vec![]),
+        ]);
+        let mut dst = [0u8; 4096];
+        assert_eq!(
+            receive(&mut port, &mut dst).err(),
+            Some(Error::OutOfSequence)
+        );
+        port.finish();
+    }
+
+    #[test]
+    fn timeouts() {
+        // Nobody sends: the receiver keeps asking, then gives up
+        let mut expect = vec![CRC_REQUEST; START_RETRIES as usize];
+        expect.extend([CAN, CAN]);
+        let mut port = Transcript::new(vec![(expect, vec![])]);
+        assert_eq!(receive(&mut port, &mut [0; 16]).err(), Some(Error::Timeout));
+        port.finish();
+
+        // The sender stops after the header: every wait ends in a NAK
+        let mut expect = vec![NAK; MAX_ERRORS as usize - 1];
+        expect.extend([CAN, CAN]);
+        let mut port = Transcript::new(vec![
+            (vec![CRC_REQUEST], header("Image", 100)),
+            (vec![ACK, CRC_REQUEST], vec![]),
+            (expect, vec![]),
+        ]);
+        assert_eq!(
+            receive(&mut port, &mut [0; 128]).err(),
+            Some(Error::TooManyErrors)
+        );
+        port.finish();
+    }
+
+    /// Transfers from the `sz` and `sx` of lrzsz, over a pseudo terminal as from a serial
+    /// console. Skipped where lrzsz is not installed.
+    #[cfg(target_os = "linux")]
+    mod lrzsz {
+        use super::*;
+        use std::fs::File;
+        use std::io::{Read, Write};
+        use std::os::fd::{AsRawFd, FromRawFd};
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::path::PathBuf;
+        use std::process::{Child, Command, ExitStatus, Stdio};
+        use std::sync::mpsc::{self, Receiver};
+        use std::time::{Duration, Instant};
+
+        mod sys {
+            use core::ffi::{c_char, c_int};
+
+            pub const O_RDWR: c_int = 2;
+            pub const O_NOCTTY: c_int = 0o400;
+            pub const TCSANOW: c_int = 0;
+
+            extern "C" {
+                pub fn posix_openpt(flags: c_int) -> c_int;
+                pub fn grantpt(fd: c_int) -> c_int;
+                pub fn unlockpt(fd: c_int) -> c_int;
+                pub fn ptsname(fd: c_int) -> *const c_char;
+                // `struct termios` is only passed along, its layout does not matter here
+                pub fn tcgetattr(fd: c_int, termios: *mut u8) -> c_int;
+                pub fn cfmakeraw(termios: *mut u8);
+                pub fn tcsetattr(fd: c_int, action: c_int, termios: *const u8) -> c_int;
+            }
+        }
+
+        /// A sender on the other end of a raw pty.
+        struct Sender {
+            child: Child,
+            pty: File,
+            rx: Receiver<u8>,
+            path: PathBuf,
+        }
+
+        impl Sender {
+            /// Start `program` (or its `l`-prefixed name) sending `file`, or `None` where
+            /// lrzsz is missing.
+            fn spawn(program: &str, args: &[&str], file: &[u8]) -> Option<Self> {
+                use sys::*;
+                let (pty, tty) = unsafe {
+                    let fd = posix_openpt(O_RDWR | O_NOCTTY);
+                    assert!(fd >= 0);
+                    let pty = File::from_raw_fd(fd);
+                    assert_eq!(grantpt(fd), 0);
+                    assert_eq!(unlockpt(fd), 0);
+                    let name = core::ffi::CStr::from_ptr(ptsname(fd));
+                    let tty = File::options()
+                        .read(true)
+                        .write(true)
+                        .custom_flags(O_NOCTTY)
+                        .open(name.to_str().unwrap())
+                        .unwrap();
+                    let mut termios = [0u8; 256];
+                    assert_eq!(tcgetattr(tty.as_raw_fd(), termios.as_mut_ptr()), 0);
+                    cfmakeraw(termios.as_mut_ptr());
+                    assert_eq!(tcsetattr(tty.as_raw_fd(), TCSANOW, termios.as_ptr()), 0);
+                    (pty, tty)
+                };
+
+                let name = format!("ymodem-{}-{program}{}", std::process::id(), args.concat());
+                let path = std::env::temp_dir().join(name);
+                std::fs::write(&path, file).unwrap();
+                let child =
+                    [program.to_owned(), format!("l{program}")]
+                        .iter()
+                        .find_map(|program| {
+                            Command::new(program)
+                                .args(args)
+                                .arg(&path)
+                                .stdin(tty.try_clone().unwrap())
+                                .stdout(tty.try_clone().unwrap())
+                                .stderr(Stdio::null())
+                                .spawn()
+                                .ok()
+                        });
+                let Some(child) = child else {
+                    std::fs::remove_file(&path).unwrap();
+                    eprintln!("{program} of lrzsz not found, skipped");
+                    return None;
+                };
+
+                let (tx, rx) = mpsc::channel();
+                let mut reader = pty.try_clone().unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    // Ends with EIO once the sender exits
+                    while let Ok(n @ 1..) = reader.read(&mut buf) {
+                        if buf[..n].iter().any(|&b| tx.send(b).is_err()) {
+                            break;
+                        }
+                    }
+                });
+                Some(Sender {
+                    child,
+                    pty,
+                    rx,
+                    path,
+                })
+            }
+
+            /// Wait for the sender to exit, killing it if it hangs.
+            fn finish(mut self) -> ExitStatus {
+                let start = Instant::now();
+                loop {
+                    if let Some(status) = self.child.try_wait().unwrap() {
+                        return status;
+                    }
+                    if start.elapsed() > Duration::from_secs(10) {
+                        self.child.kill().unwrap();
+                        panic!("sender did not exit");
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+
+        impl Drop for Sender {
+            fn drop(&mut self) {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+
+        impl Port for Sender {
+            fn read(&mut self, timeout_ms: u32) -> Option<u8> {
+                let timeout = Duration::from_millis(timeout_ms.into());
+                self.rx.recv_timeout(timeout).ok()
+            }
+
+            fn write(&mut self, byte: u8) {
+                // The sender may be gone already after a cancel
+                let _ = self.pty.write_all(&[byte]);
+            }
+        }
+
+        /// Sizes around the 128 byte and 1K blocks, and beyond 255 blocks.
+        const LENGTHS: [usize; 5] = [1, 128, 1000, 5000, 300 * 1024 + 3];
+
+        #[test]
+        fn ymodem() {
+            // 128 byte blocks, and 1K ones with a 128 byte tail
+            for args in [&["--ymodem"][..], &["--ymodem", "-k"]] {
+                for len in LENGTHS {
+                    let file = file(len);
+                    let Some(mut sender) = Sender::spawn("sz", args, &file) else {
+                        return;
+                    };
+                    let mut dst = vec![0; len];
+                    let received = receive(&mut sender, &mut dst).unwrap();
+                    assert!(sender.finish().success(), "sz {args:?}, {len} bytes");
+                    assert_eq!(received.size, len);
+                    assert!(received.name().unwrap().starts_with("ymodem-"));
+                    assert_eq!(dst, file);
+                }
+            }
+        }
+
+        #[test]
+        fn xmodem() {
+            // CRC asked for with `C`, in 128 byte and 1K blocks
+            for args in [&[][..], &["-k"]] {
+                for len in LENGTHS {
+                    let file = file(len);
+                    let Some(mut sender) = Sender::spawn("sx", args, &file) else {
+                        return;
+                    };
+                    let mut dst = vec![0; len + 1024];
+                    let received = receive(&mut sender, &mut dst).unwrap();
+                    assert!(sender.finish().success(), "sx {args:?}, {len} bytes");
+                    assert_eq!(received.name(), None);
+                    assert!((len..len + 1024).contains(&received.size));
+                    assert_eq!(received.size % 128, 0);
+                    assert_eq!(dst[..len], file);
+                    assert!(dst[len..received.size].iter().all(|&b| b == CPMEOF));
+                }
+            }
+        }
+
+        #[test]
+        fn cancel_sender() {
+            // Our CANs make the sender give up instead of waiting for an ACK
+            let file = file(5000);
+            let Some(mut sender) = Sender::spawn("sz", &["--ymodem"], &file) else {
+                return;
+            };
+            let mut dst = [0u8; 4096];
+            assert_eq!(receive(&mut sender, &mut dst).err(), Some(Error::TooLarge));
+            assert!(!sender.finish().success());
+        }
+    }
+}
@@ -0,0 +1,108 @@
+#!/usr/bin/env python3
+"""Build and parse RustSBI-HPM environment images.
+
+The image holds both redundant copies, to be flashed at the first one
+(0x80FF1000, flash offset 0xFF1000):
+
+    envtool.py build env.txt -o env.bin
+    hpm_isp flash 0 write 0xFF1000 env.bin
+    envtool.py parse env.bin
+
+`env.txt` has one `name=value` per line; empty lines and lines starting
+with `#` are ignored.
+"""
+
+import argparse
+import struct
+import sys
+import zlib
+
+COPY_SIZE = 4096
+HEADER_SIZE = 5
+DATA_SIZE = COPY_SIZE - HEADER_SIZE
+
+
+def build_copy(variables, generation):
+    data = b"".join(f"{name}={value}".encode() + b"\0" for name, value in variables)
+    if len(data) >= DATA_SIZE:
+        sys.exit(f"environment too large: {len(data)} of {DATA_SIZE - 1} bytes")
+    data = data.ljust(DATA_SIZE, b"\0")
+    return struct.pack("<IB", zlib.crc32(data), generation) + data
+
+
+def parse_copy(copy):
+    crc, generation = struct.unpack_from("<IB", copy)
+    data = copy[HEADER_SIZE:COPY_SIZE]
+    if crc != zlib.crc32(data):
+        return None
+    variables = []
+    for entry in data.split(b"\0"):
+        if not entry:
+            break
+        name, _, value = entry.decode(errors="replace").partition("=")
+        variables.append((name, value))
+    return generation, variables
+
+
+def newer(g1, g0):
+    return (g1 - g0) & 0xFF in range(1, 0x80)
+
+
+def read_variables(path):
+    variables = []
+    with open(path) as f:
+        for number, line in enumerate(f, 1):
+            line = line.rstrip("\n")
+            if not line.strip() or line.lstrip().startswith("#"):
+                continue
+            name, sep, value = line.partition("=")
+            if not sep or not name:
+                sys.exit(f"{path}:{number}: expected name=value")
+            variables.append((name, value))
+    return variables
+
+
+def build(args):
+    copy = build_copy(read_variables(args.input), args.generation)
+    # The second copy stays erased, so the first one is current
+    image = copy if args.single else copy + b"\xff" * COPY_SIZE
+    with open(args.output, "wb") as f:
+        f.write(image)
+
+
+def parse(args):
+    with open(args.image, "rb") as f:
+        image = f.read()
+    copies = [image[i : i + COPY_SIZE] for i in range(0, len(image), COPY_SIZE)][:2]
+    parsed = [parse_copy(c) if len(c) == COPY_SIZE else None for c in copies]
+    for i, p in enumerate(parsed):
+        print(f"copy {i}: " + ("invalid" if p is None else f"generation {p[0]}"))
+    valid = [(i, p) for i, p in enumerate(parsed) if p is not None]
+    if not valid:
+        sys.exit("no valid copy")
+    current = valid[0]
+    if len(valid) == 2 and newer(valid[1][1][0], valid[0][1][0]):
+        current = valid[1]
+    print(f"current: copy {current[0]}")
+    for name, value in current[1][1]:
+        print(f"{name}={value}")
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n")[0])
+    sub = parser.add_subparsers(required=True)
+    p = sub.add_parser("build", help="build an image from a text file")
+    p.add_argument("input")
+    p.add_argument("-o", "--output", required=True)
+    p.add_argument("-g", "--generation", type=int, default=1)
+    p.add_argument("--single", action="store_true", help="only output one copy")
+    p.set_defaults(func=build)
+    p = sub.add_parser("parse", help="print the variables of an image")
+    p.add_argument("image")
+    p.set_defaults(func=parse)
+    args = parser.parse_args()
+    args.func(args)
+
+
+if __name__ == "__main__":
+    main()